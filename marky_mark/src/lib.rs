@@ -2,6 +2,7 @@
 extern crate failure;
 #[macro_use]
 extern crate lazy_static;
+extern crate noisy_float;
 #[macro_use]
 extern crate quote;
 #[macro_use]
@@ -27,6 +28,7 @@ use std::path::{Path, PathBuf};
 
 use failure::*;
 use fs2::FileExt;
+use noisy_float::prelude::R32;
 use proc_macro2::Span;
 use regex::Regex;
 use syn::{Ident as SynIdent, Path as SynPath};
@@ -47,6 +49,64 @@ pub struct Benchmark {
     #[serde(rename = "crate")]
     pub crate_name: String,
     pub entrypoint_path: PathBuf,
+    /// Criterion settings for this benchmark which take precedence over those for the whole run.
+    #[serde(default, skip_serializing_if = "CriterionOverrides::is_empty")]
+    pub criterion: CriterionOverrides,
+    /// Install lolbench_support's counting allocator in the entrypoint to record allocation
+    /// metrics. Off by default, since counting slows down every allocation the benchmark makes.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub count_allocations: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Criterion settings to measure with instead of lolbench's defaults. Unset fields fall back to
+/// whatever they're being applied on top of.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct CriterionOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warm_up_time_ms: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub measurement_time_ms: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nresamples: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub noise_threshold: Option<R32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence_level: Option<R32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub significance_level: Option<R32>,
+}
+
+impl CriterionOverrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// These overrides, with any that `other` sets replacing them.
+    pub fn overridden_by(&self, other: &Self) -> Self {
+        macro_rules! pick {
+            ($($field:ident),*) => {
+                Self {
+                    $($field: other.$field.or(self.$field),)*
+                }
+            };
+        }
+
+        pick!(
+            sample_size,
+            warm_up_time_ms,
+            measurement_time_ms,
+            nresamples,
+            noise_threshold,
+            confidence_level,
+            significance_level
+        )
+    }
 }
 
 impl Benchmark {
@@ -56,6 +116,8 @@ impl Benchmark {
             crate_name: crate_name.to_string(),
             runner: None,
             entrypoint_path: path.to_owned(),
+            criterion: CriterionOverrides::default(),
+            count_allocations: false,
         };
         n.strip();
         n
@@ -94,16 +156,35 @@ impl Benchmark {
         let name_syn: SynPath = syn::parse_str(&self.name).unwrap();
         let crate_name_syn = SynIdent::new(&self.crate_name, Span::call_site());
 
-        let source = quote! {
-            extern crate #crate_name_syn;
-            extern crate lolbench_support;
+        let source = if self.count_allocations {
+            quote! {
+                extern crate #crate_name_syn;
+                extern crate lolbench_support;
+
+                use lolbench_support::{criterion_from_env, init_logging, CountingAllocator};
 
-            use lolbench_support::{criterion_from_env, init_logging};
+                #[global_allocator]
+                static ALLOCATOR: CountingAllocator = CountingAllocator;
 
-            fn main() {
-                init_logging();
-                let mut crit = criterion_from_env();
-                #crate_name_syn::#name_syn(&mut crit);
+                fn main() {
+                    ALLOCATOR.enable();
+                    init_logging();
+                    let mut crit = criterion_from_env();
+                    #crate_name_syn::#name_syn(&mut crit);
+                }
+            }
+        } else {
+            quote! {
+                extern crate #crate_name_syn;
+                extern crate lolbench_support;
+
+                use lolbench_support::{criterion_from_env, init_logging};
+
+                fn main() {
+                    init_logging();
+                    let mut crit = criterion_from_env();
+                    #crate_name_syn::#name_syn(&mut crit);
+                }
             }
         };
 
@@ -116,6 +197,11 @@ impl Benchmark {
         self.strip();
         let (mut registry, _f) = Registry::from_disk()?;
         ecx!("Updating registry", registry.update(self))?;
+
+        // the entrypoint is generated from the registry's settings, not just those passed in
+        if let Some(registered) = registry.benchmarks.get(&self.key()) {
+            self.count_allocations = registered.count_allocations;
+        }
         write_if_changed(&self.source(), &full_path)
     }
 
@@ -130,6 +216,12 @@ impl Benchmark {
         }
 
         assign_opt!(runner);
+
+        if !other.criterion.is_empty() {
+            self.criterion = other.criterion.clone();
+        }
+
+        self.count_allocations |= other.count_allocations;
     }
 }
 
@@ -235,3 +327,50 @@ impl Registry {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use noisy_float::prelude::r32;
+
+    fn overrides(sample_size: Option<usize>, noise_threshold: Option<f32>) -> CriterionOverrides {
+        CriterionOverrides {
+            sample_size,
+            noise_threshold: noise_threshold.map(r32),
+            ..CriterionOverrides::default()
+        }
+    }
+
+    #[test]
+    fn set_overrides_replace_unset_ones_fall_through() {
+        let cli = overrides(Some(10), Some(0.02));
+        let registry = overrides(Some(50), None);
+
+        let combined = cli.overridden_by(&registry);
+        assert_eq!(combined.sample_size, Some(50));
+        assert_eq!(combined.noise_threshold, Some(r32(0.02)));
+        assert_eq!(combined.warm_up_time_ms, None);
+
+        assert_eq!(cli.overridden_by(&CriterionOverrides::default()), cli);
+        assert_eq!(CriterionOverrides::default().overridden_by(&cli), cli);
+    }
+
+    #[test]
+    fn updating_a_benchmark_keeps_its_overrides_unless_replaced() {
+        let path = Path::new("benches/bin/quickcheck.rs");
+        let mut registered = Benchmark::new("quickcheck", "shrink", path);
+        registered.criterion = overrides(Some(50), None);
+
+        // regenerating the benchmark from source doesn't know about registry-only settings
+        registered.absorg(&Benchmark::new("quickcheck", "shrink", path));
+        assert_eq!(registered.criterion, overrides(Some(50), None));
+        assert!(!registered.count_allocations);
+
+        let mut updated = Benchmark::new("quickcheck", "shrink", path);
+        updated.criterion = overrides(None, Some(0.05));
+        updated.count_allocations = true;
+        registered.absorg(&updated);
+        assert_eq!(registered.criterion, overrides(None, Some(0.05)));
+        assert!(registered.count_allocations);
+    }
+}
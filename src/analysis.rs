@@ -14,21 +14,27 @@ pub struct Analysis {
 pub struct AnomalousTiming {
     pub index: AnomalyIndex,
     pub bench_fn: String,
+    pub build_config: Option<String>,
     pub toolchain: Toolchain,
     pub timing: TimingRecord,
 }
 
 impl AnomalousTiming {
     pub fn benchmark_for_linking(&self) -> ::website::Benchmark {
-        ::website::Benchmark::empty(self.bench_fn.clone())
+        ::website::Benchmark::empty(
+            self.bench_fn.clone(),
+            self.toolchain.channel(),
+            self.build_config.clone(),
+        )
     }
 }
 
 impl Analysis {
-    pub fn new(timings: Vec<(String, TimingRecord)>) -> Self {
+    /// Takes each timing along with its benchmark's name and build configuration.
+    pub fn new(timings: Vec<(String, Option<String>, TimingRecord)>) -> Self {
         let mut anomalous_timings = timings
             .iter()
-            .filter(|(_, t)| {
+            .filter(|(_, _, t)| {
                 t.anomaly_index
                     .as_ref()
                     .map(|i| i.nanoseconds.is_of_interest())
@@ -36,7 +42,7 @@ impl Analysis {
             })
             .fold(
                 BTreeMap::<Toolchain, Vec<AnomalousTiming>>::new(),
-                |mut anomalies, (bench_fn, timing)| {
+                |mut anomalies, (bench_fn, build_config, timing)| {
                     let toolchain = timing.toolchains[0].clone();
                     {
                         let all_anomalies_for_toolchain =
@@ -44,9 +50,10 @@ impl Analysis {
 
                         all_anomalies_for_toolchain.push(AnomalousTiming {
                             bench_fn: bench_fn.clone(),
+                            build_config: build_config.clone(),
                             toolchain,
                             timing: timing.to_owned(),
-                            index: timing.anomaly_index.unwrap(),
+                            index: timing.anomaly_index.clone().unwrap(),
                         });
 
                         all_anomalies_for_toolchain.sort();
@@ -64,6 +71,27 @@ impl Analysis {
     }
 }
 
+/// Finds the gaps in a series of timings (in toolchain order) across which the runtime changed
+/// enough to be interesting, as the last toolchain before each gap and the first one after it.
+/// Measuring the toolchains in between narrows down where the change happened.
+pub fn anomalous_gaps(series: &[TimingRecord]) -> Vec<(Toolchain, Toolchain)> {
+    series
+        .windows(2)
+        .filter(|pair| {
+            pair[1]
+                .anomaly_index
+                .as_ref()
+                .map(|i| i.nanoseconds.is_of_interest())
+                .unwrap_or(false)
+        })
+        .filter_map(|pair| {
+            let before = pair[0].toolchains.last()?;
+            let after = pair[1].toolchains.first()?;
+            Some((before.clone(), after.clone()))
+        })
+        .collect()
+}
+
 pub fn geometric_mean(values: &[R64]) -> R64 {
     values
         .iter()
@@ -83,17 +111,26 @@ pub fn normalized_against_first(
 pub struct TimingRecord {
     pub binary_hash: String,
     pub toolchains: Vec<Toolchain>,
+    /// In the same order as `toolchains`, `None` if we never recorded metadata for one.
+    pub toolchain_metadata: Vec<Option<ToolchainMetadata>>,
     pub anomaly_index: Option<AnomalyIndex>,
     pub metrics: RuntimeMetrics,
     pub normalized_metrics: RuntimeMetrics,
+    /// Build time and binary size, keyed like `BUILD_METRICS`, and the conditions they were
+    /// measured under, keyed like `BUILD_CONTEXT`. Missing for binaries which were measured before
+    /// they were recorded.
+    pub build_metrics: BTreeMap<String, MetricData>,
+    /// The fingerprint id of the host which measured this, if it was recorded.
+    pub host: Option<String>,
 }
 
 impl TimingRecord {
-    pub fn new(
+    pub fn new<'a>(
         current_binhash: &[u8],
         current_toolchains: &[Toolchain],
         current_measure: &Estimates,
-        previous: &[Self],
+        previous: impl Clone + Iterator<Item = &'a Self>,
+        toolchain_metadata: &BTreeMap<Toolchain, ToolchainMetadata>,
     ) -> Self {
         let nice_hex =
             String::from_utf8(current_binhash.iter().fold(Vec::new(), |mut buf, byte| {
@@ -104,27 +141,125 @@ impl TimingRecord {
             .unwrap();
 
         let metrics = RuntimeMetrics::from_measure(current_measure);
-        let normalized_metrics = previous
-            .get(0)
-            .map(|first| metrics.normalized_against(&first.metrics))
-            // this is only None if this is the first TimingRecord in the series, in which case
-            // we'll normalize everything else as if this was a one
-            .unwrap_or_else(RuntimeMetrics::ones);
+        let normalized_metrics =
+            metrics.normalized_against_earliest(previous.clone().map(|p| &p.metrics));
 
         let toolchains = current_toolchains.into_iter().cloned().sorted();
+        let toolchain_metadata = toolchains
+            .iter()
+            .map(|tc| toolchain_metadata.get(tc).cloned())
+            .collect();
+
+        let build_metrics = BUILD_METRICS
+            .iter()
+            .chain(BUILD_CONTEXT)
+            .filter_map(|&name| {
+                current_measure
+                    .get(name)
+                    .map(|stat| (name.to_string(), MetricData::from_statistic(stat)))
+            })
+            .collect();
 
         let mut timing = Self {
             binary_hash: nice_hex,
             toolchains,
+            toolchain_metadata,
             anomaly_index: None,
             metrics,
             normalized_metrics,
+            build_metrics,
+            host: None,
         };
 
-        let anomaly_index = AnomalyIndex::new(&timing.metrics, previous.iter().map(|p| p.metrics));
+        let anomaly_index = AnomalyIndex::new(&timing.metrics, previous.map(|p| &p.metrics));
         timing.anomaly_index = anomaly_index;
         timing
     }
+
+    /// Groups a benchmark's estimates (in toolchain order) into timings, one per run of toolchains
+    /// which produced identical binaries on the same host. Returned in the same order.
+    ///
+    /// Unless `compare_across_hosts` is set, each timing is only compared (and normalized) against
+    /// earlier timings from a host with the same fingerprint, since a different host's results
+    /// would show up as anomalies.
+    pub fn series(
+        mut estimates: impl Iterator<Item = (Toolchain, StoredEstimates)>,
+        toolchain_metadata: &BTreeMap<Toolchain, ToolchainMetadata>,
+        compare_across_hosts: bool,
+    ) -> Vec<Self> {
+        let mut timings = Vec::new();
+        let first_estimate = match estimates.next() {
+            Some(e) => e,
+            None => return timings,
+        };
+
+        let mut current_binhash = (first_estimate.1).0;
+        let mut current_toolchains = vec![first_estimate.0];
+        let mut current_measure = (first_estimate.1).1;
+        let mut current_host = (first_estimate.1).2;
+
+        while let Some((tc, (binhash, measure, host))) = estimates.next() {
+            if binhash == current_binhash && host == current_host {
+                current_toolchains.push(tc.to_owned());
+            } else {
+                let mut timing = TimingRecord::new(
+                    &current_binhash,
+                    &current_toolchains,
+                    &current_measure,
+                    Self::comparable(&timings, &current_host, compare_across_hosts),
+                    toolchain_metadata,
+                );
+                timing.host = current_host.clone();
+
+                timings.push(timing);
+
+                current_binhash = binhash.to_owned();
+                current_toolchains = vec![tc.to_owned()];
+                current_measure = measure.to_owned();
+                current_host = host;
+            }
+        }
+
+        let mut timing = TimingRecord::new(
+            &current_binhash,
+            &current_toolchains,
+            &current_measure,
+            Self::comparable(&timings, &current_host, compare_across_hosts),
+            toolchain_metadata,
+        );
+        timing.host = current_host;
+        timings.push(timing);
+
+        timings
+    }
+
+    /// The timings a new one measured on `host` should be compared against.
+    fn comparable<'a>(
+        timings: &'a [Self],
+        host: &'a Option<String>,
+        compare_across_hosts: bool,
+    ) -> impl Clone + Iterator<Item = &'a Self> {
+        timings
+            .iter()
+            .filter(move |t| compare_across_hosts || t.host == *host)
+    }
+
+    /// One row per toolchain for display: name, rustc commit hash, commit date and LLVM version.
+    pub fn toolchain_rows(&self) -> Vec<(Toolchain, String, String, String)> {
+        self.toolchains
+            .iter()
+            .zip(&self.toolchain_metadata)
+            .map(|(tc, metadata)| match metadata {
+                Some(m) => (
+                    tc.clone(),
+                    m.commit_hash.clone().unwrap_or_default(),
+                    m.commit_date.map(|d| d.to_string()).unwrap_or_default(),
+                    m.llvm_version.clone().unwrap_or_default(),
+                ),
+                None => (tc.clone(), String::new(), String::new(), String::new()),
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
@@ -162,193 +297,84 @@ impl MetricData {
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
-pub struct RuntimeMetrics {
-    pub nanoseconds: MetricData,
-    pub instructions: MetricData,
-    pub cpu_cycles: MetricData,
-
-    pub context_switches: MetricData,
-    pub cpu_clock: MetricData,
-
-    pub branch_instructions: MetricData,
-    pub branch_misses: MetricData,
-
-    pub cache_misses: MetricData,
-    pub cache_references: MetricData,
-}
+/// Runtime metrics by the name they're stored under, e.g. `nanoseconds` or `cache-misses`. Which
+/// ones a measurement has depends on the perf events that were counted for it.
+#[derive(Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct RuntimeMetrics(pub BTreeMap<String, MetricData>);
 
 impl RuntimeMetrics {
+    /// Every metric in the estimates except for those describing the build.
     fn from_measure(current_measure: &Estimates) -> Self {
-        let nanoseconds = MetricData::from_statistic(&current_measure["nanoseconds"]);
-        let instructions = MetricData::from_statistic(&current_measure["instructions"]);
-        let context_switches = MetricData::from_statistic(&current_measure["context-switches"]);
-        let cpu_clock = MetricData::from_statistic(&current_measure["cpu-clock"]);
-        let branch_instructions =
-            MetricData::from_statistic(&current_measure["branch-instructions"]);
-        let branch_misses = MetricData::from_statistic(&current_measure["branch-misses"]);
-        let cache_misses = MetricData::from_statistic(&current_measure["cache-misses"]);
-        let cache_references = MetricData::from_statistic(&current_measure["cache-references"]);
-        let cpu_cycles = MetricData::from_statistic(&current_measure["cpu-cycles"]);
-
-        RuntimeMetrics {
-            nanoseconds,
-            instructions,
-            context_switches,
-            cpu_clock,
-            branch_instructions,
-            branch_misses,
-            cache_misses,
-            cache_references,
-            cpu_cycles,
-        }
+        RuntimeMetrics(
+            current_measure
+                .iter()
+                .filter(|(name, _)| {
+                    !BUILD_METRICS.contains(&name.as_str())
+                        && !BUILD_CONTEXT.contains(&name.as_str())
+                })
+                .map(|(name, statistic)| (name.clone(), MetricData::from_statistic(statistic)))
+                .collect(),
+        )
     }
 
-    pub fn normalized_against(&self, baseline: &Self) -> Self {
-        Self {
-            nanoseconds: self.nanoseconds.normalized_against(&baseline.nanoseconds),
-            instructions: self.instructions.normalized_against(&baseline.instructions),
-            cpu_clock: self.cpu_clock.normalized_against(&baseline.cpu_clock),
-            branch_instructions: self
-                .branch_instructions
-                .normalized_against(&baseline.branch_instructions),
-            branch_misses: self
-                .branch_misses
-                .normalized_against(&baseline.branch_misses),
-            cache_misses: self.cache_misses.normalized_against(&baseline.cache_misses),
-            cache_references: self
-                .cache_references
-                .normalized_against(&baseline.cache_references),
-            cpu_cycles: self.cpu_cycles.normalized_against(&baseline.cpu_cycles),
-            context_switches: self
-                .context_switches
-                .normalized_against(&baseline.context_switches),
-        }
-    }
-
-    pub fn ones() -> Self {
-        RuntimeMetrics {
-            nanoseconds: MetricData::ones(),
-            instructions: MetricData::ones(),
-            cpu_clock: MetricData::ones(),
-            branch_instructions: MetricData::ones(),
-            branch_misses: MetricData::ones(),
-            cache_misses: MetricData::ones(),
-            cache_references: MetricData::ones(),
-            cpu_cycles: MetricData::ones(),
-            context_switches: MetricData::ones(),
-        }
+    pub fn get(&self, metric: &str) -> Option<&MetricData> {
+        self.0.get(metric)
     }
-}
-
-#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
-pub struct SimpleRuntimeMetrics {
-    pub nanoseconds: R64,
-    pub instructions: R64,
-    pub cpu_cycles: R64,
 
-    pub context_switches: R64,
-    pub cpu_clock: R64,
-
-    pub branch_instructions: R64,
-    pub branch_misses: R64,
-
-    pub cache_misses: R64,
-    pub cache_references: R64,
-}
-
-impl SimpleRuntimeMetrics {
-    pub fn ones() -> Self {
-        let o = r64(1.0);
-        SimpleRuntimeMetrics {
-            nanoseconds: o,
-            instructions: o,
-            cpu_clock: o,
-            branch_instructions: o,
-            branch_misses: o,
-            cache_misses: o,
-            cache_references: o,
-            cpu_cycles: o,
-            context_switches: o,
-        }
+    /// Normalizes each metric against the earliest of `previous` which recorded it. Runners
+    /// without access to perf counters only record some metrics, so that isn't necessarily the
+    /// first of them. A metric none of them recorded is a one, since later timings will be
+    /// normalized against this one.
+    pub fn normalized_against_earliest<'a>(
+        &self,
+        previous: impl Clone + Iterator<Item = &'a RuntimeMetrics>,
+    ) -> Self {
+        RuntimeMetrics(
+            self.0
+                .iter()
+                .map(|(name, data)| {
+                    let normalized = previous
+                        .clone()
+                        .find_map(|p| p.get(name))
+                        .map(|baseline| data.normalized_against(baseline))
+                        .unwrap_or_else(MetricData::ones);
+                    (name.clone(), normalized)
+                })
+                .collect(),
+        )
     }
 }
 
-#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+/// Scores for each of a timing's metrics which has enough history to compare against.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct AnomalyIndex {
+    /// Runtime decides whether a timing is of interest, so a timing is only scored if this is.
     pub nanoseconds: AnomalyScore,
-    pub instructions: AnomalyScore,
-    pub cpu_cycles: AnomalyScore,
-    pub branch_instructions: AnomalyScore,
-    pub branch_misses: AnomalyScore,
-    pub cache_references: AnomalyScore,
-    pub cache_misses: AnomalyScore,
+    /// Includes `nanoseconds`.
+    pub scores: BTreeMap<String, AnomalyScore>,
 }
 
 impl AnomalyIndex {
-    fn new(
-        &RuntimeMetrics {
-            nanoseconds,
-            instructions,
-            cpu_cycles,
-            branch_instructions,
-            branch_misses,
-            cache_references,
-            cache_misses,
-            ..
-        }: &RuntimeMetrics,
-        previous: impl Clone + Iterator<Item = RuntimeMetrics>,
+    fn new<'a>(
+        current: &RuntimeMetrics,
+        previous: impl Clone + Iterator<Item = &'a RuntimeMetrics>,
     ) -> Option<Self> {
-        if let (
-            Some(nanoseconds),
-            Some(instructions),
-            Some(cpu_cycles),
-            Some(branch_instructions),
-            Some(branch_misses),
-            Some(cache_references),
-            Some(cache_misses),
-        ) = (
-            AnomalyScore::new(
-                nanoseconds.median,
-                previous.clone().map(|p| p.nanoseconds.median),
-            ),
-            AnomalyScore::new(
-                instructions.median,
-                previous.clone().map(|p| p.instructions.median),
-            ),
-            AnomalyScore::new(
-                cpu_cycles.median,
-                previous.clone().map(|p| p.cpu_cycles.median),
-            ),
-            AnomalyScore::new(
-                branch_instructions.median,
-                previous.clone().map(|p| p.branch_instructions.median),
-            ),
-            AnomalyScore::new(
-                branch_misses.median,
-                previous.clone().map(|p| p.branch_misses.median),
-            ),
-            AnomalyScore::new(
-                cache_references.median,
-                previous.clone().map(|p| p.cache_references.median),
-            ),
-            AnomalyScore::new(
-                cache_misses.median,
-                previous.clone().map(|p| p.cache_misses.median),
-            ),
-        ) {
-            Some(Self {
-                nanoseconds,
-                instructions,
-                cpu_cycles,
-                branch_instructions,
-                branch_misses,
-                cache_references,
-                cache_misses,
+        let scores = current
+            .0
+            .iter()
+            .filter_map(|(name, data)| {
+                let history = previous
+                    .clone()
+                    .filter_map(|p| p.get(name))
+                    .map(|p| p.median);
+                AnomalyScore::new(data.median, history).map(|score| (name.clone(), score))
             })
-        } else {
-            None
-        }
+            .collect::<BTreeMap<_, _>>();
+
+        Some(Self {
+            nanoseconds: *scores.get("nanoseconds")?,
+            scores,
+        })
     }
 }
 
@@ -417,17 +443,121 @@ impl Display for AnomalyScore {
 impl<'a> ::std::ops::Index<&'a str> for AnomalyIndex {
     type Output = AnomalyScore;
     fn index(&self, i: &'a str) -> &Self::Output {
-        match i {
-            "nanoseconds" => &self.nanoseconds,
-            "instructions" => &self.instructions,
-            "cpu_cycles" => &self.cpu_cycles,
-            "branch_instructions" => &self.branch_instructions,
-            "branch_misses" => &self.branch_misses,
-            "cache_references" => &self.cache_references,
-            "cache_misses" => &self.cache_misses,
-            _ => panic!(),
-        }
+        &self.scores[i]
     }
 }
 
 const NUM_SAMPLES: usize = 10;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimates(metrics: &[(&str, f64)]) -> Estimates {
+        metrics
+            .iter()
+            .map(|&(name, value)| (name.to_string(), Statistic::exact(value)))
+            .collect()
+    }
+
+    #[test]
+    fn partial_metrics_are_normalized_and_scored() {
+        let metadata = BTreeMap::new();
+        let mut series: Vec<TimingRecord> = Vec::new();
+
+        // a runner without perf counters records only wall time for the first dozen binaries
+        for i in 0..16 {
+            let nanos = 100.0 + i as f64;
+            let measure = if i < 12 {
+                estimates(&[("nanoseconds", nanos), ("build-nanoseconds", 1e9)])
+            } else {
+                estimates(&[("nanoseconds", nanos), ("instructions", 1000.0 + i as f64)])
+            };
+            let toolchain = Toolchain::from(&format!("nightly-2018-01-{:02}", i + 1));
+            let timing =
+                TimingRecord::new(&[i as u8], &[toolchain], &measure, series.iter(), &metadata);
+            series.push(timing);
+        }
+
+        let wall_time_only = &series[11];
+        assert_eq!(
+            wall_time_only.metrics.0.keys().collect::<Vec<_>>(),
+            vec!["nanoseconds"]
+        );
+        assert!(wall_time_only
+            .build_metrics
+            .contains_key("build-nanoseconds"));
+        let index = wall_time_only.anomaly_index.as_ref().unwrap();
+        assert_eq!(index.scores.keys().collect::<Vec<_>>(), vec!["nanoseconds"]);
+
+        // instructions are normalized against the first timing which counted them
+        let first_counted = &series[12].normalized_metrics;
+        assert_eq!(first_counted.get("instructions"), Some(&MetricData::ones()));
+        assert!(first_counted.get("nanoseconds").unwrap().median > 1.0);
+        let later = series[15].normalized_metrics.get("instructions").unwrap();
+        assert!(later.median > 1.0);
+
+        // too few timings have counted instructions to score them yet
+        let index = series[15].anomaly_index.as_ref().unwrap();
+        assert!(index.scores.contains_key("nanoseconds"));
+        assert!(!index.scores.contains_key("instructions"));
+    }
+
+    fn stored(binhash: u8, nanos: f64, host: &str) -> StoredEstimates {
+        (
+            vec![binhash],
+            estimates(&[("nanoseconds", nanos)]),
+            Some(host.to_string()),
+        )
+    }
+
+    fn nightly(day: u32) -> Toolchain {
+        Toolchain::from(&format!("nightly-2018-01-{:02}", day))
+    }
+
+    #[test]
+    fn series_groups_runs_of_identical_binaries() {
+        let estimates = vec![
+            (nightly(1), stored(1, 100.0, "a")),
+            (nightly(2), stored(1, 100.0, "a")),
+            (nightly(3), stored(2, 110.0, "a")),
+            (nightly(4), stored(3, 90.0, "a")),
+            (nightly(5), stored(3, 90.0, "a")),
+        ];
+
+        let series = TimingRecord::series(estimates.into_iter(), &BTreeMap::new(), false);
+        let toolchains = series
+            .iter()
+            .map(|t| t.toolchains.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            toolchains,
+            vec![
+                vec![nightly(1), nightly(2)],
+                vec![nightly(3)],
+                vec![nightly(4), nightly(5)],
+            ]
+        );
+        assert_eq!(series[2].binary_hash, "3");
+    }
+
+    #[test]
+    fn series_compares_against_earlier_timings_from_the_same_host() {
+        let estimates = vec![
+            (nightly(1), stored(1, 100.0, "a")),
+            (nightly(2), stored(2, 100.0, "a")),
+            (nightly(3), stored(3, 1000.0, "b")),
+            (nightly(4), stored(4, 200.0, "a")),
+        ];
+
+        let series = TimingRecord::series(estimates.clone().into_iter(), &BTreeMap::new(), false);
+        let nanos =
+            |timing: &TimingRecord| timing.normalized_metrics.get("nanoseconds").unwrap().median;
+        assert_eq!(nanos(&series[2]).raw(), 1.0);
+        // host a's history from before b's run is still its baseline
+        assert!((nanos(&series[3]).raw() - 201.0 / 101.0).abs() < 1e-9);
+
+        let series = TimingRecord::series(estimates.into_iter(), &BTreeMap::new(), true);
+        assert!((nanos(&series[2]).raw() - 1001.0 / 101.0).abs() < 1e-9);
+    }
+}
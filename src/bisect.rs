@@ -0,0 +1,333 @@
+//! Narrows down which nightly introduced a change in a benchmark's results.
+
+use super::Result;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::Path;
+use std::sync::Arc;
+
+use build_config::BuildConfig;
+use collector::Collector;
+use cpu_shield::ShieldSpec;
+use criterion_config;
+use dated_toolchains;
+use registry::{get_benches, BenchFilter};
+use run_plan::RunPlan;
+use storage::GitStore;
+use toolchain::{Channel, LocalToolchains, Toolchain};
+
+#[derive(Debug)]
+pub struct BisectOpts {
+    /// `crate::bench_fn`, as used by the registry.
+    pub benchmark_key: String,
+    /// The last nightly known to behave like it used to.
+    pub good: Toolchain,
+    /// A later nightly known to behave differently.
+    pub bad: Toolchain,
+    /// The key of the metric to compare in stored estimates, e.g. `nanoseconds`.
+    pub metric: String,
+    /// How far (in percent of the good toolchain's median) a nightly's median has to move in the
+    /// same direction as the bad toolchain's before it's considered bad too.
+    pub threshold_percent: f64,
+    pub shield_spec: Option<ShieldSpec>,
+    pub build_config: Option<BuildConfig>,
+    pub local_toolchains: Option<LocalToolchains>,
+}
+
+#[derive(Debug)]
+pub struct BisectReport {
+    pub last_good: (Toolchain, f64),
+    pub first_bad: (Toolchain, f64),
+    /// Nightlies in the range which couldn't be installed, built or run.
+    pub skipped: Vec<Toolchain>,
+    /// rustc commit hashes of the last good and first bad toolchains, if we know them.
+    pub commit_range: Option<(String, String)>,
+}
+
+impl BisectReport {
+    pub fn compare_url(&self) -> Option<String> {
+        self.commit_range.as_ref().map(|(good, bad)| {
+            format!(
+                "https://github.com/rust-lang/rust/compare/{}...{}",
+                good, bad
+            )
+        })
+    }
+}
+
+impl Display for BisectReport {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        writeln!(f, "last good: {} ({})", self.last_good.0, self.last_good.1)?;
+        writeln!(f, "first bad: {} ({})", self.first_bad.0, self.first_bad.1)?;
+
+        if !self.skipped.is_empty() {
+            writeln!(
+                f,
+                "skipped (unavailable or failed): {}",
+                self.skipped
+                    .iter()
+                    .map(Toolchain::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+
+        match self.compare_url() {
+            Some(url) => writeln!(f, "rustc commits: {}", url),
+            None => writeln!(f, "no rustc commit metadata recorded for these toolchains"),
+        }
+    }
+}
+
+/// Binary searches the nightlies between `good` and `bad` for the first one whose results for the
+/// benchmark crossed the threshold. Results are memoized in the data directory like any other
+/// measurement, so bisecting over previously measured nightlies is cheap.
+pub fn bisect(opts: BisectOpts, data_dir: &Path, site_dir: &Path) -> Result<BisectReport> {
+    let (good_date, bad_date) = match (opts.good.date(), opts.bad.date()) {
+        (Some(good), Some(bad))
+            if opts.good.channel() == Channel::Nightly
+                && opts.bad.channel() == Channel::Nightly =>
+        {
+            (good, bad)
+        }
+        _ => bail!("can only bisect between two dated nightlies"),
+    };
+    ensure!(
+        good_date < bad_date,
+        "the good toolchain must be older than the bad one"
+    );
+
+    let benchmark = get_benches(&BenchFilter::default())?
+        .into_iter()
+        .find(|b| b.key() == opts.benchmark_key)
+        .ok_or_else(|| format_err!("no benchmark found named {}", opts.benchmark_key))?;
+
+    let mut collector = Collector::new(data_dir, site_dir)?;
+    if let Some(local) = &opts.local_toolchains {
+        collector = collector.with_provider(Arc::new(local.clone()));
+    }
+
+    let measure = |toolchain: &Toolchain| -> Result<Option<f64>> {
+        let rp = RunPlan::new(
+            benchmark.clone(),
+            criterion_config(&benchmark.criterion),
+            opts.build_config.clone(),
+            opts.shield_spec.clone(),
+            Some(toolchain.clone()),
+            benchmark.entrypoint_path.clone(),
+        )?;
+        rp.validate()?;
+
+        if let Err(why) =
+            collector.run_benches_with_toolchain(toolchain.clone(), &[rp.clone()], false)
+        {
+            warn!("unable to measure {}: {}", toolchain, why);
+            return Ok(None);
+        }
+
+        Ok(match collector.stored_estimates(&rp)? {
+            Some(Ok(estimates)) => match estimates.get(&opts.metric) {
+                Some(statistic) => Some(statistic.median.point_estimate),
+                None => bail!("{} has no estimates for {}", toolchain, opts.metric),
+            },
+            _ => None,
+        })
+    };
+
+    // runs from the good toolchain to the bad one, inclusive
+    let candidates = dated_toolchains("nightly", good_date, bad_date);
+    let mut report = search(candidates, &opts.metric, opts.threshold_percent, measure)?;
+
+    let metadata = GitStore::ensure_initialized(data_dir)?.all_toolchain_metadata()?;
+    let commit =
+        |toolchain: &Toolchain| metadata.get(toolchain).and_then(|m| m.commit_hash.clone());
+    report.commit_range = match (commit(&report.last_good.0), commit(&report.first_bad.0)) {
+        (Some(good), Some(bad)) => Some((good, bad)),
+        _ => None,
+    };
+
+    Ok(report)
+}
+
+/// Binary searches `candidates`, ordered from the known good toolchain to the known bad one, for
+/// the first whose value crosses the threshold in the bad toolchain's direction. `measure` returns
+/// `None` for toolchains which couldn't be measured, which are skipped.
+fn search<F>(
+    mut candidates: Vec<Toolchain>,
+    metric: &str,
+    threshold_percent: f64,
+    mut measure: F,
+) -> Result<BisectReport>
+where
+    F: FnMut(&Toolchain) -> Result<Option<f64>>,
+{
+    ensure!(
+        candidates.len() >= 2,
+        "need a good and a bad toolchain to bisect between"
+    );
+    let good = candidates[0].clone();
+    let bad = candidates[candidates.len() - 1].clone();
+
+    let good_value = measure(&good)?
+        .ok_or_else(|| format_err!("unable to measure the good toolchain {}", good))?;
+    let bad_value =
+        measure(&bad)?.ok_or_else(|| format_err!("unable to measure the bad toolchain {}", bad))?;
+    ensure!(
+        good_value != 0.0,
+        "{} measured 0 for {}, changes can't be relative to it",
+        good,
+        metric
+    );
+
+    let percent_change = |value: f64| (value - good_value) / good_value * 100.0;
+    let direction = percent_change(bad_value).signum();
+    let crossed = |value: f64| percent_change(value) * direction >= threshold_percent;
+
+    ensure!(
+        crossed(bad_value),
+        "{} differs from {} by {:.2}% on {}, which doesn't cross the {}% threshold",
+        bad,
+        good,
+        percent_change(bad_value),
+        metric,
+        threshold_percent
+    );
+
+    let mut last_good = (good, good_value);
+    let mut first_bad = (bad, bad_value);
+    let mut skipped = Vec::new();
+
+    let (mut lo, mut hi) = (0, candidates.len() - 1);
+    while hi - lo > 1 {
+        let mid = lo + (hi - lo) / 2;
+        let toolchain = candidates[mid].clone();
+        info!(
+            "bisecting {}..{}, trying {}",
+            candidates[lo], candidates[hi], toolchain
+        );
+
+        match measure(&toolchain)? {
+            Some(value) if crossed(value) => {
+                first_bad = (toolchain, value);
+                hi = mid;
+            }
+            Some(value) => {
+                last_good = (toolchain, value);
+                lo = mid;
+            }
+            None => {
+                // missing nightlies are common, just take them out of the running
+                skipped.push(candidates.remove(mid));
+                hi -= 1;
+            }
+        }
+    }
+
+    skipped.sort();
+
+    Ok(BisectReport {
+        last_good,
+        first_bad,
+        skipped,
+        commit_range: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    fn nightlies(n: usize) -> Vec<Toolchain> {
+        (1..=n)
+            .map(|day| Toolchain::from(&format!("nightly-2018-01-{:02}", day)))
+            .collect()
+    }
+
+    /// Measures each nightly as the value at its index, recording which ones were measured.
+    fn fake<'a>(
+        values: &[Option<f64>],
+        measured: &'a mut Vec<Toolchain>,
+    ) -> impl FnMut(&Toolchain) -> Result<Option<f64>> + 'a {
+        let by_toolchain = nightlies(values.len())
+            .into_iter()
+            .zip(values.iter().cloned())
+            .collect::<BTreeMap<_, _>>();
+        move |toolchain| {
+            measured.push(toolchain.clone());
+            Ok(by_toolchain[toolchain])
+        }
+    }
+
+    #[test]
+    fn finds_the_first_bad_nightly() {
+        let values = [100.0, 101.0, 99.0, 100.0, 130.0, 131.0, 129.0, 130.0];
+        let values = values.iter().cloned().map(Some).collect::<Vec<_>>();
+        let mut measured = Vec::new();
+
+        let report = search(
+            nightlies(8),
+            "nanoseconds",
+            10.0,
+            fake(&values, &mut measured),
+        )
+        .unwrap();
+        assert_eq!(
+            report.last_good,
+            (Toolchain::from("nightly-2018-01-04"), 100.0)
+        );
+        assert_eq!(
+            report.first_bad,
+            (Toolchain::from("nightly-2018-01-05"), 130.0)
+        );
+        assert!(report.skipped.is_empty());
+        // both ends plus a binary search over the six in between
+        assert_eq!(measured.len(), 5);
+    }
+
+    #[test]
+    fn skips_unmeasurable_nightlies() {
+        let values = [Some(100.0), Some(100.0), None, None, Some(50.0), Some(50.0)];
+        let mut measured = Vec::new();
+
+        let report = search(
+            nightlies(6),
+            "nanoseconds",
+            10.0,
+            fake(&values, &mut measured),
+        )
+        .unwrap();
+        assert_eq!(report.last_good.0, Toolchain::from("nightly-2018-01-02"));
+        assert_eq!(report.first_bad.0, Toolchain::from("nightly-2018-01-05"));
+        assert_eq!(
+            report.skipped,
+            vec![
+                Toolchain::from("nightly-2018-01-03"),
+                Toolchain::from("nightly-2018-01-04"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_baselines() {
+        let mut measured = Vec::new();
+        let zero = [Some(0.0), Some(1.0), Some(2.0)];
+        assert!(search(
+            nightlies(3),
+            "allocations",
+            10.0,
+            fake(&zero, &mut measured)
+        )
+        .is_err());
+
+        let within_threshold = [Some(100.0), Some(101.0), Some(105.0)];
+        assert!(search(
+            nightlies(3),
+            "nanoseconds",
+            10.0,
+            fake(&within_threshold, &mut measured)
+        )
+        .is_err());
+    }
+}
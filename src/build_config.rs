@@ -0,0 +1,231 @@
+use super::Result;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::process::Command;
+use std::str::FromStr;
+
+/// A named set of codegen options to build benchmarks with, on top of the workspace's release
+/// profile. A `RunPlan` without one is built with the release profile as-is.
+///
+/// Most options are passed to every crate in the build through `RUSTFLAGS`, since older toolchains
+/// don't support overriding profiles from the command line. LTO only applies to the benchmark
+/// binary itself, which is the only crate it means anything for.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct BuildConfig {
+    pub name: String,
+    pub opt_level: Option<String>,
+    pub target_cpu: Option<String>,
+    pub lto: Option<String>,
+    pub codegen_units: Option<u32>,
+    pub panic: Option<String>,
+    /// Passed to rustc after all of the other options. Can't contain whitespace, since
+    /// `RUSTFLAGS` is split on it.
+    pub extra_rustflags: Vec<String>,
+}
+
+impl BuildConfig {
+    /// Flags for every crate in the build.
+    pub fn rustflags(&self) -> Vec<String> {
+        let mut flags = Vec::new();
+        {
+            let mut codegen = |option: &str, value: &Option<String>| {
+                if let Some(value) = value {
+                    flags.push(String::from("-C"));
+                    flags.push(format!("{}={}", option, value));
+                }
+            };
+            codegen("opt-level", &self.opt_level);
+            codegen("target-cpu", &self.target_cpu);
+            codegen(
+                "codegen-units",
+                &self.codegen_units.map(|units| units.to_string()),
+            );
+            codegen("panic", &self.panic);
+        }
+
+        flags.extend(self.extra_rustflags.iter().cloned());
+        flags
+    }
+
+    /// The value of `RUSTFLAGS` to build with.
+    pub fn rustflags_env(&self) -> String {
+        self.rustflags().join(" ")
+    }
+
+    /// Flags for the benchmark binary alone, passed through `cargo rustc`.
+    pub fn binary_rustflags(&self) -> Vec<String> {
+        match &self.lto {
+            Some(lto) => vec![String::from("-C"), format!("lto={}", lto)],
+            None => Vec::new(),
+        }
+    }
+
+    /// Checks that the given rustc accepts all of these options, since older toolchains don't
+    /// know about some of them (e.g. `lto=off`).
+    pub fn ensure_accepted_by(&self, mut rustc: Command) -> Result<()> {
+        let output = rustc
+            .args(self.rustflags())
+            .args(self.binary_rustflags())
+            .arg("--print")
+            .arg("sysroot")
+            .output()?;
+
+        ensure!(
+            output.status.success(),
+            "build configuration `{}` isn't supported by this toolchain: {}",
+            self,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(())
+    }
+}
+
+/// Formatted the same way as it's parsed, e.g. `thin-lto:lto=thin,codegen-units=16`.
+impl Display for BuildConfig {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut options = Vec::new();
+        {
+            let mut option = |name: &str, value: &Option<String>| {
+                if let Some(value) = value {
+                    options.push(format!("{}={}", name, value));
+                }
+            };
+            option("opt-level", &self.opt_level);
+            option("target-cpu", &self.target_cpu);
+            option("lto", &self.lto);
+            option(
+                "codegen-units",
+                &self.codegen_units.map(|units| units.to_string()),
+            );
+            option("panic", &self.panic);
+        }
+
+        for flag in &self.extra_rustflags {
+            options.push(format!("rustflag={}", flag));
+        }
+
+        f.write_str(&self.name)?;
+        if !options.is_empty() {
+            f.write_fmt(format_args!(":{}", options.join(",")))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for BuildConfig {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        ensure!(!name.is_empty(), "build configuration `{}` has no name", s);
+
+        let mut config = BuildConfig {
+            name: name.to_string(),
+            ..BuildConfig::default()
+        };
+
+        for option in parts
+            .next()
+            .unwrap_or("")
+            .split(',')
+            .filter(|o| !o.is_empty())
+        {
+            let mut kv = option.splitn(2, '=');
+            let key = kv.next().unwrap().trim();
+            let value = match kv.next() {
+                Some(v) => v.trim().to_string(),
+                None => bail!("`{}` in build configuration `{}` has no value", key, name),
+            };
+            // flags are passed through RUSTFLAGS, which cargo splits on whitespace
+            ensure!(
+                !value.contains(char::is_whitespace),
+                "`{}` in build configuration `{}` contains whitespace",
+                key,
+                name
+            );
+
+            match key {
+                "opt-level" => {
+                    ensure!(
+                        ["0", "1", "2", "3", "s", "z"].contains(&value.as_str()),
+                        "invalid opt-level `{}`",
+                        value
+                    );
+                    config.opt_level = Some(value);
+                }
+                "target-cpu" => config.target_cpu = Some(value),
+                "lto" => {
+                    ensure!(
+                        ["off", "thin", "fat"].contains(&value.as_str()),
+                        "invalid lto mode `{}`",
+                        value
+                    );
+                    config.lto = Some(value);
+                }
+                "codegen-units" => config.codegen_units = Some(value.parse()?),
+                "panic" => {
+                    ensure!(
+                        ["unwind", "abort"].contains(&value.as_str()),
+                        "invalid panic strategy `{}`",
+                        value
+                    );
+                    config.panic = Some(value);
+                }
+                "rustflag" => config.extra_rustflags.push(value),
+                other => bail!("unknown build configuration option `{}`", other),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_build_config() {
+        let cfg: BuildConfig = "thin-lto:lto=thin,codegen-units=16,rustflag=-Cdebuginfo=1"
+            .parse()
+            .unwrap();
+
+        assert_eq!(cfg.name, "thin-lto");
+        assert_eq!(cfg.lto, Some(String::from("thin")));
+        assert_eq!(cfg.codegen_units, Some(16));
+        assert_eq!(cfg.extra_rustflags, vec!["-Cdebuginfo=1"]);
+        assert_eq!(cfg.rustflags_env(), "-C codegen-units=16 -Cdebuginfo=1");
+        assert_eq!(cfg.binary_rustflags(), vec!["-C", "lto=thin"]);
+
+        assert_eq!(
+            cfg.to_string(),
+            "thin-lto:lto=thin,codegen-units=16,rustflag=-Cdebuginfo=1"
+        );
+        assert_eq!(cfg.to_string().parse::<BuildConfig>().unwrap(), cfg);
+        assert_eq!("plain".parse::<BuildConfig>().unwrap().to_string(), "plain");
+    }
+
+    #[test]
+    fn rejects_invalid_build_configs() {
+        assert!("opt2:opt-level=4".parse::<BuildConfig>().is_err());
+        assert!(":opt-level=2".parse::<BuildConfig>().is_err());
+        assert!("native:target-cpu".parse::<BuildConfig>().is_err());
+        assert!("lto:lto=sometimes".parse::<BuildConfig>().is_err());
+        assert!("unknown:frobnicate=1".parse::<BuildConfig>().is_err());
+        assert!("spaced:rustflag=-C debuginfo=1"
+            .parse::<BuildConfig>()
+            .is_err());
+    }
+
+    #[test]
+    #[ignore] // runs whichever rustc is on the PATH
+    fn rustc_checks_build_configs() {
+        let rustc = || Command::new("rustc");
+        let cfg: BuildConfig = "thin-lto:lto=thin,codegen-units=16".parse().unwrap();
+        cfg.ensure_accepted_by(rustc()).unwrap();
+
+        let bogus: BuildConfig = "bogus:rustflag=-Cbogus=1".parse().unwrap();
+        assert!(bogus.ensure_accepted_by(rustc()).is_err());
+    }
+}
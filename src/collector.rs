@@ -2,14 +2,25 @@ use super::Result;
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 
+use chrono::{Duration, NaiveDateTime, Utc};
 use itertools::Itertools;
 use serde_json;
 
-use run_plan::RunPlan;
+use cpu_shield::{CpuSet, ResourceLimitExceeded, RunLimits, Shield, ShieldSpec, TimedOut};
+use dry_run::PlannedWork;
+use host::HostFingerprint;
+use run_plan::{BinaryChanged, BuildMetrics, Built, CommandFailed, RunPlan};
 use signal::exit_if_needed;
-use storage::{index, measurement, Entry, Estimates, GitStore, Statistic, StorageKey};
-use toolchain::Toolchain;
+use storage::{
+    combine_estimates, index, measurement, process_runs, toolchains, unavailable, Artifact,
+    BinaryHash, Entry, Estimates, GitStore, Statistic, StorageKey,
+};
+use toolchain::{Rustup, Toolchain, ToolchainProvider, Unavailable};
+use toolchain_cache::{CacheLimits, ToolchainCache};
 use website::build_website;
 
 pub type CollectionResult<T> = ::std::result::Result<T, self::Error>;
@@ -24,6 +35,52 @@ pub enum ErrorKind {
     Build(String),
     Run(String),
     PostProcess(String),
+    /// The benchmark was killed for running longer than its wall-clock limit.
+    Timeout(String),
+    /// The benchmark was stopped for exceeding its memory or CPU time limit.
+    ResourceLimit(String),
+}
+
+impl Error {
+    /// Classifies an error from running a benchmark, so that runs which were killed for exceeding
+    /// their limits are recorded as such.
+    fn run(why: ::failure::Error) -> Self {
+        let kind = if why.downcast_ref::<TimedOut>().is_some() {
+            ErrorKind::Timeout(why.to_string())
+        } else if why.downcast_ref::<ResourceLimitExceeded>().is_some() {
+            ErrorKind::ResourceLimit(why.to_string())
+        } else {
+            ErrorKind::Run(why.to_string())
+        };
+        Error { kind }
+    }
+}
+
+/// How long to wait before trying to install a toolchain which couldn't be found. The wait doubles
+/// after each failed attempt, up to a maximum, since a nightly which is missing for more than a day
+/// or two is unlikely to ever be published.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct RetryPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::hours(6),
+            max: Duration::days(7),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The earliest time (UTC) at which we should look for the toolchain again.
+    pub fn next_attempt(&self, record: &unavailable::Record) -> NaiveDateTime {
+        let doublings = ::std::cmp::min(record.attempts.saturating_sub(1), 16);
+        let wait = ::std::cmp::min(self.initial * 2i32.pow(doublings), self.max);
+        record.last_checked + wait
+    }
 }
 
 /// Runs benchmarks, memoizes their results, and allows results to be shared across multiple
@@ -32,21 +89,82 @@ pub struct Collector {
     storage: GitStore,
     data_dir: PathBuf,
     site_dir: PathBuf,
+    provider: Arc<dyn ToolchainProvider>,
+    retry_policy: RetryPolicy,
+    cache: ToolchainCache,
+    build_jobs: Option<u32>,
+    run_limits: RunLimits,
+    process_runs: u32,
+    interleave_runs: bool,
+    compare_across_hosts: bool,
 }
 
 impl Collector {
     /// Open a Collector. Creates the passed path and initializes a git repo there if it does not
-    /// already exist.
+    /// already exist. Toolchains are installed with rustup unless another provider is set.
     pub fn new(data_dir: &Path, site_dir: &Path) -> Result<Self> {
         ::std::fs::create_dir_all(data_dir)?;
         let storage = GitStore::ensure_initialized(data_dir)?;
+        let cache = ToolchainCache::open(&storage, CacheLimits::default())?;
         Ok(Collector {
             storage,
             data_dir: data_dir.to_owned(),
             site_dir: site_dir.to_owned(),
+            provider: Arc::new(Rustup),
+            retry_policy: RetryPolicy::default(),
+            cache,
+            build_jobs: None,
+            run_limits: RunLimits::default(),
+            process_runs: 1,
+            interleave_runs: false,
+            compare_across_hosts: false,
         })
     }
 
+    /// Use a different source of toolchains, e.g. a directory of locally built compilers.
+    pub fn with_provider(mut self, provider: Arc<dyn ToolchainProvider>) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Bound how many toolchains and how much target directory space are kept between runs.
+    pub fn with_cache_limits(mut self, limits: CacheLimits) -> Self {
+        self.cache.set_limits(limits);
+        self
+    }
+
+    /// Limit how many jobs cargo runs when building benchmarks.
+    pub fn with_build_jobs(mut self, build_jobs: Option<u32>) -> Self {
+        self.build_jobs = build_jobs;
+        self
+    }
+
+    /// Limit the wall-clock time, memory and CPU time each benchmark run may use.
+    pub fn with_run_limits(mut self, run_limits: RunLimits) -> Self {
+        self.run_limits = run_limits;
+        self
+    }
+
+    /// Measure each binary with several independent runs of its process, whose estimates are
+    /// combined. If interleaved, every benchmark for a toolchain gets its first run before any
+    /// gets its second, and so on.
+    pub fn with_process_runs(mut self, process_runs: u32, interleave: bool) -> Self {
+        self.process_runs = ::std::cmp::max(process_runs, 1);
+        self.interleave_runs = interleave;
+        self
+    }
+
+    /// Compare results from hosts with different fingerprints when building the website.
+    pub fn with_compare_across_hosts(mut self, compare_across_hosts: bool) -> Self {
+        self.compare_across_hosts = compare_across_hosts;
+        self
+    }
+
     fn batch_commit(
         &mut self,
         toolchain: &Toolchain,
@@ -89,13 +207,22 @@ impl Collector {
             self.storage.push()?;
         }
 
-        build_website(&self.data_dir, &self.site_dir, publish)?;
+        build_website(
+            &self.data_dir,
+            &self.site_dir,
+            publish,
+            self.compare_across_hosts,
+        )?;
 
         Ok(())
     }
 
     /// Run all the passed benchmarks with the given toolchain, installing the toolchain beforehand
-    /// and uninstalling it afterwards if it was installed by us.
+    /// if needed. Afterwards the least recently used toolchains are evicted from the cache.
+    ///
+    /// If the benchmarks all run in the same CPU shield, it's held up for the whole batch and
+    /// upcoming benchmarks are built in the background while the current one is measured. Otherwise
+    /// builds and measurements take turns.
     pub fn run_benches_with_toolchain(
         &mut self,
         toolchain: Toolchain,
@@ -108,34 +235,206 @@ impl Collector {
             self.storage.sync_down()?;
         }
 
-        let _guard = toolchain.ensure_installed()?;
+        if let Err(why) = self.cache.checkout(&toolchain, &*self.provider) {
+            if why.downcast_ref::<Unavailable>().is_some() {
+                self.record_unavailable(&toolchain, publish)?;
+            }
+            return Err(why);
+        }
+
+        // evict even if measuring failed partway, the toolchain and its target dir are still there
+        let measured = self.run_checked_out(&toolchain, run_plans, publish);
+        let evicted = self.cache.evict(&*self.provider, &toolchain);
+        measured.and(evicted)
+    }
+
+    /// Builds and runs each plan with a toolchain which has already been checked out of the cache.
+    fn run_checked_out(
+        &mut self,
+        toolchain: &Toolchain,
+        run_plans: &[RunPlan],
+        publish: bool,
+    ) -> Result<()> {
+        let unavailable_key = unavailable::Key {
+            toolchain: toolchain.clone(),
+        };
+        if self.storage.remove(&unavailable_key)? {
+            info!(
+                "{} is available now, forgetting that it was missing",
+                toolchain
+            );
+        }
+
+        self.ensure_toolchain_metadata(toolchain)?;
+
+        // a kernel or firmware update could land between any two batches
+        let host = HostFingerprint::capture();
+        info!("measuring on {}", host);
+        self.storage.record_host(&host)?;
+
+        let mut needs_build = Vec::new();
+        for rp in run_plans {
+            needs_build.push(self.existing_binary_hash(rp)?.1.is_none());
+        }
+
+        // the shield stays up for the whole batch so that background builds never see it change
+        let batch_shield = run_plans
+            .first()
+            .and_then(|first| first.shield.clone())
+            .filter(|spec| run_plans.iter().all(|rp| rp.shield.as_ref() == Some(spec)));
+        let _shield = match &batch_shield {
+            Some(spec) => Some(Shield::create(spec)?),
+            None => None,
+        };
+
+        let prebuilder = if let Some(spec) = &batch_shield {
+            let to_build = run_plans
+                .iter()
+                .zip(&needs_build)
+                .filter(|&(_, needed)| *needed)
+                .map(|(rp, _)| rp.clone())
+                .collect::<Vec<_>>();
+            info!("building {} benchmarks in the background", to_build.len());
+            Some(Prebuilder::start(
+                to_build,
+                self.provider.clone(),
+                self.build_jobs,
+                spec,
+            )?)
+        } else {
+            None
+        };
 
         let mut results = Vec::new();
 
         let batch_size = 10;
         let mut pushed = 0;
 
-        for rp in run_plans {
+        // interleaved measurements only get their first run as their binaries come in
+        let runs_now = if self.interleave_runs {
+            1
+        } else {
+            self.process_runs
+        };
+        let mut pending = Vec::new();
+
+        for (rp, &needed) in run_plans.iter().zip(&needs_build) {
             exit_if_needed();
 
-            let (status, hexhash) = self.run(rp)?;
-            results.push((status, rp.clone(), hexhash));
+            let (binary_hash_res, build_metrics) = match &prebuilder {
+                Some(builder) if needed => built_entry(index::Key::new(rp), builder.next()?),
+                _ => self.compute_binary_hash(rp)?,
+            };
+
+            match self.run_built(rp, binary_hash_res, build_metrics, runs_now) {
+                Ok(Outcome::Done(status, hexhash)) => results.push((status, rp.clone(), hexhash)),
+                Ok(Outcome::Pending(measurement)) => pending.push(measurement),
+                Err(ref why) if why.downcast_ref::<BinaryChanged>().is_some() => {
+                    // nothing is stored, so it'll be tried again next time
+                    warn!("not measuring {}: {}", rp, why);
+                    continue;
+                }
+                Err(why) => return Err(why),
+            }
 
             pushed += 1;
             if pushed == batch_size {
-                self.batch_commit(&toolchain, &mut results, publish)?;
+                self.batch_commit(toolchain, &mut results, publish)?;
                 pushed = 0;
             }
         }
 
+        for _ in 1..self.process_runs {
+            let mut still_pending = Vec::new();
+            for mut measurement in pending {
+                exit_if_needed();
+                if measurement.error.is_none() {
+                    if let Err(why) = self.run_once(&mut measurement) {
+                        warn!("not measuring {}: {}", measurement.rp, why);
+                        continue;
+                    }
+                }
+                still_pending.push(measurement);
+            }
+            pending = still_pending;
+        }
+
+        for measurement in pending {
+            let rp = measurement.rp.clone();
+            let hexhash = hex_hash(&measurement.binary_hash);
+            self.finish_measurement(measurement)?
+                .ensure_persisted(&mut self.storage)?;
+            results.push((true, rp, Some(hexhash)));
+        }
+
         // pick up any stragglers
-        self.batch_commit(&toolchain, &mut results, publish)?;
+        self.batch_commit(toolchain, &mut results, publish)
+    }
+
+    /// Remember that a toolchain couldn't be found so that we don't look for it again until the
+    /// retry policy says so.
+    fn record_unavailable(&mut self, toolchain: &Toolchain, publish: bool) -> Result<()> {
+        let key = unavailable::Key {
+            toolchain: toolchain.clone(),
+        };
+        let now = Utc::now().naive_utc();
+
+        let record = match self.storage.get(&key)? {
+            Some(existing) => existing.checked_again(now),
+            None => unavailable::Record::new(now),
+        };
+
+        info!(
+            "{} unavailable after {} attempt(s), will retry after {}",
+            toolchain,
+            record.attempts,
+            self.retry_policy.next_attempt(&record)
+        );
+
+        self.storage.set(&key, &record)?;
+        self.storage.commit(&format!(
+            "{} unavailable, attempt {}",
+            toolchain, record.attempts
+        ))?;
+
+        if publish {
+            self.storage.push()?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether we failed to find this toolchain recently enough that we shouldn't look again.
+    fn recently_unavailable(&mut self, toolchain: &Toolchain) -> Result<bool> {
+        let key = unavailable::Key {
+            toolchain: toolchain.clone(),
+        };
+
+        Ok(match self.storage.get(&key)? {
+            Some(record) => Utc::now().naive_utc() < self.retry_policy.next_attempt(&record),
+            None => false,
+        })
+    }
+
+    /// Records the rustc commit and LLVM version behind a toolchain if we haven't already. Assumes
+    /// that the toolchain has already been installed.
+    fn ensure_toolchain_metadata(&mut self, toolchain: &Toolchain) -> Result<()> {
+        let key = toolchains::Key {
+            toolchain: toolchain.clone(),
+        };
+
+        if self.storage.get(&key)?.is_none() {
+            match toolchain.metadata(&*self.provider) {
+                Ok(metadata) => self.storage.set(&key, &metadata)?,
+                Err(why) => warn!("unable to record metadata for {}: {}", toolchain, why),
+            }
+        }
 
         Ok(())
     }
 
     /// Take a list of potential benchmarks to run and filter out any plans for which we have end to
-    /// end results stored already.
+    /// end results stored already, or whose toolchains were recently found to be unavailable.
     pub fn compute_builds_needed(
         &mut self,
         plans: &BTreeMap<Toolchain, BTreeSet<RunPlan>>,
@@ -143,6 +442,11 @@ impl Collector {
         let mut needed = BTreeMap::new();
 
         for (toolchain, run_plans) in plans {
+            if self.recently_unavailable(toolchain)? {
+                debug!("{} was recently unavailable, skipping", toolchain);
+                continue;
+            }
+
             for rp in run_plans {
                 if !self.plan_can_be_skipped_with_no_work(rp)? {
                     needed
@@ -156,12 +460,50 @@ impl Collector {
         Ok(needed)
     }
 
+    /// Works out what measuring each plan would involve, without building or running anything.
+    pub fn classify_plans(
+        &mut self,
+        plans: &BTreeMap<Toolchain, BTreeSet<RunPlan>>,
+    ) -> Result<BTreeMap<Toolchain, Vec<(RunPlan, PlannedWork)>>> {
+        let mut classified = BTreeMap::new();
+
+        for (toolchain, run_plans) in plans {
+            let unavailable = self.recently_unavailable(toolchain)?;
+            let mut for_toolchain = Vec::new();
+
+            for rp in run_plans {
+                // the same checks `compute_builds_needed` prunes plans with
+                let work = if unavailable {
+                    PlannedWork::ToolchainUnavailable
+                } else if self.plan_can_be_skipped_with_no_work(rp)? {
+                    PlannedWork::Stored
+                } else {
+                    match self.existing_binary_hash(rp)? {
+                        (_, Some(Err(_))) => PlannedWork::PreviouslyFailed,
+                        // `exec` rebuilds binaries which have been evicted since they were hashed
+                        (_, Some(Ok(_))) if rp.binary_path().is_file() => PlannedWork::Run,
+                        _ => PlannedWork::BuildAndRun,
+                    }
+                };
+                for_toolchain.push((rp.clone(), work));
+            }
+
+            classified.insert(toolchain.clone(), for_toolchain);
+        }
+
+        Ok(classified)
+    }
+
+    pub fn process_runs(&self) -> u32 {
+        self.process_runs
+    }
+
     /// Check to see if we need to do anything with this RunPlan. Used for conveniently pruning
     /// the list of benchmarks before we start installing toolchains and building binaries.
     fn plan_can_be_skipped_with_no_work(&mut self, rp: &RunPlan) -> Result<bool> {
         Ok(
             if let (_, Some(Ok(hash))) = self.existing_binary_hash(rp)? {
-                if let (_, Some(_)) = self.existing_estimates(rp, &hash)? {
+                if let (_, Some(_)) = self.existing_estimates(rp, &hash.normalized)? {
                     true
                 } else {
                     false
@@ -173,17 +515,16 @@ impl Collector {
     }
 
     /// Builds a benchmark binary for the current runner if it not been previously built and run.
-    fn compute_binary_hash(&mut self, rp: &RunPlan) -> Result<Entry<index::Key>> {
+    /// Also returns the build's metrics if it had to be built.
+    fn compute_binary_hash(
+        &mut self,
+        rp: &RunPlan,
+    ) -> Result<(Entry<index::Key>, Option<BuildMetrics>)> {
         let (key, maybe_existing) = self.existing_binary_hash(rp)?;
 
         Ok(match maybe_existing {
-            Some(r) => Entry::Existing(r),
-            None => Entry::New(
-                key,
-                rp.build().map_err(|e| Error {
-                    kind: ErrorKind::Build(e.to_string()),
-                }),
-            ),
+            Some(r) => (Entry::Existing(r), None),
+            None => built_entry(key, rp.build(&*self.provider, self.build_jobs, None)),
         })
     }
 
@@ -191,39 +532,237 @@ impl Collector {
     fn existing_binary_hash(
         &mut self,
         rp: &RunPlan,
-    ) -> Result<(index::Key, Option<CollectionResult<Vec<u8>>>)> {
+    ) -> Result<(index::Key, Option<CollectionResult<BinaryHash>>)> {
         let ikey = index::Key::new(&rp);
         let found = self.storage.get(&ikey)?;
         Ok((ikey, found))
     }
 
     /// Runs a benchmark for the current runner if the results have not previously been recorded.
+    /// The metrics of the build which produced the binary are stored with its runtime estimates.
+    ///
+    /// Returns a measurement which still needs more process runs if `runs` is fewer than the
+    /// collector is configured to take.
     fn compute_estimates(
         &mut self,
         rp: &RunPlan,
-        binary_hash: &[u8],
-    ) -> Result<Entry<measurement::Key>> {
-        let (mkey, maybe_existing) = self.existing_estimates(rp, binary_hash)?;
+        binary_hash: &BinaryHash,
+        build_metrics: Option<BuildMetrics>,
+        runs: u32,
+    ) -> Result<::std::result::Result<Entry<measurement::Key>, PendingMeasurement>> {
+        let (mkey, maybe_existing) = self.existing_estimates(rp, &binary_hash.normalized)?;
 
-        let res = match maybe_existing {
-            Some(e) => Entry::Existing(e),
-            None => {
-                let res = rp
-                    .exec()
-                    .map_err(|why| Error {
-                        kind: ErrorKind::Run(why.to_string()),
-                    })
-                    .and_then(|()| {
-                        self.process(&rp).map_err(|why| Error {
+        if let Some(e) = maybe_existing {
+            return Ok(Ok(Entry::Existing(e)));
+        }
+
+        let mut measurement = PendingMeasurement {
+            key: mkey,
+            rp: rp.clone(),
+            binary_hash: binary_hash.clone(),
+            build_metrics,
+            runs: Vec::new(),
+            artifacts: Vec::new(),
+            error: None,
+        };
+
+        for _ in 0..runs {
+            self.run_once(&mut measurement)?;
+            if measurement.error.is_some() {
+                break;
+            }
+        }
+
+        if measurement.error.is_none() && measurement.runs.len() < self.process_runs as usize {
+            Ok(Err(measurement))
+        } else {
+            Ok(Ok(self.finish_measurement(measurement)?))
+        }
+    }
+
+    /// Runs a measurement's binary once more, recording its estimates or why it failed. Fails
+    /// without recording anything if the binary isn't the one being measured anymore.
+    fn run_once(&mut self, measurement: &mut PendingMeasurement) -> Result<()> {
+        let run = measurement.runs.len() + 1;
+        let mut artifacts = Vec::new();
+
+        match self.exec(&measurement.rp, &measurement.binary_hash) {
+            Ok((rebuilt, log)) => {
+                artifacts.push(log);
+                if run == 1 {
+                    match measurement.rp.disassemble() {
+                        Ok(disassembly) => artifacts.push(disassembly),
+                        Err(why) => warn!("unable to disassemble {}: {}", measurement.rp, why),
+                    }
+                }
+
+                if rebuilt.is_some() {
+                    measurement.build_metrics = rebuilt;
+                }
+
+                match self.process(&measurement.rp) {
+                    Ok(estimates) => measurement.runs.push(estimates),
+                    Err(why) => {
+                        measurement.error = Some(Error {
                             kind: ErrorKind::Run(why.to_string()),
                         })
-                    });
+                    }
+                }
+            }
+            Err(why) => {
+                if why.downcast_ref::<BinaryChanged>().is_some() {
+                    return Err(why);
+                }
+                artifacts.extend(failure_log(&why));
+                measurement.error = Some(Error::run(why));
+            }
+        }
 
-                Entry::New(mkey, res)
+        // the first run's log keeps the name it had before there could be more than one
+        for artifact in &mut artifacts {
+            if run > 1 && artifact.name == "run-log" {
+                artifact.name = format!("run-log-{}", run);
             }
+        }
+        measurement.artifacts.extend(artifacts);
+        Ok(())
+    }
+
+    /// Combines a measurement's runs into the estimates to store, storing each run's estimates
+    /// separately if there was more than one.
+    fn finish_measurement(
+        &mut self,
+        measurement: PendingMeasurement,
+    ) -> Result<Entry<measurement::Key>> {
+        let PendingMeasurement {
+            key,
+            rp,
+            build_metrics,
+            runs,
+            artifacts,
+            error,
+            ..
+        } = measurement;
+
+        let res = match error {
+            Some(error) => Err(error),
+            None => {
+                let mut estimates = combine_estimates(&runs);
+
+                if runs.len() > 1 {
+                    let runs = process_runs::Runs::new(runs);
+                    if let Some(spread) = runs.between_runs_std_dev.get("nanoseconds") {
+                        info!(
+                            "{} nanoseconds/iteration varied by {:.2} (std dev) across {} runs",
+                            rp,
+                            spread,
+                            runs.estimates.len()
+                        );
+                    }
+
+                    let runs_key = process_runs::Key {
+                        measurement: key.clone(),
+                    };
+                    self.storage.set(&runs_key, &runs)?;
+                }
+
+                if let Some(metrics) = build_metrics {
+                    estimates.extend(metrics.estimates());
+                }
+
+                Ok(estimates)
+            }
+        };
+
+        Ok(Entry::New(key, res, artifacts))
+    }
+
+    /// Looks up the stored results of a plan which has already been run, returning `None` if it
+    /// hasn't been or if its binary failed to build.
+    pub fn stored_estimates(
+        &mut self,
+        rp: &RunPlan,
+    ) -> Result<Option<CollectionResult<Estimates>>> {
+        Ok(match self.existing_binary_hash(rp)? {
+            (_, Some(Ok(hash))) => self.existing_estimates(rp, &hash.normalized)?.1,
+            _ => None,
+        })
+    }
+
+    /// Profiles a plan whose results are already stored under `perf record`, storing the stacks it
+    /// sampled with those results. Returns whether a profile was stored. Plans which haven't been
+    /// measured or were already profiled are skipped. The profile comes from a build of the plan
+    /// for profiling (see `RunPlan::for_profiling`), for which its toolchain is checked out of the
+    /// toolchain cache.
+    pub fn profile(&mut self, rp: &RunPlan) -> Result<bool> {
+        let binary_hash = match self.existing_binary_hash(rp)? {
+            (_, Some(Ok(hash))) => hash,
+            _ => return Ok(false),
         };
 
-        Ok(res)
+        let mkey = match self.existing_estimates(rp, &binary_hash.normalized)? {
+            (mkey, Some(Ok(_))) => mkey,
+            _ => return Ok(false),
+        };
+
+        if self.data_dir.join(mkey.artifact_path("profile")).is_file() {
+            debug!("{} has already been profiled", rp);
+            return Ok(false);
+        }
+
+        if let Some(toolchain) = &rp.toolchain {
+            self.cache.checkout(toolchain, &*self.provider)?;
+        }
+
+        info!("profiling {}", rp);
+        let profiling = rp.for_profiling();
+        let limits = self.run_limits;
+        let profiled = profiling
+            .build(&*self.provider, self.build_jobs, None)
+            .and_then(|_| profiling.profile(limits));
+        if let Some(toolchain) = &rp.toolchain {
+            self.cache.evict(&*self.provider, toolchain)?;
+        }
+
+        self.storage.add_artifacts(&mkey, &[profiled?])?;
+        Ok(true)
+    }
+
+    /// Runs a plan's binary, rebuilding it first if its target directory has been evicted from the
+    /// toolchain cache since its hash was recorded. Returns the rebuild's metrics if there was one,
+    /// along with the run's log.
+    fn exec(
+        &mut self,
+        rp: &RunPlan,
+        binary_hash: &BinaryHash,
+    ) -> Result<(Option<BuildMetrics>, Artifact)> {
+        let rebuilt = self.rebuild_if_evicted(rp, binary_hash)?;
+        let log = rp.exec(binary_hash, self.run_limits)?;
+        Ok((rebuilt, log))
+    }
+
+    /// Builds a plan's binary again if its target directory has been evicted from the toolchain
+    /// cache since its hash was recorded, returning the build's metrics if it had to be.
+    fn rebuild_if_evicted(
+        &mut self,
+        rp: &RunPlan,
+        binary_hash: &BinaryHash,
+    ) -> Result<Option<BuildMetrics>> {
+        Ok(if !rp.binary_path().is_file() {
+            info!("{} isn't on disk anymore, rebuilding", rp);
+            let built = rp.build(&*self.provider, self.build_jobs, None)?;
+
+            // the code is what's measured, but the rest of the binary needn't be reproducible
+            if built.binary_hash.normalized == binary_hash.normalized
+                && built.binary_hash.raw != binary_hash.raw
+            {
+                self.storage
+                    .set(&index::Key::new(rp), &Ok(built.binary_hash))?;
+            }
+            Some(built.metrics)
+        } else {
+            None
+        })
     }
 
     /// Check to see if we have already have measurements for this benchmark.
@@ -239,6 +778,7 @@ impl Collector {
             binary_hash.to_vec(),
             rp.benchmark.runner.clone(),
             rp.shield.clone(),
+            rp.bench_config.clone(),
         );
 
         let found = self.storage.get(&mkey)?;
@@ -253,35 +793,39 @@ impl Collector {
     ///
     /// Assumes that the `RunPlan`'s toolchain has already been installed.
     pub fn run(&mut self, rp: &RunPlan) -> Result<(bool, Option<String>)> {
-        let binary_hash_res = self.compute_binary_hash(rp)?;
+        let (binary_hash_res, build_metrics) = self.compute_binary_hash(rp)?;
+        let runs = self.process_runs;
+        match self.run_built(rp, binary_hash_res, build_metrics, runs)? {
+            Outcome::Done(status, hexhash) => Ok((status, hexhash)),
+            Outcome::Pending(_) => unreachable!("all of a plan's runs were requested"),
+        }
+    }
 
+    /// Runs a plan whose binary has already been built (or failed to build), giving it up to
+    /// `runs` process runs.
+    fn run_built(
+        &mut self,
+        rp: &RunPlan,
+        binary_hash_res: Entry<index::Key>,
+        build_metrics: Option<BuildMetrics>,
+        runs: u32,
+    ) -> Result<Outcome> {
         binary_hash_res
             .clone()
             .ensure_persisted(&mut self.storage)?;
 
-        let (estimates, binary_hash) = if let Ok(hash) = &*binary_hash_res {
-            (Some(self.compute_estimates(rp, &*hash)?), Some(hash))
-        } else {
-            (None, None)
-        };
-
-        let status = if let Some(e) = estimates {
-            e.ensure_persisted(&mut self.storage)?;
-            true
-        } else {
-            false
+        let binary_hash = match &*binary_hash_res {
+            Ok(hash) => hash,
+            Err(_) => return Ok(Outcome::Done(false, None)),
         };
 
-        let hexhash = binary_hash.map(|h| {
-            h.into_iter()
-                .map(|d| format!("{:x}", d))
-                .fold(String::new(), |mut acc, x| {
-                    acc.push_str(&x);
-                    acc
-                })
-        });
-
-        Ok((status, hexhash))
+        match self.compute_estimates(rp, binary_hash, build_metrics, runs)? {
+            Ok(estimates) => {
+                estimates.ensure_persisted(&mut self.storage)?;
+                Ok(Outcome::Done(true, Some(hex_hash(binary_hash))))
+            }
+            Err(pending) => Ok(Outcome::Pending(pending)),
+        }
     }
 
     /// Parses the results of a benchmark. This assumes that the benchmark has already been
@@ -289,13 +833,8 @@ impl Collector {
     fn process(&self, rp: &RunPlan) -> Result<Estimates> {
         info!("post-processing {}", rp);
 
-        let target_dir = rp
-            .toolchain
-            .as_ref()
-            .map(Toolchain::target_dir)
-            .unwrap_or_else(|| PathBuf::from("target"));
-
-        let path = target_dir
+        let path = rp
+            .target_dir()
             .join("criterion")
             .join(format!(
                 "{}::{}",
@@ -327,9 +866,299 @@ impl Collector {
             let estimates: Estimates = serde_json::from_str(&metrics_estimates_json)?;
             metrics_estimates.extend(estimates);
         } else {
-            warn!("couldn't read metrics-estimates.json for {}", rp);
+            // e.g. in a VM, or where perf_event_paranoid doesn't allow counting
+            warn!(
+                "couldn't read metrics-estimates.json for {}, only recording its runtime",
+                rp
+            );
         }
 
         Ok(metrics_estimates)
     }
 }
+
+/// How far `Collector::run_built` got with a plan.
+enum Outcome {
+    /// Whether the plan's results were stored, and the hash of its binary if it was built.
+    Done(bool, Option<String>),
+    /// The plan's binary needs more process runs before its results are stored.
+    Pending(PendingMeasurement),
+}
+
+/// A measurement whose process runs are still being taken.
+struct PendingMeasurement {
+    key: measurement::Key,
+    rp: RunPlan,
+    binary_hash: BinaryHash,
+    build_metrics: Option<BuildMetrics>,
+    runs: Vec<Estimates>,
+    artifacts: Vec<Artifact>,
+    /// Set by the first run to fail, after which no more are taken.
+    error: Option<Error>,
+}
+
+fn hex_hash(binary_hash: &BinaryHash) -> String {
+    binary_hash
+        .normalized
+        .iter()
+        .map(|d| format!("{:x}", d))
+        .fold(String::new(), |mut acc, x| {
+            acc.push_str(&x);
+            acc
+        })
+}
+
+/// Turns the outcome of a build into the index entry to store, along with the metrics to store
+/// with the binary's estimates.
+fn built_entry(key: index::Key, built: Result<Built>) -> (Entry<index::Key>, Option<BuildMetrics>) {
+    match built {
+        Ok(Built {
+            binary_hash,
+            metrics,
+            log,
+        }) => (Entry::New(key, Ok(binary_hash), vec![log]), Some(metrics)),
+        Err(e) => {
+            let artifacts = failure_log(&e).into_iter().collect();
+            let error = Error {
+                kind: ErrorKind::Build(e.to_string()),
+            };
+            (Entry::New(key, Err(error), artifacts), None)
+        }
+    }
+}
+
+/// The log of a failed build or run, if it got far enough to produce any output. Runs which were
+/// stopped for exceeding a limit keep whatever they wrote before then.
+fn failure_log(why: &::failure::Error) -> Option<Artifact> {
+    if let Some(failed) = why.downcast_ref::<CommandFailed>() {
+        Some(failed.log.clone())
+    } else if let Some(timed_out) = why.downcast_ref::<TimedOut>() {
+        let (stdout, stderr) = (&timed_out.stdout, &timed_out.stderr);
+        Some(Artifact::streams(
+            "run-log",
+            stdout.as_bytes(),
+            stderr.as_bytes(),
+        ))
+    } else if let Some(exceeded) = why.downcast_ref::<ResourceLimitExceeded>() {
+        let (stdout, stderr) = (&exceeded.stdout, &exceeded.stderr);
+        Some(Artifact::streams(
+            "run-log",
+            stdout.as_bytes(),
+            stderr.as_bytes(),
+        ))
+    } else {
+        None
+    }
+}
+
+/// Builds plans on a background thread so that upcoming benchmarks are ready by the time the
+/// current one has been measured. Only safe with a CPU shield which stays up for the whole batch,
+/// and the builds are pinned to the CPUs outside of it as well.
+struct Prebuilder {
+    built: Receiver<Result<Built>>,
+    _thread: JoinHandle<()>,
+}
+
+impl Prebuilder {
+    fn start(
+        plans: Vec<RunPlan>,
+        provider: Arc<dyn ToolchainProvider>,
+        build_jobs: Option<u32>,
+        shield: &ShieldSpec,
+    ) -> Result<Self> {
+        let unshielded = CpuSet::online()?.without(&shield.cpus()?);
+        ensure!(
+            !unshielded.is_empty(),
+            "no cpus outside of the {} shield to build on",
+            shield
+        );
+
+        // a single finished build is enough to keep measurements busy, there's no point in filling
+        // the disk with binaries that won't be run for a while
+        let (tx, built) = sync_channel(1);
+
+        let thread = thread::spawn(move || {
+            for rp in plans {
+                let built = rp
+                    .build(&*provider, build_jobs, Some(&unshielded))
+                    .map(|mut built| {
+                        built.metrics.concurrent = true;
+                        built
+                    });
+                if tx.send(built).is_err() {
+                    debug!("no longer measuring, stopping background builds");
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            built,
+            _thread: thread,
+        })
+    }
+
+    /// The result of the next build, in the order the plans were passed.
+    fn next(&self) -> Result<Result<Built>> {
+        self.built
+            .recv()
+            .map_err(|_| format_err!("background builds stopped unexpectedly"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use marky_mark::Benchmark;
+    use tempfile::tempdir;
+
+    use toolchain::CargoToolchain;
+    use {r32, CriterionConfig, CriterionOverrides};
+
+    /// Keeps track of which toolchains are "installed" without touching rustup, and builds with
+    /// whichever rustc is on the `PATH`.
+    #[derive(Debug, Default)]
+    struct FakeProvider {
+        installed: Mutex<BTreeSet<Toolchain>>,
+        /// Toolchains which this provider pretends were never published.
+        missing: BTreeSet<Toolchain>,
+    }
+
+    impl ToolchainProvider for FakeProvider {
+        fn is_installed(&self, toolchain: &Toolchain) -> Result<bool> {
+            Ok(self.installed.lock().unwrap().contains(toolchain))
+        }
+
+        fn install(&self, toolchain: &Toolchain) -> Result<()> {
+            if self.missing.contains(toolchain) {
+                return Err(Unavailable {
+                    toolchain: toolchain.clone(),
+                }
+                .into());
+            }
+            self.installed.lock().unwrap().insert(toolchain.clone());
+            Ok(())
+        }
+
+        fn uninstall(&self, toolchain: &Toolchain) -> Result<()> {
+            self.installed.lock().unwrap().remove(toolchain);
+            Ok(())
+        }
+
+        fn cargo_toolchain(&self, _: &Toolchain) -> CargoToolchain {
+            CargoToolchain::Rustc(PathBuf::from("rustc"))
+        }
+    }
+
+    fn quickcheck_plan(toolchain: &Toolchain) -> RunPlan {
+        let entrypoint_path = Path::new("benches")
+            .join("quickcheck_0_6_1")
+            .join("src")
+            .join("bin")
+            .join("shrink-string-1-tuple.rs");
+
+        RunPlan::new(
+            Benchmark {
+                runner: None,
+                name: String::from("shrink_string_1_tuple"),
+                crate_name: String::from("quickcheck_0_6_1"),
+                entrypoint_path: entrypoint_path.clone(),
+                criterion: CriterionOverrides::default(),
+                count_allocations: false,
+            },
+            Some(CriterionConfig {
+                confidence_level: r32(0.95),
+                measurement_time_ms: 500,
+                nresamples: 2,
+                noise_threshold: r32(0.0),
+                sample_size: 5,
+                significance_level: r32(0.05),
+                warm_up_time_ms: 1,
+            }),
+            None,
+            None,
+            Some(toolchain.clone()),
+            Path::new(env!("CARGO_MANIFEST_DIR")).join(entrypoint_path),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn runs_plans_with_a_fake_provider() {
+        let _ = ::simple_logger::init();
+        let data_dir = tempdir().unwrap();
+        let site_dir = tempdir().unwrap();
+
+        let available = Toolchain::from("fake-stage1");
+        let missing = Toolchain::from("fake-stage2");
+        let provider = Arc::new(FakeProvider {
+            missing: vec![missing.clone()].into_iter().collect(),
+            ..FakeProvider::default()
+        });
+
+        let mut collector = Collector::new(data_dir.path(), site_dir.path())
+            .unwrap()
+            .with_provider(provider.clone())
+            .with_cache_limits(CacheLimits {
+                max_toolchains: 0,
+                max_target_bytes: 0,
+            });
+
+        let mut plans = BTreeMap::new();
+        for toolchain in &[&available, &missing] {
+            plans
+                .entry((*toolchain).clone())
+                .or_insert_with(BTreeSet::new)
+                .insert(quickcheck_plan(toolchain));
+        }
+        assert_eq!(collector.compute_builds_needed(&plans).unwrap().len(), 2);
+
+        let plan = quickcheck_plan(&available);
+        collector
+            .run_benches_with_toolchain(available.clone(), &[plan.clone()], false)
+            .unwrap();
+        match collector.stored_estimates(&plan).unwrap() {
+            Some(Ok(estimates)) => assert!(estimates.contains_key("nanoseconds")),
+            other => panic!("no estimates stored for {}: {:?}", plan, other),
+        }
+        // the cache has no room to keep it installed
+        assert!(provider.installed.lock().unwrap().is_empty());
+
+        let why = collector
+            .run_benches_with_toolchain(missing.clone(), &[quickcheck_plan(&missing)], false)
+            .unwrap_err();
+        assert!(why.downcast_ref::<Unavailable>().is_some());
+
+        // one has results stored and the other was just found to be unavailable
+        assert!(collector.compute_builds_needed(&plans).unwrap().is_empty());
+
+        // measuring with other settings needs the evicted binary to be built again
+        let mut remeasured = quickcheck_plan(&available);
+        remeasured.bench_config.as_mut().unwrap().sample_size = 10;
+        plans
+            .get_mut(&available)
+            .unwrap()
+            .insert(remeasured.clone());
+
+        let classified = collector.classify_plans(&plans).unwrap();
+        let work = |toolchain: &Toolchain, rp: &RunPlan| {
+            classified[toolchain]
+                .iter()
+                .find(|(planned, _)| planned == rp)
+                .map(|(_, work)| *work)
+        };
+        assert!(!plan.binary_path().is_file());
+        assert_eq!(work(&available, &plan), Some(PlannedWork::Stored));
+        assert_eq!(
+            work(&available, &remeasured),
+            Some(PlannedWork::BuildAndRun)
+        );
+        assert_eq!(
+            work(&missing, &quickcheck_plan(&missing)),
+            Some(PlannedWork::ToolchainUnavailable)
+        );
+    }
+}
@@ -1,24 +1,40 @@
 use super::Result;
 
+use std::collections::BTreeSet;
 use std::ffi::OsStr;
 use std::fmt::{Display, Formatter, Result as FmtResult};
-use std::io;
-use std::process::{Child, Command, ExitStatus, Output};
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
 pub struct ShieldSpec {
+    /// Always in `CpuSet`'s canonical form, so that equivalent masks share stored results.
     pub cpu_mask: String,
     pub kthread_on: bool,
 }
 
 impl ShieldSpec {
-    pub fn new(cpu_mask: String, kthread_on: bool) -> Result<Self> {
-        // FIXME(anp): these arguments need to be validated
+    /// Checks that the mask only names CPUs which are online before anything is run with it.
+    pub fn new(cpu_mask: &str, kthread_on: bool) -> Result<Self> {
+        let cpus = cpu_mask.parse::<CpuSet>()?;
+        cpus.ensure_within(&CpuSet::online()?)?;
+
         Ok(Self {
-            cpu_mask,
+            cpu_mask: cpus.to_string(),
             kthread_on,
         })
     }
+
+    pub fn cpus(&self) -> Result<CpuSet> {
+        self.cpu_mask.parse()
+    }
 }
 
 impl Display for ShieldSpec {
@@ -31,6 +47,156 @@ impl Display for ShieldSpec {
     }
 }
 
+/// Where the kernel lists the CPUs which can currently be scheduled on.
+const ONLINE_CPUS: &str = "/sys/devices/system/cpu/online";
+
+/// A set of CPUs, written the way the kernel and cset write them: a comma-separated list of CPU
+/// numbers and inclusive ranges, e.g. `0-2,5`.
+#[derive(Clone, Debug, Default, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub struct CpuSet(BTreeSet<u32>);
+
+impl CpuSet {
+    /// The CPUs which are currently online, according to sysfs.
+    pub fn online() -> Result<Self> {
+        let listed = fs::read_to_string(ONLINE_CPUS).map_err(|why| {
+            format_err!("unable to read online cpus from {}: {}", ONLINE_CPUS, why)
+        })?;
+        listed.trim().parse()
+    }
+
+    pub fn contains(&self, cpu: u32) -> bool {
+        self.0.contains(&cpu)
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = u32> + 'a {
+        self.0.iter().cloned()
+    }
+
+    /// The CPUs in this set which aren't in `other`.
+    pub fn without(&self, other: &CpuSet) -> CpuSet {
+        CpuSet(self.0.difference(&other.0).cloned().collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn ensure_within(&self, online: &CpuSet) -> Result<()> {
+        let missing = self
+            .0
+            .difference(&online.0)
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        ensure!(
+            missing.is_empty(),
+            "cpus {} aren't online (online cpus are {})",
+            CpuSet(missing),
+            online
+        );
+        Ok(())
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut cpus = BTreeSet::new();
+
+        for part in s.split(',').map(str::trim) {
+            ensure!(!part.is_empty(), "empty entry in cpu list `{}`", s);
+
+            let mut bounds = part.splitn(2, '-');
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<u32>()
+                    .map_err(|_| format_err!("invalid cpu `{}` in cpu list `{}`", n, s))
+            };
+            let start = parse(bounds.next().unwrap())?;
+            let end = match bounds.next() {
+                Some(end) => parse(end)?,
+                None => start,
+            };
+            ensure!(
+                start <= end,
+                "backwards range `{}` in cpu list `{}`",
+                part,
+                s
+            );
+
+            cpus.extend(start..=end);
+        }
+
+        Ok(CpuSet(cpus))
+    }
+}
+
+/// Formatted canonically, with CPUs in ascending order and runs of two or more written as ranges.
+impl Display for CpuSet {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for cpu in self.iter() {
+            if let Some(range) = ranges.last_mut() {
+                if range.1 + 1 == cpu {
+                    range.1 = cpu;
+                    continue;
+                }
+            }
+            ranges.push((cpu, cpu));
+        }
+
+        let ranges = ranges
+            .into_iter()
+            .map(|(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{}-{}", start, end)
+                }
+            })
+            .collect::<Vec<_>>();
+        f.write_str(&ranges.join(","))
+    }
+}
+
+/// Limits on a single run of a benchmark binary, so that a hung or runaway benchmark can't stall
+/// the whole runner. `None` leaves that resource unlimited, which is the default for all of them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, PartialOrd, Ord)]
+pub struct RunLimits {
+    /// Wall-clock time after which the run's whole process group is killed.
+    pub timeout: Option<Duration>,
+    /// The largest address space the benchmark may map, in bytes (`RLIMIT_AS`).
+    pub max_memory_bytes: Option<u64>,
+    /// How much CPU time the benchmark may use, in seconds (`RLIMIT_CPU`).
+    pub max_cpu_seconds: Option<u64>,
+}
+
+/// Returned when a run was killed for taking longer than its wall-clock limit.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "killed after running for more than {:?}. stdout: {}, stderr: {}",
+    timeout, stdout, stderr
+)]
+pub struct TimedOut {
+    pub timeout: Duration,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Returned when a run was stopped by one of its rlimits.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "exceeded its {} limit of {}. stdout: {}, stderr: {}",
+    resource, limit, stdout, stderr
+)]
+pub struct ResourceLimitExceeded {
+    pub resource: &'static str,
+    /// The limit which was exceeded, e.g. `60s`.
+    pub limit: String,
+    pub stdout: String,
+    pub stderr: String,
+}
+
 #[derive(Debug)]
 pub struct RenameThisCommandWrapper {
     shielded: Command,
@@ -64,48 +230,16 @@ impl RenameThisCommandWrapper {
         F: FnOnce(&mut Command) -> R,
     {
         if let Some(spec) = self.spec.as_ref() {
-            info!("creating cpu shield");
-            // sudo cset shield --cpu=${CPU_MASK} --kthread=${on|off}
-
-            // TODO notify sudo dep, find another way to do this
-            let mut shield_create = Command::new("sudo");
-            shield_create.arg("cset");
-            shield_create.arg("shield");
-
-            // NOTE: this should only get called if the cpu_mask has already been validated
-            shield_create.arg(format!("--cpu={}", spec.cpu_mask));
-
-            if spec.kthread_on {
-                shield_create.arg("--kthread=on");
+            let active = ACTIVE_SHIELD.lock().unwrap().clone();
+            match active {
+                Some(ref active) if active == spec => return Ok(f(&mut self.shielded)),
+                Some(active) => bail!("cpu shield {} is up, unable to run in {}", active, spec),
+                None => (),
             }
 
-            let output = shield_create.output()?;
-
-            if !output.status.success() {
-                let stdout = String::from_utf8(output.stdout)?;
-                let stderr = String::from_utf8(output.stderr)?;
-                bail!(
-                    "unable to create cpu shield. stdout: {}, stderr: {}",
-                    stdout,
-                    stderr
-                );
-            }
-
-            // end cset shield setup
-
+            create_shield(spec)?;
             let result = f(&mut self.shielded);
-
-            // tear down shield
-            let reset_res = Command::new("sudo")
-                .arg("cset")
-                .arg("shield")
-                .arg("--reset")
-                .status();
-
-            match reset_res {
-                Err(why) => error!("error destroying shield: {:#?}", why),
-                _ => (),
-            };
+            reset_shield();
 
             Ok(result)
         } else {
@@ -118,6 +252,13 @@ impl RenameThisCommandWrapper {
         Ok(self.maybe_with_shielded(|cmd| cmd.output())??)
     }
 
+    /// Like `output`, but enforces the limits on the command and anything it starts. A run which
+    /// exceeds them fails with `TimedOut` or `ResourceLimitExceeded` once its process group has
+    /// been killed and the shield (if any) torn down.
+    pub fn output_with_limits(&mut self, limits: RunLimits) -> Result<Output> {
+        self.maybe_with_shielded(|cmd| output_with_limits(cmd, limits))?
+    }
+
     pub fn status(&mut self) -> Result<ExitStatus> {
         Ok(self.maybe_with_shielded(|cmd| cmd.status())??)
     }
@@ -144,4 +285,386 @@ impl RenameThisCommandWrapper {
         self.shielded.env(key, val);
         self
     }
+
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.shielded.env_clear();
+        self
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.shielded.current_dir(dir);
+        self
+    }
+}
+
+lazy_static! {
+    /// The shield held up by a `Shield`, which commands run in it don't need to create themselves.
+    static ref ACTIVE_SHIELD: Mutex<Option<ShieldSpec>> = Mutex::new(None);
+}
+
+/// Keeps a CPU shield up until it's dropped, rather than creating and resetting it around each
+/// shielded command. Creating or resetting a shield migrates every task on the machine, so anything
+/// started while that happens (e.g. by a build running alongside) could be left on the shielded
+/// CPUs.
+#[derive(Debug)]
+pub struct Shield(());
+
+impl Shield {
+    pub fn create(spec: &ShieldSpec) -> Result<Self> {
+        let mut active = ACTIVE_SHIELD.lock().unwrap();
+        ensure!(active.is_none(), "a cpu shield is already up");
+
+        create_shield(spec)?;
+        *active = Some(spec.clone());
+        Ok(Shield(()))
+    }
+}
+
+impl Drop for Shield {
+    fn drop(&mut self) {
+        reset_active_shield();
+    }
+}
+
+/// Tears down the shield held by a `Shield`, if any. For exiting without unwinding.
+pub fn reset_active_shield() {
+    if ACTIVE_SHIELD.lock().unwrap().take().is_some() {
+        reset_shield();
+    }
+}
+
+fn create_shield(spec: &ShieldSpec) -> Result<()> {
+    info!("creating cpu shield");
+    // sudo cset shield --cpu=${CPU_MASK} --kthread=${on|off}
+
+    // TODO notify sudo dep, find another way to do this
+    let mut shield_create = Command::new("sudo");
+    shield_create.arg("cset");
+    shield_create.arg("shield");
+
+    // the mask was validated and canonicalized when the spec was created
+    shield_create.arg(format!("--cpu={}", spec.cpu_mask));
+
+    if spec.kthread_on {
+        shield_create.arg("--kthread=on");
+    }
+
+    let output = shield_create.output()?;
+
+    if !output.status.success() {
+        let stdout = String::from_utf8(output.stdout)?;
+        let stderr = String::from_utf8(output.stderr)?;
+        bail!(
+            "unable to create cpu shield. stdout: {}, stderr: {}",
+            stdout,
+            stderr
+        );
+    }
+
+    Ok(())
+}
+
+fn reset_shield() {
+    let reset_res = Command::new("sudo")
+        .arg("cset")
+        .arg("shield")
+        .arg("--reset")
+        .status();
+
+    match reset_res {
+        Err(why) => error!("error destroying shield: {:#?}", why),
+        _ => (),
+    };
+}
+
+/// How often to check whether a limited run has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn output_with_limits(cmd: &mut Command, limits: RunLimits) -> Result<Output> {
+    unsafe {
+        cmd.pre_exec(move || {
+            // a group of its own lets us kill anything the benchmark (or cset) started along with it
+            if ::libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if let Some(bytes) = limits.max_memory_bytes {
+                let limit = ::libc::rlimit {
+                    rlim_cur: bytes as ::libc::rlim_t,
+                    rlim_max: bytes as ::libc::rlim_t,
+                };
+                if ::libc::setrlimit(::libc::RLIMIT_AS, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            if let Some(seconds) = limits.max_cpu_seconds {
+                // SIGXCPU at the soft limit, and SIGKILL a second later if that's ignored
+                let limit = ::libc::rlimit {
+                    rlim_cur: seconds as ::libc::rlim_t,
+                    rlim_max: (seconds + 1) as ::libc::rlim_t,
+                };
+                if ::libc::setrlimit(::libc::RLIMIT_CPU, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+
+    let OutputWithUsage {
+        status,
+        stdout,
+        stderr,
+        usage,
+    } = output_with_usage(cmd, limits.timeout)?;
+    let cpu_seconds = |time: ::libc::timeval| time.tv_sec as f64 + time.tv_usec as f64 / 1e6;
+    let cpu_seconds = cpu_seconds(usage.ru_utime) + cpu_seconds(usage.ru_stime);
+
+    let status = match status {
+        Some(status) => status,
+        None => {
+            return Err(TimedOut {
+                timeout: limits.timeout.unwrap(),
+                stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                stderr: String::from_utf8_lossy(&stderr).into_owned(),
+            }
+            .into())
+        }
+    };
+
+    let exceeded = match (killed_by(status), limits) {
+        (
+            Some(::libc::SIGXCPU),
+            RunLimits {
+                max_cpu_seconds: Some(seconds),
+                ..
+            },
+        ) => Some(("cpu time", format!("{}s", seconds))),
+        // the hard limit, for benchmarks which carried on after SIGXCPU
+        (
+            Some(::libc::SIGKILL),
+            RunLimits {
+                max_cpu_seconds: Some(seconds),
+                ..
+            },
+        ) if cpu_seconds >= seconds as f64 => Some(("cpu time", format!("{}s", seconds))),
+        // allocation failures abort, since nothing can be allocated to unwind with
+        (
+            Some(::libc::SIGABRT),
+            RunLimits {
+                max_memory_bytes: Some(bytes),
+                ..
+            },
+        ) => Some(("memory", format!("{} bytes", bytes))),
+        _ => None,
+    };
+
+    if let Some((resource, limit)) = exceeded {
+        return Err(ResourceLimitExceeded {
+            resource,
+            limit,
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        }
+        .into());
+    }
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// A command's output along with its rusage, which covers the command and any of its own children
+/// it waited for (e.g. a benchmark under cset, or the rustc invocations under cargo).
+pub struct OutputWithUsage {
+    /// `None` if the command was killed for running past its timeout.
+    pub status: Option<ExitStatus>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub usage: ::libc::rusage,
+}
+
+/// Like `Command::output`, but also returns the command's rusage. With a timeout, the process
+/// group the command leads is killed once it's been running for that long, so the command should
+/// be started in a group of its own.
+pub fn output_with_usage(cmd: &mut Command, timeout: Option<Duration>) -> Result<OutputWithUsage> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let group = child.id() as ::libc::pid_t;
+
+    let read_all = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            pipe.read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+    };
+    let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(child.stderr.take().unwrap()));
+
+    // std doesn't expose the child's rusage, so we reap it ourselves
+    let mut usage: ::libc::rusage = unsafe { ::std::mem::zeroed() };
+    let mut reap = |options| -> Result<Option<ExitStatus>> {
+        let mut status = 0;
+        loop {
+            match unsafe { ::libc::wait4(group, &mut status, options, &mut usage) } {
+                0 => return Ok(None),
+                pid if pid > 0 => return Ok(Some(ExitStatus::from_raw(status))),
+                _ => {
+                    let why = io::Error::last_os_error();
+                    if why.kind() != io::ErrorKind::Interrupted {
+                        bail!("unable to wait for {:?}: {}", cmd, why);
+                    }
+                }
+            }
+        }
+    };
+
+    let status = match timeout {
+        None => reap(0)?,
+        Some(timeout) => {
+            let started = Instant::now();
+            loop {
+                if let Some(status) = reap(::libc::WNOHANG)? {
+                    break Some(status);
+                }
+
+                if started.elapsed() >= timeout {
+                    warn!("{:?} timed out, killing its process group", cmd);
+                    unsafe { ::libc::kill(-group, ::libc::SIGKILL) };
+                    reap(0)?;
+                    break None;
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    };
+
+    let join = |reader: thread::JoinHandle<io::Result<Vec<u8>>>| -> Result<Vec<u8>> {
+        Ok(reader
+            .join()
+            .map_err(|_| format_err!("thread reading child output panicked"))??)
+    };
+
+    Ok(OutputWithUsage {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+        usage,
+    })
+}
+
+/// The signal which stopped a process, either directly or as reported by a wrapper like cset or a
+/// shell exiting with 128 plus the signal's number.
+fn killed_by(status: ExitStatus) -> Option<i32> {
+    status.signal().or_else(|| {
+        status
+            .code()
+            .filter(|&code| code > 128)
+            .map(|code| code - 128)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equivalent_masks_are_canonicalized() {
+        for mask in &["0-2", "0,1,2", "2,0-1", "0-1,1-2", " 0 , 1-2 "] {
+            assert_eq!(mask.parse::<CpuSet>().unwrap().to_string(), "0-2");
+        }
+        assert_eq!("7,3,4,0".parse::<CpuSet>().unwrap().to_string(), "0,3-4,7");
+    }
+
+    #[test]
+    fn complements_cpu_sets() {
+        let online = "0-7".parse::<CpuSet>().unwrap();
+        let shielded = "2-3,6".parse::<CpuSet>().unwrap();
+        assert_eq!(online.without(&shielded).to_string(), "0-1,4-5,7");
+        assert!(shielded.without(&online).is_empty());
+    }
+
+    fn limited(script: &str, limits: RunLimits) -> Result<Output> {
+        output_with_limits(Command::new("sh").arg("-c").arg(script), limits)
+    }
+
+    #[test]
+    fn cpu_time_limit_is_seen_through_a_wrapper() {
+        let limits = RunLimits {
+            timeout: None,
+            max_memory_bytes: None,
+            max_cpu_seconds: Some(1),
+        };
+
+        // the inner shell spins until it's stopped and the outer one reports the signal it got
+        for script in &[
+            "while :; do :; done",
+            "sh -c 'while :; do :; done'; exit $?",
+        ] {
+            let why = limited(script, limits).unwrap_err();
+            let exceeded = why.downcast_ref::<ResourceLimitExceeded>().unwrap();
+            assert_eq!(exceeded.resource, "cpu time");
+            assert_eq!(exceeded.limit, "1s");
+        }
+
+        // being killed isn't enough to have exceeded the limit
+        let output = limited("kill -KILL $$", limits).unwrap();
+        assert_eq!(output.status.signal(), Some(::libc::SIGKILL));
+    }
+
+    #[test]
+    fn memory_limit_is_seen_from_the_abort() {
+        let limits = RunLimits {
+            max_memory_bytes: Some(1 << 30),
+            ..RunLimits::default()
+        };
+
+        let why = limited("kill -ABRT $$", limits).unwrap_err();
+        let exceeded = why.downcast_ref::<ResourceLimitExceeded>().unwrap();
+        assert_eq!(exceeded.resource, "memory");
+
+        // a benchmark failing on its own didn't hit the limit
+        let output = limited("exit 101", limits).unwrap();
+        assert_eq!(output.status.code(), Some(101));
+    }
+
+    #[test]
+    fn timeouts_keep_partial_output() {
+        let limits = RunLimits {
+            timeout: Some(Duration::from_millis(200)),
+            max_memory_bytes: None,
+            max_cpu_seconds: None,
+        };
+
+        let why = limited("echo started; sleep 10", limits).unwrap_err();
+        let timed_out = why.downcast_ref::<TimedOut>().unwrap();
+        assert_eq!(timed_out.stdout, "started\n");
+    }
+
+    #[test]
+    fn rejects_malformed_masks() {
+        for mask in &["", "0,", "a", "1-", "-1", "3-1", "0-1-2"] {
+            assert!(mask.parse::<CpuSet>().is_err(), "accepted `{}`", mask);
+        }
+    }
+
+    #[test]
+    fn checks_cpus_are_online() {
+        let online = "0-3".parse::<CpuSet>().unwrap();
+        assert!("1,3"
+            .parse::<CpuSet>()
+            .unwrap()
+            .ensure_within(&online)
+            .is_ok());
+        assert!("3-4"
+            .parse::<CpuSet>()
+            .unwrap()
+            .ensure_within(&online)
+            .is_err());
+    }
 }
@@ -0,0 +1,399 @@
+//! Disassembles the code a benchmark runs, so that the binaries of consecutive toolchains can be
+//! compared instruction by instruction.
+
+use super::Result;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::path::Path;
+use std::process::Command;
+
+use marky_mark::Benchmark;
+
+/// How many calls away from the benchmark's own functions to follow. Criterion's timing loop and
+/// the code under test are usually within a couple of calls of the closure passed to it.
+const CALL_DEPTH: usize = 3;
+
+/// Keeps disassemblies readable (and diffable) when a benchmark calls into a lot of code.
+const MAX_LINES: usize = 20_000;
+
+/// Beyond this, diffs don't bother finding matching lines and show everything as changed.
+const MAX_DIFF_CELLS: usize = 16_000_000;
+
+/// Disassembles the benchmark's functions (including its closures) and their callees, found by
+/// following calls between the binary's symbols. Addresses, symbol hashes and rip-relative offsets
+/// are stripped so that code which only moved doesn't look changed.
+pub fn disassemble(binary: &Path, benchmark: &Benchmark) -> Result<String> {
+    let output = Command::new("objdump")
+        .arg("--disassemble")
+        .arg("--demangle")
+        .arg("--no-show-raw-insn")
+        .arg(binary)
+        .output()?;
+
+    ensure!(
+        output.status.success(),
+        "unable to disassemble {}: {}",
+        binary.display(),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let functions = parse_functions(&String::from_utf8_lossy(&output.stdout));
+    let root = format!("{}::{}", benchmark.crate_name, benchmark.name);
+    let text = render(&functions, &root);
+
+    ensure!(
+        !text.is_empty(),
+        "no functions named {} found in {}",
+        root,
+        binary.display()
+    );
+
+    Ok(text)
+}
+
+/// Maps each function in objdump's output to its instructions, whitespace collapsed.
+fn parse_functions(objdump: &str) -> BTreeMap<String, Vec<String>> {
+    let mut functions = BTreeMap::new();
+    let mut current: Option<(String, Vec<String>)> = None;
+
+    for line in objdump.lines() {
+        if let Some(name) = function_header(line) {
+            if let Some((name, instructions)) = current.take() {
+                functions.insert(name, instructions);
+            }
+            current = Some((name, Vec::new()));
+        } else if let Some((_, instructions)) = current.as_mut() {
+            if let Some(instruction) = instruction(line) {
+                instructions.push(instruction);
+            }
+        }
+    }
+
+    if let Some((name, instructions)) = current {
+        functions.insert(name, instructions);
+    }
+
+    functions
+}
+
+/// `0000000000006a50 <name>:`
+fn function_header(line: &str) -> Option<String> {
+    let line = line.trim_end();
+    if !line.ends_with(">:") {
+        return None;
+    }
+
+    let start = line.find(" <")?;
+    if start == 0 || !line[..start].chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+
+    Some(line[start + 2..line.len() - 2].to_string())
+}
+
+/// `    6a50:	sub    $0x18,%rsp`
+fn instruction(line: &str) -> Option<String> {
+    let mut parts = line.splitn(2, ":\t");
+    let address = parts.next()?.trim();
+    let text = parts.next()?;
+
+    if address.is_empty() || !address.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+
+    Some(text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// The function called (or tail called) by an instruction, if it's a symbol in the binary.
+fn call_target(instruction: &str) -> Option<&str> {
+    if !(instruction.starts_with("call") || instruction.starts_with("jmp")) {
+        return None;
+    }
+
+    let start = instruction.find('<')? + 1;
+    let end = instruction.rfind('>')?;
+    let target = instruction.get(start..end)?;
+
+    // jumps within a function are to an offset from its start, and PLT stubs aren't interesting
+    if target.contains('+') || target.ends_with("@plt") {
+        None
+    } else {
+        Some(target)
+    }
+}
+
+/// The disassembly of every function within `CALL_DEPTH` calls of the root, sorted by name.
+fn render(functions: &BTreeMap<String, Vec<String>>, root: &str) -> String {
+    let nested = format!("{}::", root);
+    let mut frontier = functions
+        .keys()
+        .filter(|name| {
+            let name = strip_hashes(name);
+            name == root || name.starts_with(&nested)
+        })
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    let mut seen = BTreeSet::new();
+    for _ in 0..=CALL_DEPTH {
+        let mut next = Vec::new();
+        for name in frontier {
+            if !seen.insert(name) {
+                continue;
+            }
+
+            next.extend(
+                functions[name]
+                    .iter()
+                    .filter_map(|i| call_target(i))
+                    .filter(|target| functions.contains_key(*target)),
+            );
+        }
+        frontier = next;
+    }
+
+    let mut rendered = seen
+        .into_iter()
+        .map(|name| {
+            let instructions = functions[name]
+                .iter()
+                .map(|i| normalize(i))
+                .collect::<Vec<_>>();
+            (strip_hashes(name), instructions)
+        })
+        .collect::<Vec<_>>();
+    rendered.sort();
+
+    let mut lines = Vec::new();
+    for (name, instructions) in rendered {
+        lines.push(format!("<{}>:", name));
+        lines.extend(instructions);
+        lines.push(String::new());
+    }
+
+    if lines.len() > MAX_LINES {
+        lines.truncate(MAX_LINES);
+        lines.push(String::from("... truncated"));
+    }
+
+    lines.join("\n")
+}
+
+/// Strips what changes whenever code moves: target addresses, symbol hashes, and the offsets of
+/// rip-relative operands (objdump names what they point at in a comment).
+fn normalize(instruction: &str) -> String {
+    let words = instruction.split(' ').collect::<Vec<_>>();
+    let mut kept = Vec::new();
+
+    for (i, word) in words.iter().enumerate() {
+        let is_address = !word.is_empty() && word.chars().all(|c| c.is_digit(16));
+        let names_symbol = words.get(i + 1).map_or(false, |next| next.starts_with('<'));
+        if is_address && names_symbol {
+            continue;
+        }
+
+        match word.find("(%rip)") {
+            Some(end) => {
+                let start = word[..end]
+                    .rfind(|c| c == ',' || c == '*')
+                    .map_or(0, |i| i + 1);
+                kept.push(format!("{}{}", &word[..start], &word[end..]));
+            }
+            None => kept.push(word.to_string()),
+        }
+    }
+
+    strip_hashes(&kept.join(" "))
+}
+
+/// Removes the `::h0123456789abcdef` suffixes rustc gives symbols, which change with the compiler.
+pub fn strip_hashes(name: &str) -> String {
+    let mut stripped = String::with_capacity(name.len());
+    let mut rest = name;
+
+    while let Some(start) = rest.find("::h") {
+        let hash = &rest[start + 3..];
+        let is_hash = hash
+            .get(..16)
+            .map_or(false, |digits| digits.chars().all(|c| c.is_digit(16)))
+            && !hash[16..].starts_with(|c: char| c.is_alphanumeric() || c == '_');
+
+        if is_hash {
+            stripped.push_str(&rest[..start]);
+            rest = &hash[16..];
+        } else {
+            stripped.push_str(&rest[..start + 3]);
+            rest = hash;
+        }
+    }
+
+    stripped.push_str(rest);
+    stripped
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiffKind {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Used as a CSS class.
+impl Display for DiffKind {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(match self {
+            DiffKind::Same => "same",
+            DiffKind::Removed => "removed",
+            DiffKind::Added => "added",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    pub text: String,
+}
+
+/// A line diff of two disassemblies, from their longest common subsequence.
+pub fn diff_lines(before: &str, after: &str) -> Vec<DiffLine> {
+    let before = before.lines().collect::<Vec<_>>();
+    let after = after.lines().collect::<Vec<_>>();
+    let line = |kind, text: &str| DiffLine {
+        kind,
+        text: text.to_string(),
+    };
+
+    let prefix = before
+        .iter()
+        .zip(&after)
+        .take_while(|(b, a)| b == a)
+        .count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(b, a)| b == a)
+        .count();
+
+    let old = &before[prefix..before.len() - suffix];
+    let new = &after[prefix..after.len() - suffix];
+
+    let mut diff = before[..prefix]
+        .iter()
+        .map(|l| line(DiffKind::Same, l))
+        .collect::<Vec<_>>();
+
+    if (old.len() + 1) * (new.len() + 1) > MAX_DIFF_CELLS {
+        diff.extend(old.iter().map(|l| line(DiffKind::Removed, l)));
+        diff.extend(new.iter().map(|l| line(DiffKind::Added, l)));
+    } else {
+        // common[i * width + j] is the length of the LCS of old[i..] and new[j..]
+        let width = new.len() + 1;
+        let mut common = vec![0u32; (old.len() + 1) * width];
+        for i in (0..old.len()).rev() {
+            for j in (0..new.len()).rev() {
+                common[i * width + j] = if old[i] == new[j] {
+                    common[(i + 1) * width + j + 1] + 1
+                } else {
+                    ::std::cmp::max(common[(i + 1) * width + j], common[i * width + j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() || j < new.len() {
+            if i < old.len() && j < new.len() && old[i] == new[j] {
+                diff.push(line(DiffKind::Same, old[i]));
+                i += 1;
+                j += 1;
+            } else if j == new.len()
+                || (i < old.len() && common[(i + 1) * width + j] >= common[i * width + j + 1])
+            {
+                diff.push(line(DiffKind::Removed, old[i]));
+                i += 1;
+            } else {
+                diff.push(line(DiffKind::Added, new[j]));
+                j += 1;
+            }
+        }
+    }
+
+    diff.extend(
+        before[before.len() - suffix..]
+            .iter()
+            .map(|l| line(DiffKind::Same, l)),
+    );
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OBJDUMP: &str = "
+bench:     file format elf64-x86-64
+
+Disassembly of section .text:
+
+0000000000001000 <sha2_0_8_0::sha256::bench1_10::h0123456789abcdef>:
+    1000:\tsub    $0x18,%rsp
+    1004:\tlea    0x2a3b8(%rip),%rdi        # 2b3c3 <anon.1+0x10>
+    100b:\tcall   1100 <criterion::Bencher::iter::hfedcba9876543210>
+    100e:\tcall   *0x2fe2(%rip)        # 3ff6 <_GLOBAL_OFFSET_TABLE_+0x20>
+    1010:\tjmp    1004 <sha2_0_8_0::sha256::bench1_10::h0123456789abcdef+0x4>
+
+0000000000001100 <criterion::Bencher::iter::hfedcba9876543210>:
+    1100:\tcall   1200 <memcpy@plt>
+    1105:\tret
+
+0000000000001300 <sha2_0_8_0::sha256::bench1_100::h1111111111111111>:
+    1300:\tret
+";
+
+    #[test]
+    fn follows_calls_from_the_benchmark() {
+        let functions = parse_functions(OBJDUMP);
+        assert_eq!(functions.len(), 3);
+
+        let rendered = render(&functions, "sha2_0_8_0::sha256::bench1_10");
+        assert_eq!(
+            rendered,
+            "<criterion::Bencher::iter>:
+call <memcpy@plt>
+ret
+
+<sha2_0_8_0::sha256::bench1_10>:
+sub $0x18,%rsp
+lea (%rip),%rdi # <anon.1+0x10>
+call <criterion::Bencher::iter>
+call *(%rip) # <_GLOBAL_OFFSET_TABLE_+0x20>
+jmp <sha2_0_8_0::sha256::bench1_10+0x4>
+"
+        );
+    }
+
+    #[test]
+    fn strips_only_symbol_hashes() {
+        assert_eq!(strip_hashes("a::b::h0123456789abcdef"), "a::b");
+        assert_eq!(strip_hashes("a::helper"), "a::helper");
+        assert_eq!(
+            strip_hashes("<a::h0123456789abcdef as b>::c::hffffffffffffffff"),
+            "<a as b>::c"
+        );
+    }
+
+    #[test]
+    fn diffs_changed_lines() {
+        let diff = diff_lines("a\nb\nc\nd", "a\nc\ne\nd");
+        let summary = diff
+            .iter()
+            .map(|l| format!("{}:{}", l.kind, l.text))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec!["same:a", "removed:b", "same:c", "added:e", "same:d"]
+        );
+    }
+}
@@ -0,0 +1,302 @@
+//! Describes what a `measure` invocation would do without doing any of it, along with a rough
+//! estimate of how long it would take based on what's already stored.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use run_plan::RunPlan;
+use storage::StoredEstimates;
+use toolchain::Toolchain;
+use {criterion_defaults, CriterionConfig};
+
+/// What measuring a plan would involve.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, PartialOrd, Ord)]
+pub enum PlannedWork {
+    /// The binary needs to be built before it can be run.
+    BuildAndRun,
+    /// The binary's hash is stored, but it hasn't been measured with this plan's settings.
+    Run,
+    /// Results are already stored.
+    Stored,
+    /// A previous build failed, and isn't retried.
+    PreviouslyFailed,
+    /// The toolchain was recently found to be unavailable and won't be tried again yet.
+    ToolchainUnavailable,
+}
+
+impl PlannedWork {
+    fn label(self) -> &'static str {
+        match self {
+            PlannedWork::BuildAndRun => "build and run",
+            PlannedWork::Run => "run",
+            PlannedWork::Stored => "stored",
+            PlannedWork::PreviouslyFailed => "previously failed",
+            PlannedWork::ToolchainUnavailable => "toolchain unavailable",
+        }
+    }
+}
+
+/// How long benchmarks have taken before, from stored estimates.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    /// Mean of the stored median nanoseconds per iteration, by benchmark key and build config.
+    iteration_nanos: BTreeMap<(String, Option<String>), f64>,
+    /// Mean of the stored build times in nanoseconds, keyed the same way.
+    build_nanos: BTreeMap<(String, Option<String>), f64>,
+    /// Mean build time across every benchmark, for those which have never been built.
+    overall_build_nanos: Option<f64>,
+}
+
+impl History {
+    pub fn new(
+        stored: &BTreeMap<(String, Option<String>), BTreeMap<Option<Toolchain>, StoredEstimates>>,
+    ) -> Self {
+        let mut history = History::default();
+
+        for (key, by_toolchain) in stored {
+            let metric = |name: &str| {
+                mean(
+                    by_toolchain
+                        .values()
+                        .filter_map(|(_, estimates, _)| estimates.get(name))
+                        .map(|statistic| statistic.median.point_estimate),
+                )
+            };
+
+            if let Some(nanos) = metric("nanoseconds") {
+                history.iteration_nanos.insert(key.clone(), nanos);
+            }
+            if let Some(nanos) = metric("build-nanoseconds") {
+                history.build_nanos.insert(key.clone(), nanos);
+            }
+        }
+
+        history.overall_build_nanos = mean(history.build_nanos.values().cloned());
+        history
+    }
+
+    /// Roughly how many seconds the work for a plan will take, and whether that's based on any
+    /// stored results for it.
+    pub fn estimate_seconds(
+        &self,
+        rp: &RunPlan,
+        work: PlannedWork,
+        process_runs: u32,
+    ) -> (f64, bool) {
+        let key = (
+            rp.benchmark.key(),
+            rp.build_config.as_ref().map(|c| c.name.clone()),
+        );
+        let config = rp.bench_config.clone().unwrap_or_else(criterion_defaults);
+
+        let (building, build_known) = match work {
+            PlannedWork::BuildAndRun => match self.build_nanos.get(&key) {
+                Some(&nanos) => (nanos / 1e9, true),
+                None => (self.overall_build_nanos.unwrap_or(0.0) / 1e9, false),
+            },
+            PlannedWork::Run => (0.0, true),
+            _ => return (0.0, true),
+        };
+
+        let iteration_nanos = self.iteration_nanos.get(&key).cloned();
+        let running = run_seconds(&config, iteration_nanos) * f64::from(process_runs);
+
+        (building + running, build_known && iteration_nanos.is_some())
+    }
+}
+
+/// How long criterion takes to measure a benchmark: warming up, then the longer of its target
+/// measurement time and the time the smallest possible sample takes at the benchmark's speed.
+fn run_seconds(config: &CriterionConfig, iteration_nanos: Option<f64>) -> f64 {
+    let n = config.sample_size as f64;
+    // criterion's linear sampling runs 1, 2, ..., n iterations per sample
+    let minimum_iterations = n * (n + 1.0) / 2.0;
+    let minimum_seconds = iteration_nanos.unwrap_or(0.0) * minimum_iterations / 1e9;
+
+    let target_seconds = config.measurement_time_ms as f64 / 1e3;
+    config.warm_up_time_ms as f64 / 1e3 + target_seconds.max(minimum_seconds)
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0u32), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        None
+    } else {
+        Some(sum / f64::from(count))
+    }
+}
+
+/// The plans a `measure` invocation would work through, by toolchain.
+#[derive(Clone, Debug)]
+pub struct DryRun {
+    pub plans: BTreeMap<Toolchain, Vec<PlannedMeasurement>>,
+    /// Whether to list each toolchain's plans, rather than only the totals.
+    verbose: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PlannedMeasurement {
+    pub plan: RunPlan,
+    pub work: PlannedWork,
+    pub estimated_seconds: f64,
+    /// Whether the estimate is based on stored results for this benchmark.
+    pub from_history: bool,
+}
+
+impl DryRun {
+    pub fn new(
+        classified: BTreeMap<Toolchain, Vec<(RunPlan, PlannedWork)>>,
+        history: &History,
+        process_runs: u32,
+    ) -> Self {
+        let plans = classified
+            .into_iter()
+            .map(|(toolchain, plans)| {
+                let planned = plans
+                    .into_iter()
+                    .map(|(plan, work)| {
+                        let (estimated_seconds, from_history) =
+                            history.estimate_seconds(&plan, work, process_runs);
+                        PlannedMeasurement {
+                            plan,
+                            work,
+                            estimated_seconds,
+                            from_history,
+                        }
+                    })
+                    .collect();
+                (toolchain, planned)
+            })
+            .collect();
+
+        DryRun {
+            plans,
+            verbose: false,
+        }
+    }
+
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    fn all(&self) -> impl Iterator<Item = &PlannedMeasurement> {
+        self.plans.values().flat_map(|plans| plans.iter())
+    }
+
+    pub fn estimated_seconds(&self) -> f64 {
+        self.all().map(|p| p.estimated_seconds).sum()
+    }
+
+    /// How many plans there are with each kind of work.
+    pub fn counts(&self) -> BTreeMap<PlannedWork, usize> {
+        let mut counts = BTreeMap::new();
+        for planned in self.all() {
+            *counts.entry(planned.work).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+impl Display for DryRun {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let summarize = |counts: BTreeMap<PlannedWork, usize>| {
+            counts
+                .into_iter()
+                .map(|(work, count)| format!("{} {}", count, work.label()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        if self.verbose {
+            for (toolchain, plans) in &self.plans {
+                let mut counts = BTreeMap::new();
+                for planned in plans {
+                    *counts.entry(planned.work).or_insert(0) += 1;
+                }
+                writeln!(f, "{}: {}", toolchain, summarize(counts))?;
+
+                for planned in plans {
+                    match planned.work {
+                        PlannedWork::BuildAndRun | PlannedWork::Run => writeln!(
+                            f,
+                            "    {:<14} {} ~{}{}",
+                            planned.work.label(),
+                            planned.plan,
+                            format_seconds(planned.estimated_seconds),
+                            if planned.from_history {
+                                ""
+                            } else {
+                                " (no history)"
+                            }
+                        )?,
+                        _ => (),
+                    }
+                }
+            }
+            writeln!(f)?;
+        }
+
+        let guessed = self
+            .all()
+            .filter(|p| p.estimated_seconds > 0.0 && !p.from_history)
+            .count();
+
+        writeln!(f, "total: {}", summarize(self.counts()))?;
+        write!(
+            f,
+            "estimated wall time: {}",
+            format_seconds(self.estimated_seconds())
+        )?;
+        if guessed > 0 {
+            write!(
+                f,
+                " ({} plans without stored results are estimated from criterion's settings alone)",
+                guessed
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// e.g. `3d 4h 12m`, or `42s` for anything under a minute.
+fn format_seconds(seconds: f64) -> String {
+    let total = seconds.round() as u64;
+    if total < 60 {
+        return format!("{}s", total);
+    }
+
+    let (days, hours, minutes) = (total / 86_400, total % 86_400 / 3_600, total % 3_600 / 60);
+    let mut parts = Vec::new();
+    if days > 0 {
+        parts.push(format!("{}d", days));
+    }
+    if days > 0 || hours > 0 {
+        parts.push(format!("{}h", hours));
+    }
+    parts.push(format!("{}m", minutes));
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fast_benchmarks_take_criterions_target_time() {
+        let config = criterion_defaults();
+        assert_eq!(run_seconds(&config, None), 8.0);
+        assert_eq!(run_seconds(&config, Some(1_000.0)), 8.0);
+
+        // 5050 iterations at 10ms each can't fit in 5 seconds
+        assert!((run_seconds(&config, Some(10_000_000.0)) - 53.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_seconds(42.4), "42s");
+        assert_eq!(format_seconds(125.0), "2m");
+        assert_eq!(format_seconds(3.0 * 3_600.0 + 60.0), "3h 1m");
+        assert_eq!(format_seconds(2.0 * 86_400.0 + 61.0), "2d 0h 1m");
+    }
+}
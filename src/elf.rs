@@ -0,0 +1,179 @@
+//! Just enough of an ELF parser to find the sections of a benchmark binary.
+
+use super::Result;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// `SHF_EXECINSTR`
+const EXECUTABLE_FLAG: u64 = 0x4;
+/// `SHT_NOBITS`, sections like `.bss` which occupy no space in the file.
+const NO_BITS_TYPE: u32 = 8;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Section {
+    pub fn is_executable(&self) -> bool {
+        self.flags & EXECUTABLE_FLAG != 0
+    }
+
+    /// The section's contents, empty for sections which don't occupy any space in the file.
+    pub fn contents<'a>(&self, file: &'a [u8]) -> Result<&'a [u8]> {
+        if self.kind == NO_BITS_TYPE {
+            return Ok(&[]);
+        }
+
+        match bytes(file, self.offset, self.size) {
+            Some(contents) => Ok(contents),
+            None => bail!("section {} extends past the end of the file", self.name),
+        }
+    }
+}
+
+/// `len` bytes of the file from `offset`, if they're all within it.
+fn bytes(file: &[u8], offset: u64, len: u64) -> Option<&[u8]> {
+    let end = offset.checked_add(len)?;
+    if end > file.len() as u64 {
+        return None;
+    }
+    file.get(offset as usize..end as usize)
+}
+
+/// Reads the section headers of a little-endian, 64-bit ELF file, which covers every platform the
+/// benchmarks currently run on.
+pub fn sections(file: &[u8]) -> Result<Vec<Section>> {
+    ensure!(file.len() >= 64, "too short to be an ELF file");
+    ensure!(&file[..4] == b"\x7fELF", "not an ELF file");
+    ensure!(file[4] == 2, "only 64-bit ELF files are supported");
+    ensure!(file[5] == 1, "only little-endian ELF files are supported");
+
+    let header_offset = LittleEndian::read_u64(&file[0x28..]);
+    let header_size = u64::from(LittleEndian::read_u16(&file[0x3A..]));
+    let num_headers = u64::from(LittleEndian::read_u16(&file[0x3C..]));
+    let names_index = LittleEndian::read_u16(&file[0x3E..]) as usize;
+
+    ensure!(header_size >= 64, "section headers are too small");
+    let headers = match bytes(file, header_offset, header_size * num_headers) {
+        Some(headers) => headers,
+        None => bail!("section headers extend past the end of the file"),
+    };
+    ensure!(
+        names_index < num_headers as usize,
+        "section name table is missing"
+    );
+
+    let raw = headers
+        .chunks(header_size as usize)
+        .map(|header| {
+            (
+                LittleEndian::read_u32(&header[0x00..]),
+                Section {
+                    name: String::new(),
+                    kind: LittleEndian::read_u32(&header[0x04..]),
+                    flags: LittleEndian::read_u64(&header[0x08..]),
+                    offset: LittleEndian::read_u64(&header[0x18..]),
+                    size: LittleEndian::read_u64(&header[0x20..]),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let names = raw[names_index].1.contents(file)?;
+
+    raw.into_iter()
+        .map(|(name_offset, mut section)| {
+            let name = match names.get(name_offset as usize..) {
+                Some(name) => name,
+                None => bail!("section name is out of bounds"),
+            };
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            section.name = String::from_utf8_lossy(&name[..len]).into_owned();
+            Ok(section)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An ELF header followed by a null section, `.text` and the section name table.
+    fn tiny_elf() -> Vec<u8> {
+        let names = b"\0.text\0.shstrtab\0";
+        let text = [0x90u8; 16];
+
+        let text_offset = 64;
+        let names_offset = text_offset + text.len();
+        let headers_offset = names_offset + names.len();
+
+        let mut file = vec![0u8; headers_offset + 3 * 64];
+        file[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        LittleEndian::write_u64(&mut file[0x28..], headers_offset as u64);
+        LittleEndian::write_u16(&mut file[0x3A..], 64);
+        LittleEndian::write_u16(&mut file[0x3C..], 3);
+        LittleEndian::write_u16(&mut file[0x3E..], 2);
+
+        file[text_offset..names_offset].copy_from_slice(&text);
+        file[names_offset..headers_offset].copy_from_slice(names);
+
+        let mut header =
+            |i: usize, name: u32, kind: u32, flags: u64, offset: usize, size: usize| {
+                let h = &mut file[headers_offset + i * 64..];
+                LittleEndian::write_u32(&mut h[0x00..], name);
+                LittleEndian::write_u32(&mut h[0x04..], kind);
+                LittleEndian::write_u64(&mut h[0x08..], flags);
+                LittleEndian::write_u64(&mut h[0x18..], offset as u64);
+                LittleEndian::write_u64(&mut h[0x20..], size as u64);
+            };
+        header(1, 1, 1, 0x6, text_offset, text.len());
+        header(2, 7, 3, 0, names_offset, names.len());
+
+        file
+    }
+
+    #[test]
+    fn finds_named_sections() {
+        let file = tiny_elf();
+        let sections = sections(&file).unwrap();
+
+        let names = sections.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["", ".text", ".shstrtab"]);
+
+        let text = &sections[1];
+        assert!(text.is_executable());
+        assert_eq!(text.contents(&file).unwrap(), &[0x90u8; 16][..]);
+        assert!(!sections[2].is_executable());
+    }
+
+    #[test]
+    fn rejects_non_elf() {
+        assert!(sections(&[0u8; 128]).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let truncated = tiny_elf();
+        assert!(sections(&truncated[..truncated.len() - 1]).is_err());
+
+        let mut far_headers = tiny_elf();
+        LittleEndian::write_u64(&mut far_headers[0x28..], u64::max_value() - 8);
+        assert!(sections(&far_headers).is_err());
+
+        // the text section's size would overflow when added to its offset
+        let mut huge_section = tiny_elf();
+        let text_header = huge_section.len() - 2 * 64;
+        LittleEndian::write_u64(&mut huge_section[text_header + 0x20..], u64::max_value());
+        let parsed = sections(&huge_section).unwrap();
+        assert!(parsed[1].contents(&huge_section).is_err());
+
+        let mut bad_name = tiny_elf();
+        LittleEndian::write_u32(&mut bad_name[text_header..], u32::max_value());
+        assert!(sections(&bad_name).is_err());
+    }
+}
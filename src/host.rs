@@ -0,0 +1,149 @@
+//! What a runner looked like when it took a measurement. Results shift when the hardware or its
+//! configuration changes, so they're only comparable between measurements on the same host.
+
+use super::Result;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::Path;
+
+use ring::digest::{digest, SHA256};
+use serde_json;
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct HostFingerprint {
+    pub cpu_model: Option<String>,
+    pub microcode: Option<String>,
+    /// The kernel's release, e.g. `4.15.0-43-generic`.
+    pub kernel: Option<String>,
+    /// The frequency governor of the first CPU, e.g. `performance`.
+    pub governor: Option<String>,
+    pub turbo: Option<bool>,
+    /// Whether simultaneous multithreading (hyperthreading) is active.
+    pub smt: Option<bool>,
+    pub memory_kb: Option<u64>,
+}
+
+impl HostFingerprint {
+    /// Reads the fingerprint of the current host from procfs and sysfs. Anything which can't be
+    /// read (e.g. turbo state on a CPU without a driver exposing it) is left out.
+    pub fn capture() -> Self {
+        let cpuinfo = read_trimmed("/proc/cpuinfo").unwrap_or_default();
+        let meminfo = read_trimmed("/proc/meminfo").unwrap_or_default();
+
+        let turbo = match read_trimmed("/sys/devices/system/cpu/intel_pstate/no_turbo") {
+            Some(no_turbo) => Some(no_turbo == "0"),
+            None => read_trimmed("/sys/devices/system/cpu/cpufreq/boost").map(|b| b == "1"),
+        };
+
+        Self {
+            cpu_model: cpuinfo_field(&cpuinfo, "model name"),
+            microcode: cpuinfo_field(&cpuinfo, "microcode"),
+            kernel: read_trimmed("/proc/sys/kernel/osrelease"),
+            governor: read_trimmed("/sys/devices/system/cpu/cpu0/cpufreq/scaling_governor"),
+            turbo,
+            smt: read_trimmed("/sys/devices/system/cpu/smt/active").map(|s| s == "1"),
+            memory_kb: mem_total_kb(&meminfo),
+        }
+    }
+
+    /// A short hash identifying the fingerprint, used to refer to it from stored measurements.
+    pub fn id(&self) -> Result<String> {
+        let hash = digest(&SHA256, &serde_json::to_vec(self)?);
+        Ok(hash.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+}
+
+impl Display for HostFingerprint {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let unknown = || String::from("unknown");
+        let on_off = |enabled: Option<bool>| match enabled {
+            Some(true) => String::from("on"),
+            Some(false) => String::from("off"),
+            None => unknown(),
+        };
+
+        write!(
+            f,
+            "{} (microcode {}), kernel {}, {} governor, turbo {}, SMT {}, {} MB",
+            self.cpu_model.clone().unwrap_or_else(unknown),
+            self.microcode.clone().unwrap_or_else(unknown),
+            self.kernel.clone().unwrap_or_else(unknown),
+            self.governor.clone().unwrap_or_else(unknown),
+            on_off(self.turbo),
+            on_off(self.smt),
+            self.memory_kb
+                .map(|kb| (kb / 1024).to_string())
+                .unwrap_or_else(unknown)
+        )
+    }
+}
+
+fn read_trimmed(path: impl AsRef<Path>) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// The value of the first `name : value` line for `name` in `/proc/cpuinfo`.
+fn cpuinfo_field(cpuinfo: &str, name: &str) -> Option<String> {
+    cpuinfo.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        if parts.next()?.trim() == name {
+            Some(parts.next()?.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn mem_total_kb(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    line["MemTotal:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_proc_files() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Core(TM) \
+                       i7-7700K CPU @ 4.20GHz\nmicrocode\t: 0x8e\n\nprocessor\t: 1\nmodel name\t: \
+                       something else\n";
+        assert_eq!(
+            cpuinfo_field(cpuinfo, "model name").unwrap(),
+            "Intel(R) Core(TM) i7-7700K CPU @ 4.20GHz"
+        );
+        assert_eq!(cpuinfo_field(cpuinfo, "microcode").unwrap(), "0x8e");
+        assert_eq!(cpuinfo_field(cpuinfo, "flags"), None);
+
+        let meminfo = "MemTotal:       16314432 kB\nMemFree:         1197628 kB\n";
+        assert_eq!(mem_total_kb(meminfo), Some(16314432));
+    }
+
+    #[test]
+    fn ids_change_with_the_host() {
+        let before = HostFingerprint {
+            microcode: Some(String::from("0x8e")),
+            ..HostFingerprint::default()
+        };
+        let after = HostFingerprint {
+            microcode: Some(String::from("0x96")),
+            ..before.clone()
+        };
+
+        assert_eq!(before.id().unwrap(), before.clone().id().unwrap());
+        assert_ne!(before.id().unwrap(), after.id().unwrap());
+        assert_eq!(before.id().unwrap().len(), 16);
+    }
+}
@@ -37,19 +37,28 @@ extern crate proptest;
 extern crate tempfile;
 
 mod analysis;
+mod bisect;
+mod build_config;
 mod collector;
 mod cpu_shield;
+mod disassembly;
+mod dry_run;
+mod elf;
 mod generator;
+mod host;
+mod profile;
 mod registry;
 mod run_plan;
 mod signal;
 mod storage;
 mod toolchain;
+mod toolchain_cache;
 mod website;
 
 pub use self::{
-    analysis::*, collector::*, cpu_shield::*, generator::*, registry::*, run_plan::*, signal::*,
-    storage::*, toolchain::*, website::*,
+    analysis::*, bisect::*, build_config::*, collector::*, cpu_shield::*, disassembly::*,
+    dry_run::*, generator::*, host::*, profile::*, registry::*, run_plan::*, signal::*, storage::*,
+    toolchain::*, toolchain_cache::*, website::*,
 };
 pub use lolbench_support::*;
 pub use marky_mark::*;
@@ -57,17 +66,15 @@ pub use marky_mark::*;
 use std::{
     collections::{BTreeMap, BTreeSet},
     path::Path,
+    sync::Arc,
 };
 
 use chrono::NaiveDate;
+use itertools::Itertools;
 use slug::slugify;
 
 pub fn measure(opts: BenchOpts, data_dir: &Path, site_dir: &Path, publish: bool) -> Result<()> {
-    info!("ensuring data dir {} exists", data_dir.display());
-    let mut collector = Collector::new(data_dir, site_dir)?;
-
-    info!("cataloging potential builds to run");
-    let candidates = opts.enumerate_bench_candidates()?;
+    let (mut collector, candidates) = prepare(&opts, data_dir, site_dir)?;
 
     info!(
         "{} possible toolchains to run to satisfy provided options, pruning...",
@@ -90,6 +97,70 @@ pub fn measure(opts: BenchOpts, data_dir: &Path, site_dir: &Path, publish: bool)
     Ok(())
 }
 
+/// What `measure` would do with the same options, with an estimate of how long it would take.
+pub fn plan_measurements(opts: BenchOpts, data_dir: &Path, site_dir: &Path) -> Result<DryRun> {
+    let (mut collector, candidates) = prepare(&opts, data_dir, site_dir)?;
+    let classified = collector.classify_plans(&candidates)?;
+
+    info!("estimating run times from stored results");
+    let history = History::new(&GitStore::ensure_initialized(data_dir)?.all_stored_estimates()?);
+
+    Ok(DryRun::new(classified, &history, collector.process_runs()))
+}
+
+/// Sets up a collector for the options and finds every plan they could run.
+fn prepare(
+    opts: &BenchOpts,
+    data_dir: &Path,
+    site_dir: &Path,
+) -> Result<(Collector, BTreeMap<Toolchain, BTreeSet<RunPlan>>)> {
+    info!("ensuring data dir {} exists", data_dir.display());
+    let mut collector = Collector::new(data_dir, site_dir)?;
+    if let Some(local) = &opts.local_toolchains {
+        info!("resolving toolchains from {}", local.root.display());
+        collector = collector.with_provider(Arc::new(local.clone()));
+    }
+    collector = collector
+        .with_retry_policy(opts.retry_policy)
+        .with_cache_limits(opts.cache_limits)
+        .with_build_jobs(opts.build_jobs)
+        .with_run_limits(opts.run_limits)
+        .with_process_runs(opts.process_runs, opts.interleave_runs)
+        .with_compare_across_hosts(opts.compare_across_hosts);
+
+    info!("cataloging potential builds to run");
+    let mut candidates = opts.enumerate_bench_candidates()?;
+
+    if let Sampling::CoarseThenRefine(_) = opts.sampling {
+        info!("looking for gaps in existing results to refine");
+        for (toolchain, plans) in opts.gap_filling_candidates(data_dir)? {
+            candidates.entry(toolchain).or_default().extend(plans);
+        }
+    }
+
+    Ok((collector, candidates))
+}
+
+/// Forget that toolchains were unavailable so that the next run of `measure` looks for them again.
+/// Clears every record if no toolchain is given.
+pub fn clear_unavailable(data_dir: &Path, toolchain: Option<&str>) -> Result<()> {
+    let mut storage = GitStore::ensure_initialized(data_dir)?;
+
+    let mut cleared = Vec::new();
+    for Container { key, .. } in storage.all_stored::<unavailable::Key>()? {
+        if toolchain.map_or(true, |t| key.toolchain == Toolchain::from(t)) {
+            storage.remove(&key)?;
+            cleared.push(key.toolchain);
+        }
+    }
+
+    info!("cleared {} unavailable toolchain record(s)", cleared.len());
+    storage.commit(&format!(
+        "cleared unavailable toolchains\n{}",
+        cleared.iter().join("\n")
+    ))
+}
+
 pub fn end_to_end_test(crate_name: &str, bench_name: &str) {
     let bench_source_name = format!("{}.rs", slugify(bench_name));
 
@@ -108,6 +179,8 @@ pub fn end_to_end_test(crate_name: &str, bench_name: &str) {
             name: String::from(bench_name),
             crate_name: String::from(crate_name),
             entrypoint_path,
+            criterion: CriterionOverrides::default(),
+            count_allocations: false,
         },
         Some(CriterionConfig {
             confidence_level: r32(0.95),
@@ -120,6 +193,7 @@ pub fn end_to_end_test(crate_name: &str, bench_name: &str) {
         }),
         None,
         None,
+        None,
         source_path,
     )
     .unwrap();
@@ -141,52 +215,217 @@ pub fn end_to_end_test(crate_name: &str, bench_name: &str) {
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub struct BenchOpts {
     pub shield_spec: Option<ShieldSpec>,
-    pub runner: Option<String>,
+    /// Which benchmarks to run, including the runner they're assigned to.
+    pub filter: BenchFilter,
     pub toolchains: ToolchainSpec,
+    /// Resolve toolchains from a directory of sysroots instead of installing them with rustup.
+    pub local_toolchains: Option<LocalToolchains>,
+    pub retry_policy: RetryPolicy,
+    pub cache_limits: CacheLimits,
+    /// Build configurations to measure in addition to the workspace's release profile.
+    pub build_configs: Vec<BuildConfig>,
+    pub sampling: Sampling,
+    /// How many jobs cargo runs when building benchmarks, cargo's default if `None`.
+    pub build_jobs: Option<u32>,
+    pub run_limits: RunLimits,
+    /// How many independent processes to measure each binary in.
+    pub process_runs: u32,
+    /// Take each benchmark's repeated runs in rounds across all of a toolchain's benchmarks.
+    pub interleave_runs: bool,
+    /// Criterion settings for every benchmark, unless the registry overrides them for one.
+    pub criterion_overrides: CriterionOverrides,
+    /// Compare results measured on hosts with different fingerprints when looking for changes.
+    pub compare_across_hosts: bool,
+}
+
+/// Which of the toolchains in a dated range to measure. Stable releases and explicit lists of
+/// toolchains are always measured in full.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum Sampling {
+    /// Every day in the range.
+    Daily,
+    /// Every Nth day of the range, always including its first and last days.
+    EveryNthDay(u32),
+    /// Every Nth day, plus every day between consecutive results where a benchmark's runtime
+    /// changed. Gaps are found in existing results, so each run refines what the last one found.
+    CoarseThenRefine(u32),
 }
 
 impl BenchOpts {
     pub fn enumerate_bench_candidates(&self) -> Result<BTreeMap<Toolchain, BTreeSet<RunPlan>>> {
-        let benchmarks = get_benches(self.runner.as_ref().map(String::as_str))?;
-        let toolchains = self.toolchains.all_of_em();
+        let benchmarks = get_benches(&self.filter)?;
+        let toolchains = self.toolchains.sampled(self.sampling);
+
+        let build_configs = ::std::iter::once(None)
+            .chain(self.build_configs.iter().cloned().map(Some))
+            .collect::<Vec<_>>();
 
         let mut plans = BTreeMap::new();
 
         for toolchain in toolchains {
-            let shield = self.shield_spec.as_ref().map(Clone::clone);
-            let create_runplan = |benchmark: &Benchmark| {
-                let path = benchmark.entrypoint_path.clone();
-                RunPlan::new(
-                    benchmark.clone(),
-                    // TODO(anp): serialize criterion config if we have it
-                    None,
-                    shield.clone(),
-                    Some(toolchain.clone()),
-                    path,
-                )
+            for benchmark in &benchmarks {
+                for build_config in &build_configs {
+                    let rp = self.create_runplan(benchmark, build_config, &toolchain)?;
+
+                    // TODO check if we can skip this
+
+                    plans
+                        .entry(toolchain.clone())
+                        .or_insert(BTreeSet::new())
+                        .insert(rp);
+                }
+            }
+        }
+
+        Ok(plans)
+    }
+
+    /// Plans for the toolchains in the requested range which fall between two existing results for
+    /// a benchmark where its runtime changed.
+    pub fn gap_filling_candidates(
+        &self,
+        data_dir: &Path,
+    ) -> Result<BTreeMap<Toolchain, BTreeSet<RunPlan>>> {
+        let benchmarks = get_benches(&self.filter)?
+            .into_iter()
+            .map(|b| (b.key(), b))
+            .collect::<BTreeMap<_, _>>();
+        let in_range = self
+            .toolchains
+            .all_of_em()
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+
+        let storage = GitStore::ensure_initialized(data_dir)?;
+        let toolchain_metadata = storage.all_toolchain_metadata()?;
+
+        let mut plans = BTreeMap::new();
+
+        for ((bench_key, config_name), estimates) in storage.all_stored_estimates()? {
+            let benchmark = match benchmarks.get(&bench_key) {
+                Some(b) => b,
+                None => continue,
             };
 
-            for benchmark in &benchmarks {
-                let rp = create_runplan(benchmark)?;
-                rp.validate()?;
+            let build_config = match config_name {
+                Some(name) => match self.build_configs.iter().find(|c| c.name == name) {
+                    Some(cfg) => Some(cfg.clone()),
+                    None => continue,
+                },
+                None => None,
+            };
 
-                // TODO check if we can skip this
+            let mut by_channel = BTreeMap::<Channel, Vec<_>>::new();
+            for (toolchain, estimate) in estimates {
+                if let Some(toolchain) = toolchain {
+                    by_channel
+                        .entry(toolchain.channel())
+                        .or_default()
+                        .push((toolchain, estimate));
+                }
+            }
 
-                plans
-                    .entry(toolchain.clone())
-                    .or_insert(BTreeSet::new())
-                    .insert(rp);
+            for (channel, series) in by_channel {
+                let timings = TimingRecord::series(
+                    series.into_iter(),
+                    &toolchain_metadata,
+                    self.compare_across_hosts,
+                );
+
+                for (before, after) in anomalous_gaps(&timings) {
+                    let (start, end) = match (before.date(), after.date()) {
+                        (Some(start), Some(end)) => (start.succ(), end.pred()),
+                        _ => continue,
+                    };
+
+                    for toolchain in dated_toolchains(&channel.to_string(), start, end) {
+                        if !in_range.contains(&toolchain) {
+                            continue;
+                        }
+
+                        let rp = self.create_runplan(benchmark, &build_config, &toolchain)?;
+                        plans.entry(toolchain).or_insert(BTreeSet::new()).insert(rp);
+                    }
+                }
             }
         }
 
         Ok(plans)
     }
+
+    fn create_runplan(
+        &self,
+        benchmark: &Benchmark,
+        build_config: &Option<BuildConfig>,
+        toolchain: &Toolchain,
+    ) -> Result<RunPlan> {
+        let rp = RunPlan::new(
+            benchmark.clone(),
+            criterion_config(&self.criterion_overrides.overridden_by(&benchmark.criterion)),
+            build_config.clone(),
+            self.shield_spec.clone(),
+            Some(toolchain.clone()),
+            benchmark.entrypoint_path.clone(),
+        )?;
+        rp.validate()?;
+        Ok(rp)
+    }
+}
+
+/// The settings criterion uses for anything a benchmark's config doesn't set.
+pub fn criterion_defaults() -> CriterionConfig {
+    CriterionConfig {
+        sample_size: 100,
+        warm_up_time_ms: 3_000,
+        measurement_time_ms: 5_000,
+        nresamples: 100_000,
+        noise_threshold: r32(0.01),
+        confidence_level: r32(0.95),
+        significance_level: r32(0.05),
+    }
+}
+
+/// The config to measure a benchmark with, filling in anything not overridden with criterion's own
+/// defaults. Without any overrides, the benchmark is left to use those defaults itself so that it
+/// shares results with measurements taken before configs were recorded.
+pub fn criterion_config(overrides: &CriterionOverrides) -> Option<CriterionConfig> {
+    if overrides.is_empty() {
+        return None;
+    }
+
+    let defaults = criterion_defaults();
+    Some(CriterionConfig {
+        sample_size: overrides.sample_size.unwrap_or(defaults.sample_size),
+        warm_up_time_ms: overrides
+            .warm_up_time_ms
+            .unwrap_or(defaults.warm_up_time_ms),
+        measurement_time_ms: overrides
+            .measurement_time_ms
+            .unwrap_or(defaults.measurement_time_ms),
+        nresamples: overrides.nresamples.unwrap_or(defaults.nresamples),
+        noise_threshold: overrides
+            .noise_threshold
+            .unwrap_or(defaults.noise_threshold),
+        confidence_level: overrides
+            .confidence_level
+            .unwrap_or(defaults.confidence_level),
+        significance_level: overrides
+            .significance_level
+            .unwrap_or(defaults.significance_level),
+    })
 }
 
 #[derive(Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub enum ToolchainSpec {
     Single(String),
+    /// Every nightly between the two dates, inclusive.
     Range(NaiveDate, NaiveDate),
+    /// Every stable minor release between the two versions, inclusive.
+    StableRange(RustVersion, RustVersion),
+    /// Every dated beta between the two dates, inclusive.
+    BetaRange(NaiveDate, NaiveDate),
+    /// Exactly the listed toolchains, e.g. as read from a file.
+    List(Vec<Toolchain>),
 }
 
 impl ToolchainSpec {
@@ -194,19 +433,54 @@ impl ToolchainSpec {
         use ToolchainSpec::*;
         match self {
             Single(s) => vec![Toolchain::from(s)],
-            Range(start, end) => {
+            Range(start, end) => dated_toolchains("nightly", *start, *end),
+            StableRange(start, end) => {
                 let mut current = *start;
-                let mut nightlies = Vec::new();
+                let mut stables = Vec::new();
 
                 while current <= *end {
-                    nightlies.push(Toolchain::from(&format!("nightly-{}", current)));
-                    current = current.succ();
+                    stables.push(Toolchain::from(&current.to_string()));
+                    current = current.next_minor();
                 }
 
-                nightlies
+                stables
             }
+            BetaRange(start, end) => dated_toolchains("beta", *start, *end),
+            List(toolchains) => toolchains.clone(),
         }
     }
+
+    /// The toolchains to measure with the given sampling.
+    fn sampled(&self, sampling: Sampling) -> Vec<Toolchain> {
+        use ToolchainSpec::*;
+        let step = match (self, sampling) {
+            (Range(..), Sampling::EveryNthDay(n))
+            | (Range(..), Sampling::CoarseThenRefine(n))
+            | (BetaRange(..), Sampling::EveryNthDay(n))
+            | (BetaRange(..), Sampling::CoarseThenRefine(n)) => ::std::cmp::max(n, 1) as usize,
+            _ => 1,
+        };
+
+        let all = self.all_of_em();
+        let last = all.len().saturating_sub(1);
+        all.into_iter()
+            .enumerate()
+            .filter(|&(i, _)| i % step == 0 || i == last)
+            .map(|(_, toolchain)| toolchain)
+            .collect()
+    }
+}
+
+fn dated_toolchains(channel: &str, start: NaiveDate, end: NaiveDate) -> Vec<Toolchain> {
+    let mut current = start;
+    let mut toolchains = Vec::new();
+
+    while current <= end {
+        toolchains.push(Toolchain::from(&format!("{}-{}", channel, current)));
+        current = current.succ();
+    }
+
+    toolchains
 }
 
 #[cfg(test)]
@@ -249,4 +523,87 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn toolchain_sampling_keeps_range_ends() {
+        let spec = ToolchainSpec::Range(
+            NaiveDate::from_ymd(2015, 5, 15),
+            NaiveDate::from_ymd(2015, 6, 2),
+        );
+
+        assert_eq!(
+            spec.sampled(Sampling::EveryNthDay(7)),
+            vec![
+                Toolchain::from("nightly-2015-05-15"),
+                Toolchain::from("nightly-2015-05-22"),
+                Toolchain::from("nightly-2015-05-29"),
+                Toolchain::from("nightly-2015-06-02"),
+            ]
+        );
+        assert_eq!(spec.sampled(Sampling::Daily), spec.all_of_em());
+
+        let stable =
+            ToolchainSpec::StableRange(RustVersion::new(1, 8, 0), RustVersion::new(1, 11, 0));
+        assert_eq!(
+            stable.sampled(Sampling::CoarseThenRefine(7)),
+            stable.all_of_em()
+        );
+    }
+
+    #[test]
+    fn toolchain_stable_range() {
+        let spec =
+            ToolchainSpec::StableRange(RustVersion::new(1, 8, 0), RustVersion::new(1, 11, 0));
+
+        assert_eq!(
+            spec.all_of_em(),
+            vec![
+                Toolchain::from("1.8.0"),
+                Toolchain::from("1.9.0"),
+                Toolchain::from("1.10.0"),
+                Toolchain::from("1.11.0"),
+            ]
+        );
+
+        let mut sorted = spec.all_of_em();
+        sorted.sort();
+        assert_eq!(sorted, spec.all_of_em());
+    }
+
+    #[test]
+    fn registry_criterion_settings_take_precedence_over_cli() {
+        assert_eq!(criterion_config(&CriterionOverrides::default()), None);
+
+        let cli = CriterionOverrides {
+            sample_size: Some(10),
+            measurement_time_ms: Some(1_000),
+            ..CriterionOverrides::default()
+        };
+        let registry = CriterionOverrides {
+            sample_size: Some(50),
+            noise_threshold: Some(r32(0.05)),
+            ..CriterionOverrides::default()
+        };
+
+        let config = criterion_config(&cli.overridden_by(&registry)).unwrap();
+        assert_eq!(
+            config,
+            CriterionConfig {
+                sample_size: 50,
+                measurement_time_ms: 1_000,
+                noise_threshold: r32(0.05),
+                ..criterion_defaults()
+            }
+        );
+
+        // a registry override alone still moves the benchmark off of criterion's defaults
+        assert_eq!(
+            criterion_config(&CriterionOverrides::default().overridden_by(&registry)),
+            Some(CriterionConfig {
+                sample_size: 50,
+                noise_threshold: r32(0.05),
+                ..criterion_defaults()
+            })
+        );
+    }
 }
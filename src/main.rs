@@ -12,8 +12,9 @@ extern crate simple_logger;
 use lolbench::*;
 
 use std::path::PathBuf;
+use std::time::Duration as StdDuration;
 
-use chrono::{NaiveDate, Utc};
+use chrono::{Duration, NaiveDate, Utc};
 use structopt::StructOpt;
 
 fn main() -> Result<()> {
@@ -47,10 +48,87 @@ struct Measure {
     #[structopt(short = "k", long = "move-kthreads")]
     move_kernel_threads: bool,
 
+    /// How many jobs cargo may run when building benchmarks. If a CPU pattern is set, benchmarks
+    /// are built on the remaining CPUs while others are being measured.
+    #[structopt(short = "j", long = "build-jobs")]
+    build_jobs: Option<u32>,
+
+    /// Kill a benchmark run which takes longer than this many minutes.
+    #[structopt(long = "run-timeout-minutes")]
+    run_timeout_minutes: Option<u64>,
+
+    /// Stop a benchmark run whose address space grows past this many megabytes.
+    #[structopt(long = "run-memory-mb")]
+    run_memory_mb: Option<u64>,
+
+    /// Stop a benchmark run which uses more than this many seconds of CPU time.
+    #[structopt(long = "run-cpu-seconds")]
+    run_cpu_seconds: Option<u64>,
+
+    /// Measure each benchmark binary in this many independent processes and combine their
+    /// estimates.
+    #[structopt(long = "process-runs", default_value = "1")]
+    process_runs: u32,
+
+    /// Take repeated process runs in rounds, running every other benchmark for the toolchain
+    /// between two runs of the same one.
+    #[structopt(long = "interleave-runs")]
+    interleave_runs: bool,
+
+    /// How many samples criterion takes of each benchmark. This and the other criterion settings
+    /// apply to every benchmark, except where registry.toml overrides them for one. Any override,
+    /// from here or from the registry, becomes part of a benchmark's measurement key, so those
+    /// benchmarks no longer share results with earlier runs made under criterion's defaults.
+    #[structopt(long = "sample-size")]
+    sample_size: Option<usize>,
+
+    /// How long criterion warms each benchmark up for, in milliseconds.
+    #[structopt(long = "warm-up-time-ms")]
+    warm_up_time_ms: Option<usize>,
+
+    /// How long criterion spends measuring each benchmark, in milliseconds.
+    #[structopt(long = "measurement-time-ms")]
+    measurement_time_ms: Option<usize>,
+
+    /// How many bootstrap resamples criterion takes when computing its estimates.
+    #[structopt(long = "nresamples")]
+    nresamples: Option<usize>,
+
+    /// Changes smaller than this fraction are treated as noise by criterion.
+    #[structopt(long = "noise-threshold")]
+    noise_threshold: Option<f32>,
+
+    /// The confidence level of criterion's confidence intervals.
+    #[structopt(long = "confidence-level")]
+    confidence_level: Option<f32>,
+
+    /// The significance level criterion uses to decide whether a benchmark changed.
+    #[structopt(long = "significance-level")]
+    significance_level: Option<f32>,
+
+    /// Look for changes between results measured on hosts with different fingerprints (e.g.
+    /// before and after a kernel or microcode update). Off by default, since those changes would
+    /// be the host's rather than the compiler's.
+    #[structopt(long = "compare-across-hosts")]
+    compare_across_hosts: bool,
+
     /// Limit the benchmarks run to those assigned to the given runner.
     #[structopt(long = "runner")]
     runner: Option<String>,
 
+    /// Only run benchmarks from crates matching this glob, e.g. `regex_*`. May be repeated.
+    #[structopt(long = "crate")]
+    crates: Vec<String>,
+
+    /// Only run benchmarks whose `crate::bench_fn` key matches this glob, e.g.
+    /// `regex_0_2_6::sherlock::*`. May be repeated.
+    #[structopt(long = "bench")]
+    benches: Vec<String>,
+
+    /// Skip benchmarks whose `crate::bench_fn` key matches this glob. May be repeated.
+    #[structopt(long = "exclude")]
+    exclude: Vec<String>,
+
     /// Run benchmarks with a single toolchain.
     #[structopt(long = "single-toolchain")]
     single_toolchain: Option<String>,
@@ -59,6 +137,58 @@ struct Measure {
     #[structopt(long = "nightlies-since")]
     nightlies_since: Option<NaiveDate>,
 
+    /// Run benchmarks with every stable release starting from a specific version (e.g. 1.20.0).
+    #[structopt(long = "stable-since")]
+    stable_since: Option<RustVersion>,
+
+    /// Run benchmarks with dated betas starting from a specific date.
+    #[structopt(long = "betas-since")]
+    betas_since: Option<NaiveDate>,
+
+    /// Run benchmarks with the toolchains listed in a file, one per line. Blank lines and lines
+    /// starting with `#` are ignored.
+    #[structopt(long = "toolchains-file", parse(from_os_str))]
+    toolchains_file: Option<PathBuf>,
+
+    /// Only run nightlies or betas from every Nth day of the range, plus its last day.
+    #[structopt(long = "every-nth-day")]
+    every_nth_day: Option<u32>,
+
+    /// Run nightlies or betas from every Nth day (weekly by default), and fill in the days between
+    /// existing results where a benchmark's runtime changed.
+    #[structopt(long = "coarse-then-refine")]
+    coarse_then_refine: bool,
+
+    /// Resolve toolchains from a directory of unpacked or locally built sysroots, each named after
+    /// its directory, instead of installing them with rustup.
+    #[structopt(long = "toolchains-dir", parse(from_os_str))]
+    toolchains_dir: Option<PathBuf>,
+
+    /// If toolchains are resolved from a directory, register them with `rustup toolchain link`
+    /// instead of pointing cargo at their rustc with `RUSTC`.
+    #[structopt(long = "link-toolchains")]
+    link_toolchains: bool,
+
+    /// How long to wait before looking again for a toolchain which couldn't be found. Doubles
+    /// after each failed attempt, up to a week.
+    #[structopt(long = "retry-missing-after-hours", default_value = "6")]
+    retry_missing_after_hours: i64,
+
+    /// How many recently used toolchains to keep installed between runs, along with their target
+    /// directories.
+    #[structopt(long = "cache-toolchains", default_value = "3")]
+    cache_toolchains: usize,
+
+    /// How much disk space the cached target directories may use, in gigabytes.
+    #[structopt(long = "cache-gb", default_value = "30")]
+    cache_gb: u64,
+
+    /// Also measure benchmarks built with a named configuration on top of the release profile,
+    /// e.g. `thin-lto:lto=thin,codegen-units=16` or `native:target-cpu=native`. Supported options
+    /// are opt-level, target-cpu, lto, codegen-units, panic and rustflag. Can be repeated.
+    #[structopt(long = "build-config")]
+    build_configs: Vec<BuildConfig>,
+
     /// Path to data directory. Will be created if empty.
     #[structopt(long = "data-dir", parse(from_os_str))]
     data_dir: PathBuf,
@@ -70,40 +200,310 @@ struct Measure {
     /// If passed, we'll attempt to upload the website when committing to the data directory.
     #[structopt(long = "publish")]
     publish: bool,
+
+    /// Print how many plans would be built, run or skipped because their results are stored, with
+    /// an estimate of how long it would all take, without installing, building or running anything.
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+
+    /// With --dry-run, also list the plans for each toolchain which would be built or run.
+    #[structopt(long = "verbose", short = "v")]
+    verbose: bool,
 }
 
 impl Measure {
     fn run(self) -> Result<()> {
+        let today = Utc::today().naive_utc();
         let toolchains = match self {
             Self {
                 single_toolchain: Some(toolchain),
                 nightlies_since: None,
+                stable_since: None,
+                betas_since: None,
+                toolchains_file: None,
                 ..
             } => ToolchainSpec::Single(toolchain.clone()),
 
             Self {
                 single_toolchain: None,
                 nightlies_since: Some(start),
+                stable_since: None,
+                betas_since: None,
+                toolchains_file: None,
                 ..
-            } => ToolchainSpec::Range(start.clone(), Utc::today().naive_utc()),
+            } => ToolchainSpec::Range(start.clone(), today),
+
+            Self {
+                single_toolchain: None,
+                nightlies_since: None,
+                stable_since: Some(start),
+                betas_since: None,
+                toolchains_file: None,
+                ..
+            } => ToolchainSpec::StableRange(start, RustVersion::latest_stable_as_of(today)),
+
+            Self {
+                single_toolchain: None,
+                nightlies_since: None,
+                stable_since: None,
+                betas_since: Some(start),
+                toolchains_file: None,
+                ..
+            } => ToolchainSpec::BetaRange(start, today),
+
+            Self {
+                single_toolchain: None,
+                nightlies_since: None,
+                stable_since: None,
+                betas_since: None,
+                toolchains_file: Some(path),
+                ..
+            } => ToolchainSpec::List(
+                ::std::fs::read_to_string(path)?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(Toolchain::from)
+                    .collect(),
+            ),
 
             _ => bail!("unsupported toolchain configuration"),
         };
 
+        let sampling = match (self.every_nth_day, self.coarse_then_refine) {
+            (Some(n), true) => Sampling::CoarseThenRefine(n),
+            (None, true) => Sampling::CoarseThenRefine(7),
+            (Some(n), false) => Sampling::EveryNthDay(n),
+            (None, false) => Sampling::Daily,
+        };
+
         let kthread_on = self.move_kernel_threads;
 
-        let shield_spec = self.cpu_pattern.as_ref().map(move |cpus| ShieldSpec {
-            cpu_mask: cpus.to_string(),
-            kthread_on,
-        });
+        let shield_spec = self
+            .cpu_pattern
+            .as_ref()
+            .map(|cpus| ShieldSpec::new(cpus, kthread_on))
+            .transpose()?;
+
+        let link = self.link_toolchains;
+        let local_toolchains = self
+            .toolchains_dir
+            .clone()
+            .map(|root| LocalToolchains { root, link });
 
         let opts = BenchOpts {
             toolchains,
-            runner: self.runner.clone(),
+            filter: BenchFilter::new(
+                self.runner.clone(),
+                &self.crates,
+                &self.benches,
+                &self.exclude,
+            )?,
+            shield_spec,
+            local_toolchains,
+            retry_policy: RetryPolicy {
+                initial: Duration::hours(self.retry_missing_after_hours),
+                ..RetryPolicy::default()
+            },
+            cache_limits: CacheLimits {
+                max_toolchains: self.cache_toolchains,
+                max_target_bytes: self.cache_gb * 1024 * 1024 * 1024,
+            },
+            build_configs: self.build_configs.clone(),
+            sampling,
+            build_jobs: self.build_jobs,
+            run_limits: RunLimits {
+                timeout: self
+                    .run_timeout_minutes
+                    .map(|minutes| StdDuration::from_secs(minutes * 60)),
+                max_memory_bytes: self.run_memory_mb.map(|mb| mb * 1024 * 1024),
+                max_cpu_seconds: self.run_cpu_seconds,
+            },
+            process_runs: self.process_runs,
+            interleave_runs: self.interleave_runs,
+            criterion_overrides: CriterionOverrides {
+                sample_size: self.sample_size,
+                warm_up_time_ms: self.warm_up_time_ms,
+                measurement_time_ms: self.measurement_time_ms,
+                nresamples: self.nresamples,
+                noise_threshold: self.noise_threshold.map(r32),
+                confidence_level: self.confidence_level.map(r32),
+                significance_level: self.significance_level.map(r32),
+            },
+            compare_across_hosts: self.compare_across_hosts,
+        };
+
+        if self.dry_run {
+            println!(
+                "{}",
+                plan_measurements(opts, &self.data_dir, &self.site_dir)?.with_verbose(self.verbose)
+            );
+            Ok(())
+        } else {
+            measure(opts, &self.data_dir, &self.site_dir, self.publish)
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct Bisect {
+    /// The benchmark to bisect, e.g. `crossbeam_epoch_0_4_0::defer::multi_alloc_defer_free`.
+    benchmark: String,
+
+    /// The last nightly known to have the old results, e.g. `nightly-2018-06-01`.
+    #[structopt(long = "good")]
+    good: String,
+
+    /// A later nightly with the new results.
+    #[structopt(long = "bad")]
+    bad: String,
+
+    /// The metric to compare, e.g. `nanoseconds` or `instructions`.
+    #[structopt(long = "metric", default_value = "nanoseconds")]
+    metric: String,
+
+    /// How far a nightly's median has to move from the good toolchain's, in percent and in the
+    /// same direction as the bad toolchain's, to count as bad.
+    #[structopt(long = "threshold-percent", default_value = "5")]
+    threshold_percent: f64,
+
+    /// Selects specific CPUs on which *only* benchmarks will run, as for `measure`.
+    #[structopt(short = "c", long = "cpus")]
+    cpu_pattern: Option<String>,
+
+    /// If a CPU pattern is set, also ask the kernel to try to relocate kernel tasks off of
+    /// benchmark CPUs.
+    #[structopt(short = "k", long = "move-kthreads")]
+    move_kernel_threads: bool,
+
+    /// Build with a named configuration instead of the release profile, as for `measure`.
+    #[structopt(long = "build-config")]
+    build_config: Option<BuildConfig>,
+
+    /// Resolve toolchains from a directory of sysroots instead of installing them with rustup.
+    #[structopt(long = "toolchains-dir", parse(from_os_str))]
+    toolchains_dir: Option<PathBuf>,
+
+    /// Path to data directory. Will be created if empty.
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    data_dir: PathBuf,
+
+    /// Path to site directory. Will be created if empty.
+    #[structopt(long = "site-dir", parse(from_os_str))]
+    site_dir: PathBuf,
+}
+
+impl Bisect {
+    fn run(self) -> Result<()> {
+        let kthread_on = self.move_kernel_threads;
+        let shield_spec = self
+            .cpu_pattern
+            .as_ref()
+            .map(|cpus| ShieldSpec::new(cpus, kthread_on))
+            .transpose()?;
+
+        let opts = BisectOpts {
+            benchmark_key: self.benchmark,
+            good: Toolchain::from(&self.good),
+            bad: Toolchain::from(&self.bad),
+            metric: self.metric,
+            threshold_percent: self.threshold_percent,
             shield_spec,
+            build_config: self.build_config,
+            local_toolchains: self
+                .toolchains_dir
+                .map(|root| LocalToolchains { root, link: false }),
         };
 
-        measure(opts, &self.data_dir, &self.site_dir, self.publish)
+        let report = bisect(opts, &self.data_dir, &self.site_dir)?;
+        println!("{}", report);
+        Ok(())
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct ProfileAnomalies {
+    /// Only profile anomalies of benchmarks assigned to the given runner.
+    #[structopt(long = "runner")]
+    runner: Option<String>,
+
+    /// Only profile anomalies of benchmarks from crates matching this glob. May be repeated.
+    #[structopt(long = "crate")]
+    crates: Vec<String>,
+
+    /// Only profile anomalies of benchmarks whose `crate::bench_fn` key matches this glob. May be
+    /// repeated.
+    #[structopt(long = "bench")]
+    benches: Vec<String>,
+
+    /// Skip benchmarks whose `crate::bench_fn` key matches this glob. May be repeated.
+    #[structopt(long = "exclude")]
+    exclude: Vec<String>,
+
+    /// Also profile anomalies in results of a named configuration, as for `measure`. Can be
+    /// repeated.
+    #[structopt(long = "build-config")]
+    build_configs: Vec<BuildConfig>,
+
+    /// Selects specific CPUs on which *only* benchmarks will run, as for `measure`.
+    #[structopt(short = "c", long = "cpus")]
+    cpu_pattern: Option<String>,
+
+    /// If a CPU pattern is set, also ask the kernel to try to relocate kernel tasks off of
+    /// benchmark CPUs.
+    #[structopt(short = "k", long = "move-kthreads")]
+    move_kernel_threads: bool,
+
+    /// Look for anomalies between results measured on hosts with different fingerprints.
+    #[structopt(long = "compare-across-hosts")]
+    compare_across_hosts: bool,
+
+    /// How many recently used toolchains to keep installed, as for `measure`. Toolchains are
+    /// installed again to rebuild binaries which have been evicted.
+    #[structopt(long = "cache-toolchains", default_value = "3")]
+    cache_toolchains: usize,
+
+    /// How much disk space the cached target directories may use, in gigabytes.
+    #[structopt(long = "cache-gb", default_value = "30")]
+    cache_gb: u64,
+
+    /// Path to data directory.
+    #[structopt(long = "data-dir", parse(from_os_str))]
+    data_dir: PathBuf,
+
+    /// Path to site directory.
+    #[structopt(long = "site-dir", parse(from_os_str))]
+    site_dir: PathBuf,
+}
+
+impl ProfileAnomalies {
+    fn run(self) -> Result<()> {
+        let kthread_on = self.move_kernel_threads;
+        let shield_spec = self
+            .cpu_pattern
+            .as_ref()
+            .map(|cpus| ShieldSpec::new(cpus, kthread_on))
+            .transpose()?;
+
+        let opts = ProfileOpts {
+            filter: BenchFilter::new(
+                self.runner.clone(),
+                &self.crates,
+                &self.benches,
+                &self.exclude,
+            )?,
+            build_configs: self.build_configs.clone(),
+            shield_spec,
+            compare_across_hosts: self.compare_across_hosts,
+            cache_limits: CacheLimits {
+                max_toolchains: self.cache_toolchains,
+                max_target_bytes: self.cache_gb * 1024 * 1024 * 1024,
+            },
+        };
+
+        let profiled = profile_anomalies(opts, &self.data_dir, &self.site_dir)?;
+        println!("stored {} profiles", profiled);
+        Ok(())
     }
 }
 
@@ -126,11 +526,33 @@ enum SubCommand {
         #[structopt(flatten)]
         inner: Measure,
     },
+    /// Find the first nightly between two others where a benchmark's results changed.
+    #[structopt(name = "bisect")]
+    Bisect {
+        #[structopt(flatten)]
+        inner: Bisect,
+    },
+    /// Rerun the binaries on either side of each anomaly in stored results under `perf record`,
+    /// storing their profiles for the website's differential flame graphs.
+    #[structopt(name = "profile-anomalies")]
+    ProfileAnomalies {
+        #[structopt(flatten)]
+        inner: ProfileAnomalies,
+    },
     #[structopt(name = "rebalance-benchmarks")]
     Rebalance {
         #[structopt(long = "sample-dir", parse(from_os_str))]
         sample_dir: PathBuf,
     },
+    /// Forget that toolchains couldn't be found, so that the next measurement looks for them.
+    #[structopt(name = "clear-unavailable")]
+    ClearUnavailable {
+        #[structopt(long = "data-dir", parse(from_os_str))]
+        data_dir: PathBuf,
+        /// Only clear the record for this toolchain.
+        #[structopt(long = "toolchain")]
+        toolchain: Option<String>,
+    },
     #[structopt(name = "build-website")]
     Present {
         #[structopt(long = "data-dir", parse(from_os_str))]
@@ -139,6 +561,9 @@ enum SubCommand {
         output_dir: PathBuf,
         #[structopt(long = "publish")]
         publish: bool,
+        /// Look for changes between results measured on hosts with different fingerprints.
+        #[structopt(long = "compare-across-hosts")]
+        compare_across_hosts: bool,
     },
 }
 
@@ -147,12 +572,19 @@ impl Cli {
         match self.cmd {
             SubCommand::Measure { inner } => inner.run(),
             SubCommand::Generate { inner } => inner.run(),
+            SubCommand::Bisect { inner } => inner.run(),
+            SubCommand::ProfileAnomalies { inner } => inner.run(),
             SubCommand::Rebalance { sample_dir } => rebalance(sample_dir),
+            SubCommand::ClearUnavailable {
+                data_dir,
+                toolchain,
+            } => clear_unavailable(&data_dir, toolchain.as_ref().map(String::as_str)),
             SubCommand::Present {
                 data_dir,
                 output_dir,
                 publish,
-            } => build_website(data_dir, output_dir, publish),
+                compare_across_hosts,
+            } => build_website(data_dir, output_dir, publish, compare_across_hosts),
         }
     }
 }
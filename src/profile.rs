@@ -0,0 +1,311 @@
+//! Profiles of the binaries on either side of an anomalous change in a benchmark's results, so
+//! that where the time went can be seen without rebuilding and reprofiling by hand.
+
+use super::Result;
+
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io::{self, BufRead};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use analysis::{anomalous_gaps, TimingRecord};
+use build_config::BuildConfig;
+use collector::Collector;
+use cpu_shield::ShieldSpec;
+use criterion_config;
+use disassembly::strip_hashes;
+use registry::{get_benches, BenchFilter};
+use run_plan::RunPlan;
+use signal::exit_if_needed;
+use storage::GitStore;
+use toolchain::Channel;
+use toolchain_cache::CacheLimits;
+
+#[derive(Debug)]
+pub struct ProfileOpts {
+    /// Which benchmarks' anomalies to profile.
+    pub filter: BenchFilter,
+    /// Configurations whose results are profiled in addition to the release profile's.
+    pub build_configs: Vec<BuildConfig>,
+    pub shield_spec: Option<ShieldSpec>,
+    /// Look for anomalies across results from hosts with different fingerprints.
+    pub compare_across_hosts: bool,
+    pub cache_limits: CacheLimits,
+}
+
+/// Finds anomalous changes in stored results the same way as the website does, and profiles the
+/// binaries built by the toolchains on either side of each one, rebuilt for profiling. Returns how
+/// many profiles were stored.
+pub fn profile_anomalies(opts: ProfileOpts, data_dir: &Path, site_dir: &Path) -> Result<usize> {
+    let benchmarks = get_benches(&opts.filter)?
+        .into_iter()
+        .map(|b| (b.key(), b))
+        .collect::<BTreeMap<_, _>>();
+
+    let storage = GitStore::ensure_initialized(data_dir)?;
+    let toolchain_metadata = storage.all_toolchain_metadata()?;
+    let mut collector = Collector::new(data_dir, site_dir)?.with_cache_limits(opts.cache_limits);
+    let mut profiled = 0;
+
+    for ((bench_key, config_name), estimates) in storage.all_stored_estimates()? {
+        let benchmark = match benchmarks.get(&bench_key) {
+            Some(b) => b,
+            None => continue,
+        };
+
+        let build_config = match config_name {
+            Some(name) => match opts.build_configs.iter().find(|c| c.name == name) {
+                Some(cfg) => Some(cfg.clone()),
+                None => continue,
+            },
+            None => None,
+        };
+
+        let mut by_channel = BTreeMap::<Channel, Vec<_>>::new();
+        for (toolchain, estimate) in estimates {
+            if let Some(toolchain) = toolchain {
+                by_channel
+                    .entry(toolchain.channel())
+                    .or_default()
+                    .push((toolchain, estimate));
+            }
+        }
+
+        for series in by_channel.values() {
+            let timings = TimingRecord::series(
+                series.iter().cloned(),
+                &toolchain_metadata,
+                opts.compare_across_hosts,
+            );
+
+            for (before, after) in anomalous_gaps(&timings) {
+                for toolchain in vec![before, after] {
+                    exit_if_needed();
+
+                    let rp = RunPlan::new(
+                        benchmark.clone(),
+                        criterion_config(&benchmark.criterion),
+                        build_config.clone(),
+                        opts.shield_spec.clone(),
+                        Some(toolchain.clone()),
+                        benchmark.entrypoint_path.clone(),
+                    )?;
+                    rp.validate()?;
+
+                    match collector.profile(&rp) {
+                        Ok(true) => profiled += 1,
+                        Ok(false) => (),
+                        Err(why) => warn!("unable to profile {}: {}", rp, why),
+                    }
+                }
+            }
+        }
+    }
+
+    storage.commit(&format!("profiled {} anomalous results", profiled))?;
+    Ok(profiled)
+}
+
+/// Folds the output of `perf script` into one line per distinct stack, root first, with frames
+/// separated by `;` and followed by how many samples had that stack, e.g.
+/// `main;bench::run;alloc::alloc::alloc 12`. Symbol hashes and offsets are left out so that
+/// profiles of binaries built by different toolchains line up.
+pub fn fold_perf_script(mut script: impl BufRead) -> Result<String> {
+    let mut counts = BTreeMap::<String, u64>::new();
+    let mut frames = Vec::new();
+    let mut line = Vec::new();
+
+    // samples are a header line followed by indented frames, leaf first, and end with a blank line
+    loop {
+        line.clear();
+        let at_end = script.read_until(b'\n', &mut line)? == 0;
+        let text = String::from_utf8_lossy(&line);
+
+        if text.trim().is_empty() {
+            if !frames.is_empty() {
+                frames.reverse();
+                *counts.entry(frames.join(";")).or_insert(0) += 1;
+                frames.clear();
+            }
+        } else if text.starts_with(char::is_whitespace) {
+            frames.extend(frame_symbol(&text));
+        }
+
+        if at_end {
+            break;
+        }
+    }
+
+    Ok(counts
+        .into_iter()
+        .map(|(stack, count)| format!("{} {}\n", stack, count))
+        .collect())
+}
+
+/// Creates a fifo for perf's control commands or their acknowledgements.
+pub fn make_fifo(path: &Path) -> Result<()> {
+    let _ = ::std::fs::remove_file(path);
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { ::libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+        bail!(
+            "unable to create fifo at {}: {}",
+            path.display(),
+            io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// The symbol of a `perf script` frame like `\t  55d0c4a1b2c3 foo::bar::h0123456789abcdef+0x23
+/// (/path/to/binary)`.
+fn frame_symbol(line: &str) -> Option<String> {
+    let line = line.trim();
+    let mut symbol = &line[line.find(char::is_whitespace)?..];
+    if let Some(dso) = symbol.rfind(" (") {
+        symbol = &symbol[..dso];
+    }
+    if let Some(offset) = symbol.rfind("+0x") {
+        if symbol[offset + 3..].chars().all(|c| c.is_digit(16)) {
+            symbol = &symbol[..offset];
+        }
+    }
+
+    let symbol = strip_hashes(symbol.trim());
+    if symbol.is_empty() {
+        None
+    } else {
+        Some(symbol.replace(';', ":"))
+    }
+}
+
+/// A frame of a differential flame graph, in the shape d3-flame-graph expects. `value` is the
+/// frame's share of the later profile's samples in percent, and `delta` how many percentage points
+/// that grew by since the earlier profile.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct FlameNode {
+    pub name: String,
+    pub value: f64,
+    pub delta: f64,
+    pub children: Vec<FlameNode>,
+}
+
+impl FlameNode {
+    /// Compares two profiles folded by `fold_perf_script`. Frames which only appear in the earlier
+    /// profile are kept with a value of zero, so their (negative) deltas still count towards
+    /// their parents'.
+    pub fn differential(before: &str, after: &str) -> Self {
+        #[derive(Default)]
+        struct Frame {
+            value: f64,
+            delta: f64,
+            children: BTreeMap<String, Frame>,
+        }
+
+        impl Frame {
+            fn add(&mut self, percent: f64, later: bool) {
+                if later {
+                    self.value += percent;
+                    self.delta += percent;
+                } else {
+                    self.delta -= percent;
+                }
+            }
+
+            fn into_node(self, name: String) -> FlameNode {
+                FlameNode {
+                    name,
+                    value: self.value,
+                    delta: self.delta,
+                    children: self
+                        .children
+                        .into_iter()
+                        .map(|(name, frame)| frame.into_node(name))
+                        .collect(),
+                }
+            }
+        }
+
+        // the later profile sets the frames' widths, and both contribute to their deltas
+        let mut root = Frame::default();
+        for &(folded, later) in &[(after, true), (before, false)] {
+            let stacks = parse_folded(folded);
+            let total = stacks.iter().map(|&(_, count)| count).sum::<f64>();
+            for (stack, count) in stacks {
+                let percent = count / total * 100.0;
+                let mut frame = &mut root;
+                frame.add(percent, later);
+                for name in stack.split(';') {
+                    frame = frame.children.entry(name.to_string()).or_default();
+                    frame.add(percent, later);
+                }
+            }
+        }
+
+        root.into_node(String::from("all"))
+    }
+}
+
+/// The stacks of a folded profile along with their sample counts.
+fn parse_folded(folded: &str) -> Vec<(&str, f64)> {
+    folded
+        .lines()
+        .filter_map(|line| {
+            let split = line.rfind(' ')?;
+            Some((&line[..split], line[split + 1..].parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folds_perf_script_output() {
+        let script = "\
+bench 1234 [002] 100.000001:     1001001 cycles:ppp:
+\t    55d0c4a1b2c3 alloc::raw_vec::RawVec<T>::reserve::h0123456789abcdef+0x23 (/bin/bench)
+\t    55d0c4a1b000 bench::run::hfedcba9876543210+0x10 (/bin/bench)
+\t    55d0c4a1a000 main+0x5 (/bin/bench)
+
+bench 1234 [002] 100.001002:     1001001 cycles:ppp:
+\t    55d0c4a1b000 bench::run::hfedcba9876543210+0x18 (/bin/bench)
+\t    55d0c4a1a000 main+0x5 (/bin/bench)
+
+bench 1234 [002] 100.002003:     1001001 cycles:ppp:
+\t    55d0c4a1b2d0 alloc::raw_vec::RawVec<T>::reserve::h0123456789abcdef+0x40 (/bin/bench)
+\t    55d0c4a1b000 bench::run::hfedcba9876543210+0x10 (/bin/bench)
+\t    55d0c4a1a000 main+0x5 (/bin/bench)
+\t               0 [unknown] ([unknown])
+";
+
+        assert_eq!(
+            fold_perf_script(script.as_bytes()).unwrap(),
+            "[unknown];main;bench::run;alloc::raw_vec::RawVec<T>::reserve 1\n\
+             main;bench::run 1\n\
+             main;bench::run;alloc::raw_vec::RawVec<T>::reserve 1\n"
+        );
+    }
+
+    #[test]
+    fn differential_graphs_compare_shares_of_samples() {
+        let before = "main;run 30\nmain;run;grow 10\n";
+        let after = "main;run 20\nmain;run;grow 20\nmain;other 10\n";
+        let graph = FlameNode::differential(before, after);
+
+        let main = &graph.children[0];
+        assert_eq!(main.name, "main");
+        assert!((main.value - 100.0).abs() < 1e-9);
+        assert!(main.delta.abs() < 1e-9);
+
+        let names = main.children.iter().map(|c| &c.name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["other", "run"]);
+        let run = &main.children[1];
+        assert!((run.value - 80.0).abs() < 1e-9);
+        assert!((run.delta + 20.0).abs() < 1e-9);
+        let grow = &run.children[0];
+        assert!((grow.value - 40.0).abs() < 1e-9);
+        assert!((grow.delta - 15.0).abs() < 1e-9);
+    }
+}
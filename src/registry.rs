@@ -7,33 +7,84 @@ use std::collections::BTreeMap;
 use std::iter::FromIterator;
 use std::path::Path;
 
+use glob::Pattern;
 use noisy_float::prelude::*;
 
 use marky_mark::{Benchmark, Registry};
 
 use storage::GitStore;
 
-pub fn get_benches(runner: Option<&str>) -> Result<Vec<Benchmark>> {
+/// Narrows down the benchmarks to run. Patterns are globs, e.g. `regex_0_2_6::misc::*`, and a
+/// benchmark is selected if it matches any of the crate and bench patterns (or there are none) and
+/// none of the exclusions.
+#[derive(Clone, Debug, Default, Eq, PartialEq, PartialOrd, Ord)]
+pub struct BenchFilter {
+    /// Only benchmarks assigned to this runner.
+    pub runner: Option<String>,
+    /// Matched against the benchmark's crate name.
+    pub crates: Vec<Pattern>,
+    /// Matched against the benchmark's key, `crate::bench_fn`.
+    pub benches: Vec<Pattern>,
+    /// Matched against the benchmark's key.
+    pub exclude: Vec<Pattern>,
+}
+
+impl BenchFilter {
+    pub fn new(
+        runner: Option<String>,
+        crates: &[String],
+        benches: &[String],
+        exclude: &[String],
+    ) -> Result<Self> {
+        let patterns = |globs: &[String]| -> Result<Vec<Pattern>> {
+            globs
+                .iter()
+                .map(|g| {
+                    Pattern::new(g).map_err(|why| format_err!("invalid pattern `{}`: {}", g, why))
+                })
+                .collect()
+        };
+
+        Ok(Self {
+            runner,
+            crates: patterns(crates)?,
+            benches: patterns(benches)?,
+            exclude: patterns(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, benchmark: &Benchmark) -> bool {
+        let key = benchmark.key();
+        let any = |patterns: &[Pattern], s: &str| patterns.iter().any(|p| p.matches(s));
+
+        (self.runner.is_none() || benchmark.runner == self.runner)
+            && (self.crates.is_empty() || any(&self.crates, &benchmark.crate_name))
+            && (self.benches.is_empty() || any(&self.benches, &key))
+            && !any(&self.exclude, &key)
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+pub fn get_benches(filter: &BenchFilter) -> Result<Vec<Benchmark>> {
     let (reg, _f) = Registry::from_disk()?;
     let benchmarks = reg.benches();
 
     info!("Found and parsed {} benchmarks.", benchmarks.len());
 
-    Ok(if let Some(r) = runner {
+    Ok(if filter.is_empty() {
+        benchmarks
+    } else {
         let b = benchmarks
             .into_iter()
-            .filter(|b| b.runner.as_ref().map(String::as_str) == runner)
+            .filter(|b| filter.matches(b))
             .collect::<Vec<_>>();
 
-        info!(
-            "{} benchmarks assigned to the requested runner ({}).",
-            b.len(),
-            r
-        );
+        info!("{} benchmarks match the filter: {:?}", b.len(), filter);
 
         b
-    } else {
-        benchmarks
     })
 }
 
@@ -79,7 +130,7 @@ pub fn rebalance(sample_data_dir: impl AsRef<Path>) -> Result<()> {
     info!("new assignments: {:#?}", new_assignments);
     info!("weights: {:?}", weights);
 
-    let all_benches = get_benches(None)?;
+    let all_benches = get_benches(&BenchFilter::default())?;
     let by_key = all_benches
         .into_iter()
         .map(|b| (b.key(), b))
@@ -102,7 +153,9 @@ fn most_covered_toolchains_runtimes(data_dir: impl AsRef<Path>) -> Result<Vec<(R
 
     info!("reorganizing them by toolchain");
     let mut by_toolchain = BTreeMap::new();
-    for (bench_key, toolchains) in estimates {
+    // other build configurations would count each benchmark more than once
+    for ((bench_key, _), toolchains) in estimates.into_iter().filter(|((_, cfg), _)| cfg.is_none())
+    {
         for (toolchain, estimate) in toolchains {
             by_toolchain
                 .entry(toolchain)
@@ -139,3 +192,36 @@ fn most_covered_toolchains_runtimes(data_dir: impl AsRef<Path>) -> Result<Vec<(R
 
     Ok(runtimes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_by_crate_bench_and_exclusion() {
+        let bench = |crate_name: &str, name: &str| Benchmark::new(crate_name, name, Path::new(""));
+        let patterns = |globs: &[&str]| {
+            globs
+                .iter()
+                .map(|g| Pattern::new(g).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let filter = BenchFilter {
+            crates: patterns(&["regex_*"]),
+            exclude: patterns(&["*::misc::*"]),
+            ..BenchFilter::default()
+        };
+        assert!(filter.matches(&bench("regex_0_2_6", "sherlock::words")));
+        assert!(!filter.matches(&bench("regex_0_2_6", "misc::literal")));
+        assert!(!filter.matches(&bench("byteorder_1_2_6", "f32::read_big_endian")));
+
+        let filter = BenchFilter {
+            benches: patterns(&["byteorder_1_2_6::f32::*", "*::sherlock::words"]),
+            ..BenchFilter::default()
+        };
+        assert!(filter.matches(&bench("regex_0_2_6", "sherlock::words")));
+        assert!(filter.matches(&bench("byteorder_1_2_6", "f32::read_big_endian")));
+        assert!(!filter.matches(&bench("byteorder_1_2_6", "f64::read_big_endian")));
+    }
+}
@@ -1,17 +1,27 @@
 use super::Result;
 
 use std::{
+    ffi::OsStr,
     fmt::{Display, Formatter, Result as FmtResult},
+    io,
     path::PathBuf,
-    process::Command,
+    process::{Command, Output, Stdio},
+    time::Instant,
 };
 
-use ring::digest::{digest, SHA256};
+use ring::digest::{digest, Context as RingContext, SHA256};
+use slug::slugify;
 
 use marky_mark::Benchmark;
 
-use cpu_shield::{RenameThisCommandWrapper, ShieldSpec};
-use toolchain::Toolchain;
+use build_config::BuildConfig;
+use cpu_shield::{output_with_usage, CpuSet, RenameThisCommandWrapper, RunLimits, ShieldSpec};
+use disassembly;
+use elf;
+use lolbench_support::{PERF_ACK_FIFO_VAR, PERF_CTL_FIFO_VAR};
+use profile;
+use storage::{Artifact, BinaryHash, Estimates, Statistic};
+use toolchain::{Toolchain, ToolchainProvider};
 use CriterionConfig;
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, PartialOrd, Ord, Serialize)]
@@ -20,9 +30,126 @@ pub struct RunPlan {
     pub benchmark: Benchmark,
     pub toolchain: Option<Toolchain>,
     pub bench_config: Option<CriterionConfig>,
+    pub build_config: Option<BuildConfig>,
     pub shield: Option<ShieldSpec>,
     pub source_path: PathBuf,
     pub manifest_path: PathBuf,
+    /// Whether the binary is built to be profiled, see `for_profiling`.
+    pub profiling: bool,
+}
+
+/// The keys under which build metrics are stored in a benchmark's `Estimates`.
+pub const BUILD_METRICS: &[&str] = &[
+    "build-nanoseconds",
+    "build-max-rss-kilobytes",
+    "binary-bytes",
+    "text-bytes",
+];
+
+/// The keys under which the conditions of a build are stored, which decide whether its metrics can
+/// be compared with another build's.
+pub const BUILD_CONTEXT: &[&str] = &["build-compiled-crates", "build-concurrent"];
+
+/// The outcome of building a benchmark binary.
+#[derive(Clone, Debug)]
+pub struct Built {
+    pub binary_hash: BinaryHash,
+    pub metrics: BuildMetrics,
+    /// cargo's output, including any warnings.
+    pub log: Artifact,
+}
+
+/// Returned when a build or a benchmark run exits unsuccessfully, with its full output.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", message)]
+pub struct CommandFailed {
+    pub message: String,
+    pub log: Artifact,
+}
+
+/// Returned when a plan's binary no longer has the code whose hash was recorded for it, e.g. because
+/// it was rebuilt by a toolchain which doesn't build it reproducibly. Its results would be stored
+/// under the wrong hash, so it isn't run.
+#[derive(Debug, Fail)]
+#[fail(
+    display = "the code in {} has changed since it was built for {}",
+    binary, plan
+)]
+pub struct BinaryChanged {
+    pub binary: String,
+    pub plan: String,
+}
+
+/// How expensive a benchmark was to build and how big the result was.
+#[derive(Clone, Copy, Debug)]
+pub struct BuildMetrics {
+    /// Wall-clock time for the whole cargo invocation.
+    pub nanoseconds: u64,
+    /// Peak resident set size of cargo and any rustc it waited for, in kilobytes.
+    pub max_rss_kilobytes: u64,
+    pub binary_bytes: u64,
+    pub text_bytes: u64,
+    /// How many crates cargo compiled, including the benchmark's own. A build into a target
+    /// directory which doesn't have the benchmark's dependencies yet compiles them too.
+    pub compiled_crates: u64,
+    /// Whether the build ran in the background while another benchmark was measured.
+    pub concurrent: bool,
+}
+
+impl BuildMetrics {
+    pub fn estimates(&self) -> Estimates {
+        BUILD_METRICS
+            .iter()
+            .zip(&[
+                self.nanoseconds,
+                self.max_rss_kilobytes,
+                self.binary_bytes,
+                self.text_bytes,
+            ])
+            .chain(
+                BUILD_CONTEXT
+                    .iter()
+                    .zip(&[self.compiled_crates, self.concurrent as u64]),
+            )
+            .map(|(name, &value)| (name.to_string(), Statistic::exact(value as f64)))
+            .collect()
+    }
+}
+
+/// Checks the ranges criterion would otherwise only reject (by panicking) once the benchmark runs.
+fn validate_criterion_config(cfg: &CriterionConfig) -> Result<()> {
+    ensure!(
+        cfg.sample_size >= 2,
+        "criterion needs a sample size of at least 2, got {}",
+        cfg.sample_size
+    );
+    ensure!(
+        cfg.measurement_time_ms > 0,
+        "criterion's measurement time must be positive"
+    );
+    ensure!(
+        cfg.nresamples > 0,
+        "criterion needs at least one bootstrap resample"
+    );
+    ensure!(
+        cfg.noise_threshold.raw() >= 0.0,
+        "criterion's noise threshold must not be negative, got {}",
+        cfg.noise_threshold
+    );
+
+    for &(name, level) in &[
+        ("confidence level", cfg.confidence_level),
+        ("significance level", cfg.significance_level),
+    ] {
+        ensure!(
+            level.raw() > 0.0 && level.raw() < 1.0,
+            "criterion's {} must be between 0 and 1, got {}",
+            name,
+            level
+        );
+    }
+
+    Ok(())
 }
 
 impl Display for RunPlan {
@@ -35,7 +162,11 @@ impl Display for RunPlan {
         f.write_fmt(format_args!(
             "{}: {}::{}@{:?}",
             tcs, self.benchmark.crate_name, self.benchmark.name, self.benchmark.runner,
-        ))
+        ))?;
+        if let Some(cfg) = &self.build_config {
+            f.write_fmt(format_args!(" ({})", cfg.name))?;
+        }
+        Ok(())
     }
 }
 
@@ -43,6 +174,7 @@ impl RunPlan {
     pub fn new(
         benchmark: Benchmark,
         bench_config: Option<CriterionConfig>,
+        build_config: Option<BuildConfig>,
         shield: Option<ShieldSpec>,
         toolchain: Option<Toolchain>,
         source_path: PathBuf,
@@ -72,21 +204,72 @@ impl RunPlan {
             manifest_path,
             binary_name,
             bench_config,
+            build_config,
+            profiling: false,
         })
     }
 
-    fn binary_path(&self) -> PathBuf {
+    /// The same plan, but building a binary which lets perf sample only the benchmark's body (see
+    /// `lolbench_support::Sampling`). Its code differs from the measured binary's by the calls
+    /// which switch sampling on and off, so it's kept in a target directory of its own.
+    pub fn for_profiling(&self) -> Self {
+        Self {
+            profiling: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn binary_path(&self) -> PathBuf {
         self.target_dir().join("release").join(&self.binary_name)
     }
 
-    fn target_dir(&self) -> PathBuf {
+    pub fn target_dir(&self) -> PathBuf {
         use std::env::var as envvar;
 
-        match self.toolchain {
+        let mut dir = match self.toolchain {
             Some(ref t) => t.target_dir(),
             None => {
                 PathBuf::from(envvar("CARGO_TARGET_DIR").unwrap_or_else(|_| String::from("target")))
             }
+        };
+
+        // changing RUSTFLAGS invalidates every artifact in a target directory, so each build
+        // configuration keeps its own inside the toolchain's to avoid thrashing between them, as do
+        // builds which count allocations
+        if let Some(ref cfg) = self.build_config {
+            dir.push(format!("config-{}", slugify(&cfg.name)));
+        }
+        if self.benchmark.count_allocations {
+            dir.push("count-allocations");
+        }
+        if self.profiling {
+            dir.push("profile");
+        }
+        dir
+    }
+
+    /// Applies the build configuration, if any, to a cargo invocation.
+    fn build_envs(&self) -> Vec<(&'static str, String)> {
+        let mut rustflags = self
+            .build_config
+            .iter()
+            .map(BuildConfig::rustflags_env)
+            .collect::<Vec<_>>();
+
+        // wrap_libtest! only measures allocations in crates built with this
+        if self.benchmark.count_allocations {
+            rustflags.push(String::from("--cfg lolbench_count_allocations"));
+        }
+
+        // and only lets perf sample the benchmark's body in crates built with this
+        if self.profiling {
+            rustflags.push(String::from("--cfg lolbench_profile"));
+        }
+
+        if rustflags.is_empty() {
+            Vec::new()
+        } else {
+            vec![("RUSTFLAGS", rustflags.join(" "))]
         }
     }
 
@@ -94,49 +277,110 @@ impl RunPlan {
         ensure!(self.source_path.is_file(), "source_path is not a file");
         ensure!(self.manifest_path.is_file(), "manifest_path is not a file");
 
-        // pub shield: Option<ShieldSpec>,
-        if let Some(_spec) = &self.shield {
-            // TODO(anp) validate it!
+        if let Some(spec) = &self.shield {
+            let cpus = spec.cpus()?;
+            ensure!(
+                cpus.to_string() == spec.cpu_mask,
+                "cpu mask `{}` isn't canonical, expected `{}`",
+                spec.cpu_mask,
+                cpus
+            );
+            cpus.ensure_within(&CpuSet::online()?)?;
+        }
+
+        if let Some(cfg) = &self.bench_config {
+            validate_criterion_config(cfg)?;
         }
 
-        if let Some(_cfg) = &self.bench_config {
-            // TODO(anp) validate it!
+        if let Some(cfg) = &self.build_config {
+            ensure!(
+                !slugify(&cfg.name).is_empty(),
+                "build configuration name `{}` can't be used in a path",
+                cfg.name
+            );
         }
 
         Ok(())
     }
 
-    /// Builds the benchmark target and returns the SHA256 sum of the binary.
-    pub fn build(&self) -> Result<Vec<u8>> {
+    /// Builds the benchmark target and returns the SHA256 sums of the binary and of its code, along
+    /// with what it cost to build. Cargo's default job count is used unless `jobs` is passed, and
+    /// the build (with everything it starts) is kept to `cpus` if they're passed.
+    pub fn build(
+        &self,
+        provider: &dyn ToolchainProvider,
+        jobs: Option<u32>,
+        cpus: Option<&CpuSet>,
+    ) -> Result<Built> {
         let target_name = self.source_path.file_stem().unwrap().to_string_lossy();
         info!("building {} with {:?}", target_name, self.toolchain);
 
-        let mut cmd = Command::new("cargo");
+        let mut cmd = match cpus {
+            Some(cpus) => {
+                let mut pinned = Command::new("taskset");
+                pinned.arg("--cpu-list").arg(cpus.to_string()).arg("cargo");
+                pinned
+            }
+            None => Command::new("cargo"),
+        };
+        let mut rustc = Command::new("rustc");
 
         if let Some(ref t) = self.toolchain {
-            cmd.arg(format!("+{}", t));
+            let cargo_toolchain = provider.cargo_toolchain(t);
+            cmd.args(cargo_toolchain.args());
+            cmd.envs(cargo_toolchain.envs());
+            rustc = cargo_toolchain.rustc_command();
+        }
+
+        if let Some(cfg) = &self.build_config {
+            cfg.ensure_accepted_by(rustc)?;
         }
 
-        let output = cmd
-            .arg("build")
-            .arg("--release")
+        cmd.arg("rustc");
+        if let Some(jobs) = jobs {
+            cmd.arg("--jobs").arg(jobs.to_string());
+        }
+
+        cmd.arg("--release")
             .arg("--manifest-path")
             .arg(&self.manifest_path)
             .arg("--bin")
             .arg(&*target_name)
             .env("CARGO_TARGET_DIR", &*self.target_dir())
-            .output()?;
+            .envs(self.build_envs());
+
+        if let Some(cfg) = &self.build_config {
+            cmd.arg("--").args(cfg.binary_rustflags());
+        }
+
+        let start = Instant::now();
+        let finished = output_with_usage(&mut cmd, None)?;
+        let elapsed = start.elapsed();
+
+        // covers the rustc invocations cargo waited for, too
+        let max_rss_kilobytes = finished.usage.ru_maxrss as u64;
+        let output = Output {
+            status: finished.status.expect("builds run without a timeout"),
+            stdout: finished.stdout,
+            stderr: finished.stderr,
+        };
+
+        let log = Artifact::log("build-log", &output);
 
         if !output.status.success() {
             let stdout = String::from_utf8_lossy(&output.stdout);
             let stderr = String::from_utf8_lossy(&output.stderr);
-            bail!(
-                "Unable to build {} with {:?} (None means current, adam is lazy).\nstdout:{}\nstderr:{}",
-                target_name,
-                self.toolchain,
-                stdout,
-                stderr
-            );
+            return Err(CommandFailed {
+                message: format!(
+                    "Unable to build {} with {:?} (None means current, adam is lazy).\nstdout:{}\nstderr:{}",
+                    target_name,
+                    self.toolchain,
+                    stdout,
+                    stderr
+                ),
+                log,
+            }
+            .into());
         }
 
         info!("done building {}", self.source_path.display());
@@ -146,25 +390,215 @@ impl RunPlan {
         debug!("reading contents of {} at {}", self, bin_path.display());
         let bin_contents = ::std::fs::read(&bin_path)?;
 
-        debug!("hashing binary contents");
-        Ok(digest(&SHA256, &bin_contents).as_ref().to_owned())
+        let (binary_hash, text_bytes) = self.hash_binary(&bin_contents)?;
+
+        Ok(Built {
+            binary_hash,
+            metrics: BuildMetrics {
+                nanoseconds: elapsed.as_nanos() as u64,
+                max_rss_kilobytes,
+                binary_bytes: bin_contents.len() as u64,
+                text_bytes,
+                compiled_crates: String::from_utf8_lossy(&output.stderr)
+                    .lines()
+                    .filter(|line| line.trim_start().starts_with("Compiling "))
+                    .count() as u64,
+                concurrent: false,
+            },
+            log,
+        })
     }
 
     /// Runs the benchmark target, implicitly writing criterion results to the target directory.
-    pub fn exec(&self) -> Result<()> {
+    ///
+    /// The binary must already have been built, and is only run if it's still the one whose hash
+    /// was recorded. It's run directly rather than with `cargo run`, which could rebuild it and
+    /// would run cargo inside the CPU shield. Only the variables set here and `PATH` are passed
+    /// through to it, and it runs from its crate's directory. Runs which exceed the limits fail
+    /// with the errors returned by `RenameThisCommandWrapper::output_with_limits`.
+    ///
+    /// Returns the run's log, which includes criterion's own warnings.
+    pub fn exec(&self, expected: &BinaryHash, limits: RunLimits) -> Result<Artifact> {
         debug!("configuring command for {}", self);
 
-        let mut cmd = RenameThisCommandWrapper::new("cargo", self.shield.clone());
+        let bin_path = self.built_binary(expected)?;
+        let mut cmd = self.bench_command(&bin_path)?;
+        debug!("running {} with {:?}", self, cmd);
+        let output = cmd.output_with_limits(limits)?;
+        let log = Artifact::log("run-log", &output);
 
-        if let Some(ref t) = self.toolchain {
-            cmd.arg(format!("+{}", t));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        if !output.status.success() {
+            return Err(CommandFailed {
+                message: format!("benchmark failed! stdout: {}, stderr: {}", stdout, stderr),
+                log,
+            }
+            .into());
         }
 
-        cmd.args(&["run", "--release", "--manifest-path"]);
-        cmd.arg(&self.manifest_path); // silly types
-        cmd.args(&["--bin", &self.binary_name]);
+        Ok(log)
+    }
 
-        cmd.env("CARGO_TARGET_DIR", &*self.target_dir());
+    /// Runs the benchmark target under `perf record`, like `exec`, and returns the stacks it
+    /// sampled folded into one line per distinct stack (see `profile::fold_perf_script`). perf only
+    /// samples while criterion runs the benchmark's body, which needs perf 5.10 or newer.
+    ///
+    /// The plan must be one from `for_profiling`, and its binary must already have been built.
+    pub fn profile(&self, limits: RunLimits) -> Result<Artifact> {
+        ensure!(self.profiling, "{} wasn't built for profiling", self);
+        let bin_path = self.binary_path();
+        ensure!(
+            bin_path.is_file(),
+            "{} hasn't been built, expected a binary at {}",
+            self,
+            bin_path.display()
+        );
+
+        let scratch = ::std::env::current_dir()?.join(self.target_dir());
+        let perf_data = scratch.join(format!("{}.perf.data", self.binary_name));
+        let ctl_fifo = scratch.join(format!("{}.perf-ctl", self.binary_name));
+        let ack_fifo = scratch.join(format!("{}.perf-ack", self.binary_name));
+
+        // cset would take perf's options as its own without the leading `--`, which perf ignores
+        let mut cmd = self.bench_command("perf")?;
+        cmd.arg("--")
+            .arg("record")
+            .args(&["--freq", "499", "--quiet"])
+            // each sample copies this much of the stack to unwind later, so both it and perf's
+            // buffers are kept small
+            .args(&["--call-graph", "dwarf,4096", "--mmap-pages", "64"])
+            .arg("--output")
+            .arg(&perf_data)
+            .arg("--delay=-1")
+            .arg(format!(
+                "--control=fifo:{},{}",
+                ctl_fifo.display(),
+                ack_fifo.display()
+            ))
+            .env(PERF_CTL_FIFO_VAR, &ctl_fifo)
+            .env(PERF_ACK_FIFO_VAR, &ack_fifo)
+            .arg("--")
+            .arg(&bin_path);
+
+        profile::make_fifo(&ctl_fifo)?;
+        profile::make_fifo(&ack_fifo)?;
+        debug!("profiling {} with {:?}", self, cmd);
+        let recorded = cmd.output_with_limits(limits);
+        for fifo in &[&ctl_fifo, &ack_fifo] {
+            let _ = ::std::fs::remove_file(fifo);
+        }
+        let output = recorded?;
+        if !output.status.success() {
+            let _ = ::std::fs::remove_file(&perf_data);
+            return Err(CommandFailed {
+                message: format!(
+                    "perf record failed! stderr: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                log: Artifact::log("profile-log", &output),
+            }
+            .into());
+        }
+
+        // perf script's output is many times the size of the recording, so it's folded as it's read
+        let mut script = Command::new("perf")
+            .arg("script")
+            .arg("--input")
+            .arg(&perf_data)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let folded = profile::fold_perf_script(io::BufReader::new(script.stdout.take().unwrap()));
+        let status = script.wait()?;
+        let _ = ::std::fs::remove_file(&perf_data);
+
+        let folded = folded?;
+        ensure!(
+            status.success(),
+            "perf script failed for {}: {}",
+            self,
+            status
+        );
+        ensure!(
+            !folded.is_empty(),
+            "perf didn't take any samples of {}",
+            self
+        );
+
+        Ok(Artifact {
+            name: String::from("profile"),
+            contents: folded.into_bytes(),
+        })
+    }
+
+    /// Returns the SHA256 sums of a binary and of its code, along with the size of its code.
+    fn hash_binary(&self, contents: &[u8]) -> Result<(BinaryHash, u64)> {
+        debug!("hashing binary contents");
+        let raw = digest(&SHA256, contents).as_ref().to_owned();
+
+        let (normalized, text_bytes) = match elf::sections(contents) {
+            Ok(sections) => (
+                code_hash(contents, &sections)?,
+                sections
+                    .iter()
+                    .filter(|s| s.name == ".text")
+                    .map(|s| s.size)
+                    .sum(),
+            ),
+            Err(why) => {
+                warn!(
+                    "unable to read {}'s sections, hashing all of it: {}",
+                    self, why
+                );
+                (raw.clone(), 0)
+            }
+        };
+
+        Ok((BinaryHash { normalized, raw }, text_bytes))
+    }
+
+    /// The path to the benchmark's binary, if its code is still the code whose hash was recorded.
+    /// The rest of the binary may differ, e.g. if it was rebuilt after being evicted.
+    fn built_binary(&self, expected: &BinaryHash) -> Result<PathBuf> {
+        let bin_path = self.binary_path();
+        ensure!(
+            bin_path.is_file(),
+            "{} hasn't been built, expected a binary at {}",
+            self,
+            bin_path.display()
+        );
+
+        let (on_disk, _) = self.hash_binary(&::std::fs::read(&bin_path)?)?;
+        if on_disk.normalized != expected.normalized {
+            return Err(BinaryChanged {
+                binary: bin_path.display().to_string(),
+                plan: self.to_string(),
+            }
+            .into());
+        }
+
+        Ok(bin_path)
+    }
+
+    /// A command running `program` (in the CPU shield if there is one) with the environment the
+    /// benchmark's binary expects.
+    fn bench_command(&self, program: impl AsRef<OsStr>) -> Result<RenameThisCommandWrapper> {
+        let mut cmd = RenameThisCommandWrapper::new(program, self.shield.clone());
+        cmd.env_clear();
+        if let Some(path) = ::std::env::var_os("PATH") {
+            // cset needs to be found when shielding
+            cmd.env("PATH", path);
+        }
+
+        // the crate's directory would be cargo's working directory when building it
+        cmd.current_dir(self.manifest_path.parent().unwrap());
+
+        // criterion writes its results relative to this, which mustn't move with the working dir
+        cmd.env(
+            "CARGO_TARGET_DIR",
+            ::std::env::current_dir()?.join(self.target_dir()),
+        );
 
         if let Some(cfg) = &self.bench_config {
             debug!("applying criterion config");
@@ -173,16 +607,27 @@ impl RunPlan {
             }
         }
 
-        debug!("running {} with {:?}", self, cmd);
-        let output = cmd.output()?;
-
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
+        Ok(cmd)
+    }
 
-        if !output.status.success() {
-            bail!("benchmark failed! stdout: {}, stderr: {}", stdout, stderr);
-        }
+    /// Disassembles the benchmark's code in its built binary, for comparing against the binaries
+    /// of other toolchains.
+    pub fn disassemble(&self) -> Result<Artifact> {
+        let text = disassembly::disassemble(&self.binary_path(), &self.benchmark)?;
+        Ok(Artifact {
+            name: String::from("disassembly"),
+            contents: text.into_bytes(),
+        })
+    }
+}
 
-        Ok(())
+/// Hashes the names and contents of a binary's executable sections. Notes like the build id and
+/// rustc's version string aren't executable, so binaries which only differ in those hash the same.
+fn code_hash(file: &[u8], sections: &[elf::Section]) -> Result<Vec<u8>> {
+    let mut hasher = RingContext::new(&SHA256);
+    for section in sections.iter().filter(|s| s.is_executable()) {
+        hasher.update(section.name.as_bytes());
+        hasher.update(section.contents(file)?);
     }
+    Ok(hasher.finish().as_ref().to_owned())
 }
@@ -1,6 +1,8 @@
 use libc::{SIGINT, SIGTERM};
 use signal_hook::iterator::Signals;
 
+use cpu_shield::reset_active_shield;
+
 lazy_static! {
     static ref SIGNALS: Signals = Signals::new(&[SIGTERM, SIGINT]).unwrap();
 }
@@ -10,10 +12,12 @@ pub fn exit_if_needed() {
         match signal {
             SIGINT => {
                 println!("received SIGINT, exiting");
+                reset_active_shield();
                 ::std::process::exit(1);
             }
             SIGTERM => {
                 println!("received SIGTERM, exiting");
+                reset_active_shield();
                 ::std::process::exit(0);
             }
             sig => panic!("we didn't register for it but we received {}", sig),
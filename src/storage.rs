@@ -6,7 +6,7 @@ use std::fmt::Debug;
 use std::fs::File;
 use std::io::{BufWriter, ErrorKind, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Command, Output, Stdio};
 
 use chrono::NaiveDateTime;
 use git2::Repository;
@@ -15,54 +15,167 @@ use serde::{de::DeserializeOwned, Serialize};
 use serde_json;
 use walkdir::WalkDir;
 
+use build_config::BuildConfig;
 use collector::CollectionResult;
 use cpu_shield::ShieldSpec;
+use host::HostFingerprint;
 use run_plan::RunPlan;
-use toolchain::Toolchain;
+use toolchain::{Toolchain, ToolchainMetadata};
+use CriterionConfig;
+
+/// A binary's hash, its estimates, and the fingerprint id of the host which measured it.
+pub type StoredEstimates = (Vec<u8>, Estimates, Option<String>);
+
+type StoredBuild = Container<index::Key, CollectionResult<BinaryHash>>;
+type StoredMeasurement = Container<measurement::Key, CollectionResult<Estimates>>;
 
 pub struct GitStore {
     path: PathBuf,
     repo: Repository,
+    /// The fingerprint id recorded with values stored from now on.
+    host: Option<String>,
 }
 
 impl GitStore {
+    /// Estimates for every benchmark, keyed by the benchmark and the name of the build
+    /// configuration it was built with (`None` for the default release profile). Each comes with
+    /// its binary's hash and the fingerprint id of the host which measured it, if known.
     pub fn all_stored_estimates(
         &self,
-    ) -> Result<BTreeMap<String, BTreeMap<Option<Toolchain>, (Vec<u8>, Estimates)>>> {
+    ) -> Result<BTreeMap<(String, Option<String>), BTreeMap<Option<Toolchain>, StoredEstimates>>>
+    {
         info!("finding all stored estimates in {}", self.path.display());
 
-        let measures = self.all_stored::<measurement::Key>()?;
-        let plans = self.all_stored::<index::Key>()?;
+        let mut all = BTreeMap::new();
+        for (series, builds) in self.measured_builds()? {
+            let estimates = builds
+                .into_iter()
+                .filter_map(|(toolchain, (built, measured))| {
+                    let binary_hash = built.contents.ok()?.normalized;
+                    let Container { contents, host, .. } = measured?;
+                    Some((toolchain, (binary_hash, contents.ok()?, host)))
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            if !estimates.is_empty() {
+                all.insert(series, estimates);
+            }
+        }
 
-        let measures_by_binhash: BTreeMap<Vec<u8>, Estimates> = measures
-            .into_iter()
-            .filter_map(|sc| {
-                let Container { key, contents, .. } = sc;
-                contents.ok().map(|estimates| (key.binary_hash, estimates))
-            })
-            .collect::<BTreeMap<_, _>>();
+        Ok(all)
+    }
 
-        let mut all: BTreeMap<String, BTreeMap<Option<Toolchain>, (Vec<u8>, Estimates)>> =
-            BTreeMap::new();
+    /// The artifacts stored with every benchmark's builds and with the measurements of their
+    /// binaries, keyed like `all_stored_estimates`. A binary's measurement artifacts come from the
+    /// same measurement as its estimates.
+    pub fn all_stored_artifacts(
+        &self,
+    ) -> Result<
+        BTreeMap<(String, Option<String>), BTreeMap<Option<Toolchain>, BTreeMap<String, PathBuf>>>,
+    > {
+        let mut all: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+
+        for (series, builds) in self.measured_builds()? {
+            for (toolchain, (built, measured)) in builds {
+                let mut found = built.artifacts;
+                if let Some(measured) = measured {
+                    found.extend(measured.artifacts);
+                }
 
-        for Container {
-            key,
-            contents: binary_hash,
-            ..
-        } in plans
-        {
-            if let Ok(binary_hash) = binary_hash {
-                if let Some(measure) = measures_by_binhash.get(&binary_hash) {
-                    all.entry(key.benchmark_key)
+                if !found.is_empty() {
+                    all.entry(series.clone())
                         .or_default()
-                        .insert(key.toolchain, (binary_hash.clone(), measure.clone()));
+                        .insert(toolchain, found);
+                }
+            }
+        }
+
+        Ok(all)
+    }
+
+    /// Every stored build, keyed like `all_stored_estimates`, along with the measurement of its
+    /// binary to show. A benchmark and build configuration's binaries are all shown as measured by
+    /// the same runner, shield and criterion config, whichever measured the most of them
+    /// successfully (and most recently, to break ties), so that its toolchains are compared like
+    /// for like.
+    fn measured_builds(
+        &self,
+    ) -> Result<
+        BTreeMap<
+            (String, Option<String>),
+            BTreeMap<Option<Toolchain>, (StoredBuild, Option<StoredMeasurement>)>,
+        >,
+    > {
+        let mut by_binary = BTreeMap::<_, BTreeMap<_, _>>::new();
+        for measured in self.all_stored::<measurement::Key>()? {
+            let setup = (
+                measured.key.runner.clone(),
+                measured.key.cpu_shield.clone(),
+                measured.key.criterion_config.clone(),
+            );
+            by_binary
+                .entry(measured.key.binary_hash.clone())
+                .or_default()
+                .insert(setup, measured);
+        }
+
+        let mut builds = BTreeMap::<_, BTreeMap<_, _>>::new();
+        for built in self.all_stored::<index::Key>()? {
+            let config_name = built.key.build_config.as_ref().map(|cfg| cfg.name.clone());
+            builds
+                .entry((built.key.benchmark_key.clone(), config_name))
+                .or_default()
+                .insert(built.key.toolchain.clone(), built);
+        }
+
+        let measurements_of = |built: &StoredBuild| match &built.contents {
+            Ok(hash) => by_binary.get(&hash.normalized),
+            Err(_) => None,
+        };
+
+        let mut all = BTreeMap::new();
+        for (series, by_toolchain) in builds {
+            let mut setups = BTreeMap::new();
+            for built in by_toolchain.values() {
+                for (setup, measured) in measurements_of(built).into_iter().flatten() {
+                    let (successes, latest) =
+                        setups.entry(setup).or_insert((0, measured.generated_at));
+                    if measured.contents.is_ok() {
+                        *successes += 1;
+                    }
+                    *latest = measured.generated_at.max(*latest);
                 }
             }
+
+            let shown = setups
+                .into_iter()
+                .max_by_key(|&(_, rank)| rank)
+                .map(|(setup, _)| setup);
+
+            let measured = by_toolchain
+                .into_iter()
+                .map(|(toolchain, built)| {
+                    let measurement = match (shown, measurements_of(&built)) {
+                        (Some(setup), Some(measurements)) => measurements.get(setup).cloned(),
+                        _ => None,
+                    };
+                    (toolchain, (built, measurement))
+                })
+                .collect();
+            all.insert(series, measured);
         }
 
         Ok(all)
     }
 
+    pub fn all_toolchain_metadata(&self) -> Result<BTreeMap<Toolchain, ToolchainMetadata>> {
+        Ok(self
+            .all_stored::<toolchains::Key>()?
+            .into_iter()
+            .map(|Container { key, contents, .. }| (key.toolchain, contents))
+            .collect())
+    }
+
     pub fn all_stored<K: StorageKey>(&self) -> Result<Vec<Container<K, K::Contents>>> {
         let mut found = Vec::new();
 
@@ -126,6 +239,7 @@ impl GitStore {
         Ok(Self {
             path: at.as_ref().to_owned(),
             repo,
+            host: None,
         })
     }
 
@@ -259,6 +373,33 @@ impl GitStore {
             .is_some())
     }
 
+    /// Returns the path to a file in the data directory which git will ignore, for state that
+    /// only matters to this machine and shouldn't be committed or stashed.
+    pub fn untracked_path(&self, name: &str) -> Result<PathBuf> {
+        let exclude_path = self.repo.path().join("info").join("exclude");
+        let pattern = format!("/{}", name);
+
+        let existing = match ::std::fs::read_to_string(&exclude_path) {
+            Ok(s) => s,
+            Err(ref why) if why.kind() == ErrorKind::NotFound => String::new(),
+            Err(why) => bail!("unable to read {}: {:?}", exclude_path.display(), why),
+        };
+
+        if !existing.lines().any(|l| l == pattern) {
+            ::std::fs::create_dir_all(exclude_path.parent().unwrap())?;
+            let mut exclude = ::std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&exclude_path)?;
+            if !existing.is_empty() && !existing.ends_with('\n') {
+                writeln!(exclude)?;
+            }
+            writeln!(exclude, "{}", pattern)?;
+        }
+
+        Ok(self.path.join(name))
+    }
+
     pub fn sync_down(&mut self) -> Result<()> {
         debug!("sync'ing down");
         self.stash()?;
@@ -299,16 +440,75 @@ impl GitStore {
         })
     }
 
+    /// Deletes a stored value, returning whether there was one to delete.
+    pub fn remove<K: StorageKey>(&mut self, key: &K) -> Result<bool> {
+        let own_path = key.abs_path(&self.path);
+
+        match ::std::fs::remove_file(&own_path) {
+            Ok(()) => Ok(true),
+            Err(ref why) if why.kind() == ErrorKind::NotFound => Ok(false),
+            Err(why) => bail!("unable to remove {}: {:?}", own_path.display(), why),
+        }
+    }
+
     pub fn set<K: StorageKey>(&mut self, key: &K, value: &K::Contents) -> Result<()> {
+        self.set_with_artifacts(key, value, &[])
+    }
+
+    /// Stores the fingerprint of the host taking measurements, and records it with every value
+    /// stored after this.
+    pub fn record_host(&mut self, fingerprint: &HostFingerprint) -> Result<()> {
+        let key = fingerprints::Key {
+            id: fingerprint.id()?,
+        };
+        self.set(&key, fingerprint)?;
+        self.host = Some(key.id);
+        Ok(())
+    }
+
+    /// Stores more artifacts with a value which is already stored, replacing any of the same name.
+    pub fn add_artifacts<K: StorageKey>(&mut self, key: &K, artifacts: &[Artifact]) -> Result<()> {
+        let own_path = key.abs_path(&self.path);
+        let mut container: Container<K, K::Contents> =
+            serde_json::from_str(&::std::fs::read_to_string(&own_path)?)?;
+
+        for artifact in artifacts {
+            let relative = key.artifact_path(&artifact.name);
+            ::std::fs::write(self.path.join(&relative), &artifact.contents)?;
+            container.artifacts.insert(artifact.name.clone(), relative);
+        }
+
+        let writer = BufWriter::new(File::create(&own_path)?);
+        serde_json::to_writer(writer, &container)?;
+        Ok(())
+    }
+
+    /// Stores a value along with artifacts which are written next to it and referenced from its
+    /// container.
+    pub fn set_with_artifacts<K: StorageKey>(
+        &mut self,
+        key: &K,
+        value: &K::Contents,
+        artifacts: &[Artifact],
+    ) -> Result<()> {
+        let own_path = key.abs_path(&self.path);
+        ::std::fs::create_dir_all(&own_path.parent().unwrap())?;
+
+        let mut stored = BTreeMap::new();
+        for artifact in artifacts {
+            let relative = key.artifact_path(&artifact.name);
+            ::std::fs::write(self.path.join(&relative), &artifact.contents)?;
+            stored.insert(artifact.name.clone(), relative);
+        }
+
         let to_write = Container {
             generated_at: ::chrono::Utc::now().naive_utc(),
             key: key.clone(),
             contents: value,
+            artifacts: stored,
+            host: self.host.clone(),
         };
 
-        let own_path = key.abs_path(&self.path);
-        ::std::fs::create_dir_all(&own_path.parent().unwrap())?;
-
         let file = File::create(&own_path)?;
         let mut writer = BufWriter::new(file);
         serde_json::to_writer(&mut writer, &to_write)?;
@@ -338,6 +538,11 @@ where
         path.push(format!("{}.json", self.basename()));
         path
     }
+
+    /// Where one of this key's artifacts is stored, relative to the data directory.
+    fn artifact_path(&self, name: &str) -> PathBuf {
+        Path::new(Self::DIRECTORY).join(format!("{}.{}.txt", self.basename(), name))
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -346,11 +551,50 @@ pub struct Container<K, V> {
     pub generated_at: NaiveDateTime,
     pub key: K,
     pub contents: V,
+    /// Paths of the artifacts stored with this value, by name, relative to the data directory.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub artifacts: BTreeMap<String, PathBuf>,
+    /// The id of the fingerprint of the host which stored this, if one was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+/// A file stored alongside a value, like the log of the build or run which produced it.
+#[derive(Clone, Debug)]
+pub struct Artifact {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+impl Artifact {
+    /// The stdout and stderr of a finished command, one after the other.
+    pub fn log(name: &str, output: &Output) -> Self {
+        Self::streams(name, &output.stdout, &output.stderr)
+    }
+
+    /// Whatever stdout and stderr a command wrote, e.g. before it was killed.
+    pub fn streams(name: &str, stdout: &[u8], stderr: &[u8]) -> Self {
+        let mut contents = b"==> stdout <==\n".to_vec();
+        contents.extend_from_slice(stdout);
+        contents.extend_from_slice(b"\n==> stderr <==\n");
+        contents.extend_from_slice(stderr);
+
+        Self {
+            name: name.to_string(),
+            contents,
+        }
+    }
+}
+
+/// Reads an artifact back, given its path in the data directory.
+pub fn read_artifact(data_dir: &Path, relative: &Path) -> Result<Vec<u8>> {
+    Ok(::std::fs::read(data_dir.join(relative))?)
 }
 
 #[derive(Clone)]
 pub enum Entry<K: StorageKey> {
-    New(K, K::Contents),
+    /// A value which hasn't been stored yet, along with any artifacts to store with it.
+    New(K, K::Contents, Vec<Artifact>),
     Existing(K::Contents),
 }
 
@@ -362,7 +606,7 @@ where
 
     fn deref(&self) -> &Self::Target {
         match self {
-            Entry::New(_, t) => t,
+            Entry::New(_, t, _) => t,
             Entry::Existing(t) => t,
         }
     }
@@ -374,12 +618,45 @@ where
 {
     pub fn ensure_persisted(self, store: &mut GitStore) -> Result<()> {
         Ok(match self {
-            Entry::New(k, t) => store.set(&k, &t)?,
+            Entry::New(k, t, artifacts) => store.set_with_artifacts(&k, &t, &artifacts)?,
             _ => (),
         })
     }
 }
 
+/// Identifies the binary built for a `RunPlan`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(from = "StoredBinaryHash")]
+pub struct BinaryHash {
+    /// SHA256 of only the binary's code, so that toolchains which generate identical code share
+    /// results even if they embed different version strings or build ids. Measurements are keyed
+    /// by this hash.
+    pub normalized: Vec<u8>,
+    /// SHA256 of the whole binary.
+    pub raw: Vec<u8>,
+}
+
+/// Index entries written before code was hashed separately only have the whole binary's hash,
+/// which measurements of those binaries are still keyed by.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredBinaryHash {
+    Split { normalized: Vec<u8>, raw: Vec<u8> },
+    Whole(Vec<u8>),
+}
+
+impl From<StoredBinaryHash> for BinaryHash {
+    fn from(stored: StoredBinaryHash) -> Self {
+        match stored {
+            StoredBinaryHash::Split { normalized, raw } => BinaryHash { normalized, raw },
+            StoredBinaryHash::Whole(hash) => BinaryHash {
+                normalized: hash.clone(),
+                raw: hash,
+            },
+        }
+    }
+}
+
 pub mod index {
     use super::*;
 
@@ -387,6 +664,9 @@ pub mod index {
     pub struct Key {
         pub benchmark_key: String,
         pub toolchain: Option<Toolchain>,
+        /// `None` for the workspace's release profile, which is all that older plans were built with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub build_config: Option<BuildConfig>,
     }
 
     impl Key {
@@ -394,24 +674,33 @@ pub mod index {
             Self {
                 benchmark_key: rp.benchmark.key(),
                 toolchain: rp.toolchain.clone(),
+                build_config: rp.build_config.clone(),
             }
         }
     }
 
     use slug::slugify;
     impl StorageKey for Key {
-        type Contents = CollectionResult<Vec<u8>>;
+        type Contents = CollectionResult<BinaryHash>;
         const DIRECTORY: &'static str = "run-plans";
 
         fn basename(&self) -> String {
-            slugify(format!(
+            let mut basename = format!(
                 "{}-{}",
                 self.benchmark_key,
                 self.toolchain
                     .as_ref()
                     .map(|t| t.to_string())
                     .unwrap_or_else(|| String::from("current-toolchain"))
-            ))
+            );
+
+            // the whole configuration is included so that redefining one under an existing name
+            // doesn't reuse binaries built with its old options
+            if let Some(cfg) = &self.build_config {
+                basename.push_str(&format!("-{}", cfg));
+            }
+
+            slugify(basename)
         }
     }
 }
@@ -419,11 +708,16 @@ pub mod index {
 pub mod measurement {
     use super::*;
 
-    #[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+    use std::hash::{Hash, Hasher};
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
     pub struct Key {
         pub binary_hash: Vec<u8>,
         pub runner: String,
         pub cpu_shield: Option<ShieldSpec>,
+        /// The criterion config the benchmark was run with, `None` for criterion's defaults.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub criterion_config: Option<CriterionConfig>,
     }
 
     impl Key {
@@ -431,12 +725,27 @@ pub mod measurement {
             hash: impl Into<Vec<u8>>,
             runner: Option<String>,
             cpu_shield: Option<ShieldSpec>,
+            criterion_config: Option<CriterionConfig>,
         ) -> Self {
             let runner = runner.unwrap_or_else(|| "anonymous".to_string());
             Self {
                 binary_hash: hash.into(),
                 runner,
                 cpu_shield,
+                criterion_config,
+            }
+        }
+    }
+
+    impl Hash for Key {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.binary_hash.hash(state);
+            self.runner.hash(state);
+            self.cpu_shield.hash(state);
+            // measurements with criterion's defaults keep the basenames they had before configs
+            // were part of the key
+            if let Some(config) = &self.criterion_config {
+                config.envs().hash(state);
             }
         }
     }
@@ -446,8 +755,6 @@ pub mod measurement {
         const DIRECTORY: &'static str = "measurements";
 
         fn basename(&self) -> String {
-            use std::hash::{Hash, Hasher};
-
             struct MyHasherWtf(::ring::digest::Context);
 
             impl Hasher for MyHasherWtf {
@@ -468,6 +775,161 @@ pub mod measurement {
     }
 }
 
+pub mod toolchains {
+    use super::*;
+
+    #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+    pub struct Key {
+        pub toolchain: Toolchain,
+    }
+
+    use slug::slugify;
+    impl StorageKey for Key {
+        type Contents = ToolchainMetadata;
+        const DIRECTORY: &'static str = "toolchains";
+
+        fn basename(&self) -> String {
+            slugify(self.toolchain.to_string())
+        }
+    }
+}
+
+pub mod fingerprints {
+    use super::*;
+
+    /// A host which has taken measurements, referred to by its id from what it stored.
+    #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+    pub struct Key {
+        pub id: String,
+    }
+
+    impl StorageKey for Key {
+        type Contents = HostFingerprint;
+        const DIRECTORY: &'static str = "fingerprints";
+
+        fn basename(&self) -> String {
+            self.id.clone()
+        }
+    }
+}
+
+pub mod unavailable {
+    use super::*;
+
+    /// A toolchain which couldn't be found the last time we tried to install it.
+    #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+    pub struct Key {
+        pub toolchain: Toolchain,
+    }
+
+    #[derive(Debug, Clone, Deserialize, PartialEq, Serialize)]
+    pub struct Record {
+        /// UTC
+        pub first_checked: NaiveDateTime,
+        /// UTC
+        pub last_checked: NaiveDateTime,
+        pub attempts: u32,
+    }
+
+    impl Record {
+        pub fn new(now: NaiveDateTime) -> Self {
+            Self {
+                first_checked: now,
+                last_checked: now,
+                attempts: 1,
+            }
+        }
+
+        pub fn checked_again(self, now: NaiveDateTime) -> Self {
+            Self {
+                last_checked: now,
+                attempts: self.attempts + 1,
+                ..self
+            }
+        }
+    }
+
+    use slug::slugify;
+    impl StorageKey for Key {
+        type Contents = Record;
+        const DIRECTORY: &'static str = "unavailable-toolchains";
+
+        fn basename(&self) -> String {
+            slugify(self.toolchain.to_string())
+        }
+    }
+}
+
+pub mod process_runs {
+    use super::*;
+
+    /// The individual process runs behind a measurement which was taken with more than one.
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    pub struct Key {
+        pub measurement: measurement::Key,
+    }
+
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    pub struct Runs {
+        /// Each run's estimates, in the order they ran.
+        pub estimates: Vec<Estimates>,
+        /// The standard deviation of the runs' median point estimates, by metric.
+        pub between_runs_std_dev: BTreeMap<String, f64>,
+    }
+
+    impl Runs {
+        pub fn new(estimates: Vec<Estimates>) -> Self {
+            let between_runs_std_dev = combine_estimates(&estimates)
+                .into_iter()
+                .map(|(metric, combined)| {
+                    let medians = estimates
+                        .iter()
+                        .map(|e| e[&metric].median)
+                        .collect::<Vec<_>>();
+                    (
+                        metric,
+                        Estimate::between_variance(&medians, combined.median.point_estimate).sqrt(),
+                    )
+                })
+                .collect();
+
+            Self {
+                estimates,
+                between_runs_std_dev,
+            }
+        }
+    }
+
+    impl StorageKey for Key {
+        type Contents = Runs;
+        const DIRECTORY: &'static str = "process-runs";
+
+        fn basename(&self) -> String {
+            self.measurement.basename()
+        }
+    }
+}
+
+/// Combines the estimates of independent process runs of the same binary, for the metrics which
+/// every run has.
+pub fn combine_estimates(runs: &[Estimates]) -> Estimates {
+    let first = match runs.first() {
+        Some(first) => first,
+        None => return Estimates::new(),
+    };
+
+    first
+        .keys()
+        .filter_map(|metric| {
+            let statistics = runs
+                .iter()
+                .map(|run| run.get(metric).cloned())
+                .collect::<Option<Vec<_>>>()?;
+            Some((metric.clone(), Statistic::combine(&statistics)))
+        })
+        .collect()
+}
+
 // the below is adapted from criterion
 
 pub type Estimates = BTreeMap<String, Statistic>;
@@ -487,6 +949,44 @@ pub struct Statistic {
     pub std_dev: Estimate,
 }
 
+impl Statistic {
+    /// A statistic for a single, exact observation, like the size of a binary.
+    pub fn exact(value: f64) -> Self {
+        let estimate = Estimate {
+            confidence_interval: ConfidenceInterval {
+                confidence_level: 1.0,
+                lower_bound: value,
+                upper_bound: value,
+            },
+            point_estimate: value,
+            standard_error: 0.0,
+        };
+
+        Self {
+            mean: estimate,
+            median: estimate,
+            median_abs_dev: Estimate::zero(),
+            slope: estimate,
+            std_dev: Estimate::zero(),
+        }
+    }
+
+    /// Combines the statistics of independent process runs. See `Estimate::combine`.
+    pub fn combine(runs: &[Statistic]) -> Self {
+        let combine = |get: fn(&Statistic) -> Estimate| {
+            Estimate::combine(&runs.iter().map(get).collect::<Vec<_>>())
+        };
+
+        Self {
+            mean: combine(|s| s.mean),
+            median: combine(|s| s.median),
+            median_abs_dev: combine(|s| s.median_abs_dev),
+            slope: combine(|s| s.slope),
+            std_dev: combine(|s| s.std_dev),
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, PartialOrd, Deserialize, Serialize, Debug)]
 pub struct ConfidenceInterval {
     pub confidence_level: f64,
@@ -504,6 +1004,62 @@ pub struct Estimate {
     pub standard_error: f64,
 }
 
+impl Estimate {
+    fn zero() -> Self {
+        Self {
+            confidence_interval: ConfidenceInterval {
+                confidence_level: 1.0,
+                lower_bound: 0.0,
+                upper_bound: 0.0,
+            },
+            point_estimate: 0.0,
+            standard_error: 0.0,
+        }
+    }
+
+    /// Averages the point estimates of independent runs. The standard error accounts for whichever
+    /// is larger of the runs' own errors and the variance between their point estimates, and the
+    /// confidence interval covers every run's interval. A single run is returned unchanged.
+    fn combine(runs: &[Estimate]) -> Self {
+        let n = runs.len() as f64;
+        let point_estimate = runs.iter().map(|e| e.point_estimate).sum::<f64>() / n;
+
+        let within = runs.iter().map(|e| e.standard_error.powi(2)).sum::<f64>() / n;
+        let between = Self::between_variance(runs, point_estimate);
+
+        let lower_bound = runs
+            .iter()
+            .map(|e| e.confidence_interval.lower_bound)
+            .fold(::std::f64::INFINITY, f64::min);
+        let upper_bound = runs
+            .iter()
+            .map(|e| e.confidence_interval.upper_bound)
+            .fold(::std::f64::NEG_INFINITY, f64::max);
+
+        Self {
+            confidence_interval: ConfidenceInterval {
+                confidence_level: runs[0].confidence_interval.confidence_level,
+                lower_bound,
+                upper_bound,
+            },
+            point_estimate,
+            standard_error: (within.max(between) / n).sqrt(),
+        }
+    }
+
+    /// The sample variance of the point estimates of independent runs, zero for a single run.
+    fn between_variance(runs: &[Estimate], mean: f64) -> f64 {
+        if runs.len() < 2 {
+            return 0.0;
+        }
+
+        runs.iter()
+            .map(|e| (e.point_estimate - mean).powi(2))
+            .sum::<f64>()
+            / (runs.len() - 1) as f64
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -551,4 +1107,177 @@ mod tests {
         }
     }
 
+    #[test]
+    fn binary_hashes_from_before_normalization() {
+        let legacy: BinaryHash = serde_json::from_str("[1, 2, 3]").unwrap();
+        assert_eq!(legacy.normalized, vec![1, 2, 3]);
+        assert_eq!(legacy.raw, vec![1, 2, 3]);
+
+        let current = BinaryHash {
+            normalized: vec![4, 5],
+            raw: vec![6, 7],
+        };
+        let roundtripped: BinaryHash =
+            serde_json::from_str(&serde_json::to_string(&current).unwrap()).unwrap();
+        assert_eq!(roundtripped, current);
+    }
+
+    #[test]
+    fn artifacts_are_stored_next_to_their_value() {
+        let tempdir = tempdir().unwrap();
+        let data_dir = tempdir.path();
+        let mut storage = GitStore::ensure_initialized(data_dir).unwrap();
+
+        let key = TestKey { a: 1, b: 2, c: 3 };
+        let log = Artifact {
+            name: String::from("build-log"),
+            contents: b"warning: unused variable".to_vec(),
+        };
+        storage
+            .set_with_artifacts(&key, &vec![String::from("value")], &[log.clone()])
+            .unwrap();
+
+        let stored = storage.all_stored::<TestKey>().unwrap();
+        assert_eq!(stored.len(), 1);
+
+        let path = &stored[0].artifacts["build-log"];
+        assert_eq!(path, &Path::new("fivef").join("3.build-log.txt"));
+        assert_eq!(read_artifact(data_dir, path).unwrap(), log.contents);
+    }
+
+    #[test]
+    fn artifacts_come_from_the_displayed_measurement() {
+        let tempdir = tempdir().unwrap();
+        let data_dir = tempdir.path();
+        let mut storage = GitStore::ensure_initialized(data_dir).unwrap();
+
+        let toolchain = Toolchain::from("nightly-2018-01-01");
+        let plan = index::Key {
+            benchmark_key: String::from("crate::bench"),
+            toolchain: Some(toolchain.clone()),
+            build_config: None,
+        };
+        let hash = BinaryHash {
+            normalized: vec![1],
+            raw: vec![2],
+        };
+        storage.set(&plan, &Ok(hash)).unwrap();
+
+        let log = |runner: &str| Artifact {
+            name: String::from("run-log"),
+            contents: format!("run by {}", runner).into_bytes(),
+        };
+        let measured_by =
+            |runner: &str| measurement::Key::new(vec![1], Some(runner.to_string()), None, None);
+        let estimates = vec![(String::from("nanoseconds"), Statistic::exact(1.0))]
+            .into_iter()
+            .collect::<Estimates>();
+        let failed = serde_json::from_str(r#"{"kind":{"Run":"crashed"}}"#).unwrap();
+
+        // a later failure on another runner doesn't replace the results
+        storage
+            .set_with_artifacts(&measured_by("a"), &Ok(estimates), &[log("a")])
+            .unwrap();
+        storage
+            .set_with_artifacts(&measured_by("b"), &Err(failed), &[log("b")])
+            .unwrap();
+
+        let estimates = storage.all_stored_estimates().unwrap();
+        let key = (String::from("crate::bench"), None);
+        assert!(estimates[&key].contains_key(&Some(toolchain.clone())));
+
+        let artifacts = storage.all_stored_artifacts().unwrap();
+        let path = &artifacts[&key][&Some(toolchain)]["run-log"];
+        assert_eq!(read_artifact(data_dir, path).unwrap(), log("a").contents);
+    }
+
+    #[test]
+    fn a_benchmarks_estimates_share_a_criterion_config() {
+        let tempdir = tempdir().unwrap();
+        let mut storage = GitStore::ensure_initialized(tempdir.path()).unwrap();
+
+        let toolchains = vec![
+            Toolchain::from("nightly-2018-01-01"),
+            Toolchain::from("nightly-2018-01-02"),
+        ];
+        for (hash, toolchain) in toolchains.iter().enumerate() {
+            let plan = index::Key {
+                benchmark_key: String::from("crate::bench"),
+                toolchain: Some(toolchain.clone()),
+                build_config: None,
+            };
+            let hash = BinaryHash {
+                normalized: vec![hash as u8],
+                raw: vec![hash as u8],
+            };
+            storage.set(&plan, &Ok(hash)).unwrap();
+        }
+
+        let measured = |hash: u8, config: Option<CriterionConfig>, point: f64| {
+            let key = measurement::Key::new(vec![hash], None, None, config);
+            let estimates = vec![(String::from("nanoseconds"), Statistic::exact(point))]
+                .into_iter()
+                .collect::<Estimates>();
+            (key, estimates)
+        };
+        let mut quick = ::criterion_defaults();
+        quick.sample_size = 10;
+
+        // the defaults measured both binaries, a quicker config only measured the second later on
+        for (key, estimates) in vec![
+            measured(0, None, 1.0),
+            measured(1, None, 2.0),
+            measured(1, Some(quick), 20.0),
+        ] {
+            storage.set(&key, &Ok(estimates)).unwrap();
+        }
+
+        let estimates = storage.all_stored_estimates().unwrap();
+        let by_toolchain = &estimates[&(String::from("crate::bench"), None)];
+        let points = toolchains
+            .into_iter()
+            .map(|t| {
+                by_toolchain[&Some(t)].1["nanoseconds"]
+                    .median
+                    .point_estimate
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(points, vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn combining_process_runs() {
+        let run = |point: f64, error: f64| {
+            let mut statistic = Statistic::exact(point);
+            statistic.median.standard_error = error;
+            statistic.median.confidence_interval.lower_bound = point - 2.0 * error;
+            statistic.median.confidence_interval.upper_bound = point + 2.0 * error;
+            statistic
+        };
+
+        let single = Statistic::combine(&[run(10.0, 1.0)]);
+        assert_eq!(single.median, run(10.0, 1.0).median);
+
+        let combined = Statistic::combine(&[run(10.0, 0.1), run(12.0, 0.1), run(14.0, 0.1)]);
+        assert_eq!(combined.median.point_estimate, 12.0);
+        assert_eq!(combined.median.confidence_interval.lower_bound, 9.8);
+        assert_eq!(combined.median.confidence_interval.upper_bound, 14.2);
+        // the runs disagree by much more than their own errors
+        assert_eq!(combined.median.standard_error, (4.0f64 / 3.0).sqrt());
+
+        let runs = vec![
+            vec![(String::from("nanoseconds"), run(10.0, 0.1))]
+                .into_iter()
+                .collect::<Estimates>(),
+            vec![
+                (String::from("nanoseconds"), run(14.0, 0.1)),
+                (String::from("instructions"), run(5.0, 0.1)),
+            ]
+            .into_iter()
+            .collect(),
+        ];
+        let summary = process_runs::Runs::new(runs);
+        assert_eq!(summary.between_runs_std_dev.len(), 1);
+        assert_eq!(summary.between_runs_std_dev["nanoseconds"], 8.0f64.sqrt());
+    }
 }
@@ -1,10 +1,14 @@
 use super::Result;
 
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::path::PathBuf;
 use std::process::Command;
+use std::str::FromStr;
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+use chrono::NaiveDate;
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Toolchain {
     spec: String,
 }
@@ -16,31 +20,297 @@ impl Toolchain {
         }
     }
 
+    /// The release channel this toolchain was published on, inferred from its name.
+    pub fn channel(&self) -> Channel {
+        if self.spec.starts_with("nightly") {
+            Channel::Nightly
+        } else if self.spec.starts_with("beta") {
+            Channel::Beta
+        } else if self.spec.starts_with("stable") || self.version().is_some() {
+            Channel::Stable
+        } else {
+            Channel::Other
+        }
+    }
+
+    /// The release version for toolchains named like `1.20.0`.
+    pub fn version(&self) -> Option<RustVersion> {
+        self.spec.parse().ok()
+    }
+
+    /// The publication date for toolchains named like `nightly-2018-10-01` or `beta-2018-10-01`.
+    pub fn date(&self) -> Option<NaiveDate> {
+        let date = if self.spec.starts_with("nightly-") {
+            &self.spec["nightly-".len()..]
+        } else if self.spec.starts_with("beta-") {
+            &self.spec["beta-".len()..]
+        } else {
+            return None;
+        };
+
+        date.get(..10)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+    }
+
+    /// Asks the toolchain's rustc to describe itself. Assumes the toolchain is installed.
+    pub fn metadata(&self, provider: &dyn ToolchainProvider) -> Result<ToolchainMetadata> {
+        let output = provider
+            .cargo_toolchain(self)
+            .rustc_command()
+            .arg("-vV")
+            .output()?;
+
+        if !output.status.success() {
+            bail!(
+                "unable to get rustc version info for {}: {}",
+                self,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        ToolchainMetadata::parse(&String::from_utf8_lossy(&output.stdout))
+    }
+
     pub fn target_dir(&self) -> PathBuf {
         PathBuf::from(format!("/tmp/target-{}", self.spec))
     }
 
-    fn is_installed(&self) -> Result<bool> {
+    /// Installs the toolchain if needed, returning whether it had to be installed.
+    pub fn ensure_installed(&self, provider: &dyn ToolchainProvider) -> Result<bool> {
+        if provider.is_installed(self)? {
+            info!("{} already installed, skipping installation", self);
+            return Ok(false);
+        }
+
+        info!("Installing {}...", self);
+        provider.install(self)?;
+
+        Ok(true)
+    }
+}
+
+impl Display for Toolchain {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_fmt(format_args!("{}", self.spec))
+    }
+}
+
+// stable releases need to sort by version rather than lexically, otherwise 1.9.0 comes after 1.10.0
+impl Ord for Toolchain {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.channel(), self.version(), &self.spec).cmp(&(
+            other.channel(),
+            other.version(),
+            &other.spec,
+        ))
+    }
+}
+
+impl PartialOrd for Toolchain {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The compiler behind a toolchain, so that changes in results can be traced back to a range of
+/// rustc commits or an LLVM upgrade.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct ToolchainMetadata {
+    /// e.g. `rustc 1.36.0 (a53f9df32 2019-07-03)`
+    pub version: String,
+    pub commit_hash: Option<String>,
+    pub commit_date: Option<NaiveDate>,
+    pub host: String,
+    pub release: String,
+    pub llvm_version: Option<String>,
+}
+
+impl ToolchainMetadata {
+    /// Parses the output of `rustc -vV`.
+    pub fn parse(verbose_version: &str) -> Result<Self> {
+        let mut lines = verbose_version.lines();
+        let version = match lines.next() {
+            Some(v) if v.starts_with("rustc ") => v.trim().to_string(),
+            _ => bail!("unrecognized rustc version output: {}", verbose_version),
+        };
+
+        let mut fields = ::std::collections::BTreeMap::new();
+        for line in lines {
+            if let Some(idx) = line.find(':') {
+                let (name, value) = line.split_at(idx);
+                fields.insert(name.trim(), value[1..].trim());
+            }
+        }
+
+        // locally built compilers report "unknown" for the commit info
+        let known = |name: &str| {
+            fields
+                .get(name)
+                .filter(|&&v| v != "unknown")
+                .map(|v| v.to_string())
+        };
+
+        Ok(Self {
+            version,
+            commit_hash: known("commit-hash"),
+            commit_date: known("commit-date")
+                .and_then(|d| NaiveDate::parse_from_str(&d, "%Y-%m-%d").ok()),
+            host: known("host").unwrap_or_default(),
+            release: known("release").unwrap_or_default(),
+            llvm_version: known("LLVM version"),
+        })
+    }
+}
+
+/// Release channels are never compared against one another, each gets its own series of results.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+pub enum Channel {
+    Stable,
+    Beta,
+    Nightly,
+    /// Anything we can't identify, like a custom toolchain name.
+    Other,
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+            Channel::Nightly => "nightly",
+            Channel::Other => "other",
+        })
+    }
+}
+
+/// A numbered Rust release, e.g. `1.20.0`.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Ord, Serialize)]
+pub struct RustVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl RustVersion {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// The latest stable release which would have been available on the given date, computed from
+    /// the six week release train which has been running since 1.0.0 shipped on 2015-05-15.
+    pub fn latest_stable_as_of(date: NaiveDate) -> Self {
+        // 1.0.0 was released on a friday, every release since has been on a thursday
+        let days_since_first = (date - NaiveDate::from_ymd(2015, 5, 15)).num_days() + 1;
+        Self::new(1, ::std::cmp::max(days_since_first, 0) as u32 / 42, 0)
+    }
+
+    pub fn next_minor(&self) -> Self {
+        Self::new(self.major, self.minor + 1, 0)
+    }
+}
+
+impl FromStr for RustVersion {
+    type Err = ::failure::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split('.')
+            .map(u32::from_str)
+            .collect::<::std::result::Result<Vec<_>, _>>()?;
+
+        Ok(match parts.as_slice() {
+            [major, minor] => Self::new(*major, *minor, 0),
+            [major, minor, patch] => Self::new(*major, *minor, *patch),
+            _ => bail!("{} is not a valid release version, expected e.g. 1.20.0", s),
+        })
+    }
+}
+
+impl Display for RustVersion {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_fmt(format_args!("{}.{}.{}", self.major, self.minor, self.patch))
+    }
+}
+
+/// Makes toolchains available to cargo. Everything which builds or runs benchmarks goes through one
+/// of these rather than assuming that rustup manages all of our compilers.
+pub trait ToolchainProvider: Debug + Send + Sync {
+    fn is_installed(&self, toolchain: &Toolchain) -> Result<bool>;
+    fn install(&self, toolchain: &Toolchain) -> Result<()>;
+    fn uninstall(&self, toolchain: &Toolchain) -> Result<()>;
+
+    /// How cargo and rustc should be told to use the given toolchain.
+    fn cargo_toolchain(&self, toolchain: &Toolchain) -> CargoToolchain;
+}
+
+/// Returned by a provider when a toolchain doesn't exist at all, as opposed to failing to install.
+#[derive(Debug, Fail)]
+#[fail(display = "No release found for {}.", toolchain)]
+pub struct Unavailable {
+    pub toolchain: Toolchain,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CargoToolchain {
+    /// `cargo +<name>`, for toolchains which rustup knows about.
+    Rustup(String),
+    /// `RUSTC=<path> cargo`, for compilers which rustup doesn't know about.
+    Rustc(PathBuf),
+}
+
+impl CargoToolchain {
+    /// Arguments to pass to cargo before its subcommand.
+    pub fn args(&self) -> Vec<String> {
+        match self {
+            CargoToolchain::Rustup(name) => vec![format!("+{}", name)],
+            CargoToolchain::Rustc(_) => vec![],
+        }
+    }
+
+    /// Environment variables to set for cargo.
+    pub fn envs(&self) -> Vec<(&'static str, PathBuf)> {
+        match self {
+            CargoToolchain::Rustup(_) => vec![],
+            CargoToolchain::Rustc(rustc) => vec![("RUSTC", rustc.clone())],
+        }
+    }
+
+    pub fn rustc_command(&self) -> Command {
+        match self {
+            CargoToolchain::Rustup(name) => {
+                let mut cmd = Command::new("rustc");
+                cmd.arg(format!("+{}", name));
+                cmd
+            }
+            CargoToolchain::Rustc(rustc) => Command::new(rustc),
+        }
+    }
+}
+
+/// Installs published toolchains with rustup.
+#[derive(Clone, Debug, Default)]
+pub struct Rustup;
+
+impl ToolchainProvider for Rustup {
+    fn is_installed(&self, toolchain: &Toolchain) -> Result<bool> {
         let installed_toolchains_output = Command::new("rustup")
             .arg("toolchain")
             .arg("list")
             .output()?;
 
         let stdout = String::from_utf8_lossy(&installed_toolchains_output.stdout);
-        Ok(stdout.contains(&self.spec))
+        Ok(stdout.contains(&toolchain.spec))
     }
 
-    pub fn ensure_installed(&self) -> Result<Option<InstallGuard>> {
-        if self.is_installed()? {
-            info!("{} already installed, skipping installation", self);
-            return Ok(None);
-        }
-
-        info!("Installing {}...", self);
+    fn install(&self, toolchain: &Toolchain) -> Result<()> {
         let install_output = Command::new("rustup")
             .arg("toolchain")
             .arg("install")
-            .arg(&self.spec)
+            .arg(&toolchain.spec)
             .output()?;
 
         if !install_output.status.success() {
@@ -51,48 +321,194 @@ impl Toolchain {
                 // so something is probably wrong (disk space, perms, etc)
                 bail!(
                 "rustup failed to install {}, but it wasn't because the release was missing: {}",
-                self.spec,
+                toolchain.spec,
                 stderr
             );
             }
 
-            bail!("No release found for {}.", self.spec);
+            return Err(Unavailable {
+                toolchain: toolchain.clone(),
+            }
+            .into());
         }
 
-        Ok(Some(InstallGuard(self.clone())))
+        Ok(())
     }
 
-    pub fn uninstall(&self) -> Result<()> {
-        info!("Uninstalling {}...", self);
+    fn uninstall(&self, toolchain: &Toolchain) -> Result<()> {
+        info!("Uninstalling {}...", toolchain);
         Command::new("rustup")
             .arg("toolchain")
             .arg("uninstall")
-            .arg(&self.spec)
+            .arg(&toolchain.spec)
             .status()?;
         Ok(())
     }
+
+    fn cargo_toolchain(&self, toolchain: &Toolchain) -> CargoToolchain {
+        CargoToolchain::Rustup(toolchain.spec.clone())
+    }
 }
 
-impl Display for Toolchain {
-    fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        f.write_fmt(format_args!("{}", self.spec))
+/// Resolves toolchains from a directory of unpacked or locally built sysroots, like a rustc
+/// checkout's `build/<host>/stage2`. Each toolchain's name is the name of its sysroot directory.
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct LocalToolchains {
+    pub root: PathBuf,
+    /// Register sysroots with `rustup toolchain link` instead of pointing cargo at their rustc.
+    pub link: bool,
+}
+
+impl LocalToolchains {
+    fn sysroot(&self, toolchain: &Toolchain) -> PathBuf {
+        self.root.join(&toolchain.spec)
+    }
+
+    fn rustc(&self, toolchain: &Toolchain) -> PathBuf {
+        self.sysroot(toolchain).join("bin").join("rustc")
     }
 }
 
-pub struct InstallGuard(Toolchain);
+impl ToolchainProvider for LocalToolchains {
+    fn is_installed(&self, toolchain: &Toolchain) -> Result<bool> {
+        if !self.rustc(toolchain).is_file() {
+            return Ok(false);
+        }
 
-impl Drop for InstallGuard {
-    fn drop(&mut self) {
-        if let Err(e) = ::std::fs::remove_dir_all(self.0.target_dir()) {
-            warn!(
-                "unable to clean up {}'s target directory ({}): {:?}",
-                self.0,
-                self.0.target_dir().display(),
-                e
-            );
+        if self.link {
+            Rustup.is_installed(toolchain)
+        } else {
+            Ok(true)
+        }
+    }
+
+    fn install(&self, toolchain: &Toolchain) -> Result<()> {
+        if !self.rustc(toolchain).is_file() {
+            return Err(Unavailable {
+                toolchain: toolchain.clone(),
+            }
+            .into());
+        }
+
+        if self.link {
+            let output = Command::new("rustup")
+                .arg("toolchain")
+                .arg("link")
+                .arg(&toolchain.spec)
+                .arg(self.sysroot(toolchain))
+                .output()?;
+
+            if !output.status.success() {
+                bail!(
+                    "rustup failed to link {}: {}",
+                    toolchain,
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            }
         }
-        if let Err(e) = self.0.uninstall() {
-            warn!("unable to uninstall {}: {:?}", self.0, e);
+
+        Ok(())
+    }
+
+    /// Only ever removes the rustup link, sysroots are left untouched.
+    fn uninstall(&self, toolchain: &Toolchain) -> Result<()> {
+        if self.link {
+            Rustup.uninstall(toolchain)
+        } else {
+            Ok(())
         }
     }
+
+    fn cargo_toolchain(&self, toolchain: &Toolchain) -> CargoToolchain {
+        if self.link {
+            CargoToolchain::Rustup(toolchain.spec.clone())
+        } else {
+            CargoToolchain::Rustc(self.rustc(toolchain))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn local_toolchains_resolve_sysroots() {
+        let root = tempdir().unwrap();
+        let provider = LocalToolchains {
+            root: root.path().to_owned(),
+            link: false,
+        };
+        let stage2 = Toolchain::from("stage2");
+
+        assert!(!provider.is_installed(&stage2).unwrap());
+        assert!(provider
+            .install(&stage2)
+            .unwrap_err()
+            .downcast_ref::<Unavailable>()
+            .is_some());
+
+        let bin_dir = root.path().join("stage2").join("bin");
+        ::std::fs::create_dir_all(&bin_dir).unwrap();
+        ::std::fs::write(bin_dir.join("rustc"), b"").unwrap();
+
+        assert!(provider.is_installed(&stage2).unwrap());
+        provider.install(&stage2).unwrap();
+        assert_eq!(
+            provider.cargo_toolchain(&stage2),
+            CargoToolchain::Rustc(bin_dir.join("rustc"))
+        );
+    }
+
+    #[test]
+    fn parse_rustc_verbose_version() {
+        let metadata = ToolchainMetadata::parse(
+            "rustc 1.36.0 (a53f9df32 2019-07-03)
+binary: rustc
+commit-hash: a53f9df32fbb0b5f4382caaad8f1a46f36ea887c
+commit-date: 2019-07-03
+host: x86_64-unknown-linux-gnu
+release: 1.36.0
+LLVM version: 8.0
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            metadata,
+            ToolchainMetadata {
+                version: String::from("rustc 1.36.0 (a53f9df32 2019-07-03)"),
+                commit_hash: Some(String::from("a53f9df32fbb0b5f4382caaad8f1a46f36ea887c")),
+                commit_date: Some(NaiveDate::from_ymd(2019, 7, 3)),
+                host: String::from("x86_64-unknown-linux-gnu"),
+                release: String::from("1.36.0"),
+                llvm_version: Some(String::from("8.0")),
+            }
+        );
+
+        let local = ToolchainMetadata::parse(
+            "rustc 1.38.0-dev
+binary: rustc
+commit-hash: unknown
+commit-date: unknown
+host: x86_64-unknown-linux-gnu
+release: 1.38.0-dev
+LLVM version: 9.0
+",
+        )
+        .unwrap();
+        assert_eq!(local.commit_hash, None);
+        assert_eq!(local.commit_date, None);
+    }
+
+    #[test]
+    fn latest_stable_follows_release_train() {
+        let latest = |y, m, d| RustVersion::latest_stable_as_of(NaiveDate::from_ymd(y, m, d));
+
+        assert_eq!(latest(2015, 5, 15), RustVersion::new(1, 0, 0));
+        assert_eq!(latest(2015, 6, 25), RustVersion::new(1, 1, 0));
+        assert_eq!(latest(2019, 7, 3), RustVersion::new(1, 35, 0));
+        assert_eq!(latest(2019, 7, 4), RustVersion::new(1, 36, 0));
+    }
 }
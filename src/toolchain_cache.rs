@@ -0,0 +1,295 @@
+//! Keeps recently used toolchains and their target directories around between runs, so that
+//! re-running a benchmark with a recent toolchain doesn't reinstall it and rebuild all of its
+//! dependencies from scratch.
+
+use super::Result;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{NaiveDateTime, Utc};
+use serde_json;
+use walkdir::WalkDir;
+
+use storage::GitStore;
+use toolchain::{Toolchain, ToolchainProvider};
+
+/// How much the cache is allowed to keep around once a toolchain is done running.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub struct CacheLimits {
+    /// The most toolchains to keep installed. Zero uninstalls every toolchain after it's used.
+    pub max_toolchains: usize,
+    /// The most disk space to spend on target directories, in bytes.
+    pub max_target_bytes: u64,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self {
+            max_toolchains: 3,
+            max_target_bytes: 30 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CachedToolchain {
+    toolchain: Toolchain,
+    /// UTC
+    last_used: NaiveDateTime,
+    /// Toolchains which were already installed when we first used them are never uninstalled.
+    installed_by_us: bool,
+}
+
+/// Least-recently-used cache of installed toolchains and their target directories. The cache's
+/// state lives in the data directory but is never committed, since it only describes this runner.
+pub struct ToolchainCache {
+    path: PathBuf,
+    limits: CacheLimits,
+    entries: Vec<CachedToolchain>,
+}
+
+impl ToolchainCache {
+    const FILE_NAME: &'static str = "toolchain-cache.json";
+
+    pub fn open(storage: &GitStore, limits: CacheLimits) -> Result<Self> {
+        let path = storage.untracked_path(Self::FILE_NAME)?;
+
+        let entries = match ::std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(ref why) if why.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(why) => return Err(why.into()),
+        };
+
+        Ok(Self {
+            path,
+            limits,
+            entries,
+        })
+    }
+
+    pub fn set_limits(&mut self, limits: CacheLimits) {
+        self.limits = limits;
+    }
+
+    /// Installs the toolchain if it isn't already and marks it as the most recently used.
+    pub fn checkout(
+        &mut self,
+        toolchain: &Toolchain,
+        provider: &dyn ToolchainProvider,
+    ) -> Result<()> {
+        let installed_by_us = toolchain.ensure_installed(provider)?;
+        let now = Utc::now().naive_utc();
+
+        match self.entries.iter_mut().find(|e| &e.toolchain == toolchain) {
+            Some(entry) => {
+                entry.last_used = now;
+                entry.installed_by_us |= installed_by_us;
+            }
+            None => self.entries.push(CachedToolchain {
+                toolchain: toolchain.clone(),
+                last_used: now,
+                installed_by_us,
+            }),
+        }
+
+        self.save()
+    }
+
+    /// Evicts the least recently used toolchains until the cache is back within its limits. The
+    /// toolchain in use is only evicted if the cache isn't allowed to keep any toolchains at all.
+    pub fn evict(&mut self, provider: &dyn ToolchainProvider, in_use: &Toolchain) -> Result<()> {
+        let sizes = self
+            .entries
+            .iter()
+            .map(|e| (e.toolchain.clone(), dir_bytes(&e.toolchain.target_dir())))
+            .collect::<BTreeMap<_, _>>();
+
+        for evicted in self.select_evictions(in_use, |t| sizes[t]) {
+            info!("evicting {} from the toolchain cache", evicted.toolchain);
+
+            let target_dir = evicted.toolchain.target_dir();
+            if target_dir.exists() {
+                if let Err(e) = ::std::fs::remove_dir_all(&target_dir) {
+                    warn!(
+                        "unable to clean up {}'s target directory ({}): {:?}",
+                        evicted.toolchain,
+                        target_dir.display(),
+                        e
+                    );
+                }
+            }
+
+            if evicted.installed_by_us {
+                if let Err(e) = provider.uninstall(&evicted.toolchain) {
+                    warn!("unable to uninstall {}: {:?}", evicted.toolchain, e);
+                }
+            }
+        }
+
+        self.save()
+    }
+
+    /// Removes the entries which need to be evicted, least recently used first, and returns them.
+    fn select_evictions<F>(&mut self, in_use: &Toolchain, target_bytes: F) -> Vec<CachedToolchain>
+    where
+        F: Fn(&Toolchain) -> u64,
+    {
+        self.entries.sort_by_key(|e| e.last_used);
+
+        let keep_in_use = self.limits.max_toolchains > 0;
+        let mut total_bytes: u64 = self
+            .entries
+            .iter()
+            .map(|e| target_bytes(&e.toolchain))
+            .sum();
+        let mut evicted = Vec::new();
+        let mut next = 0;
+
+        while next < self.entries.len()
+            && (self.entries.len() > self.limits.max_toolchains
+                || total_bytes > self.limits.max_target_bytes)
+        {
+            if keep_in_use && &self.entries[next].toolchain == in_use {
+                next += 1;
+                continue;
+            }
+
+            let entry = self.entries.remove(next);
+            total_bytes -= target_bytes(&entry.toolchain);
+            evicted.push(entry);
+        }
+
+        evicted
+    }
+
+    fn save(&self) -> Result<()> {
+        ::std::fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?)?;
+        Ok(())
+    }
+}
+
+fn dir_bytes(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use chrono::Duration;
+    use tempfile::tempdir;
+
+    use toolchain::CargoToolchain;
+
+    #[derive(Debug, Default)]
+    struct Uninstalls(Mutex<Vec<Toolchain>>);
+
+    impl ToolchainProvider for Uninstalls {
+        fn is_installed(&self, _: &Toolchain) -> Result<bool> {
+            Ok(true)
+        }
+
+        fn install(&self, _: &Toolchain) -> Result<()> {
+            Ok(())
+        }
+
+        fn uninstall(&self, toolchain: &Toolchain) -> Result<()> {
+            self.0.lock().unwrap().push(toolchain.clone());
+            Ok(())
+        }
+
+        fn cargo_toolchain(&self, _: &Toolchain) -> CargoToolchain {
+            CargoToolchain::Rustc(PathBuf::from("rustc"))
+        }
+    }
+
+    /// A cache holding the given toolchains, used in the order they're listed.
+    fn cache(path: PathBuf, limits: CacheLimits, specs: &[&str]) -> ToolchainCache {
+        let start = Utc::now().naive_utc();
+        ToolchainCache {
+            path,
+            limits,
+            entries: specs
+                .iter()
+                .enumerate()
+                .map(|(i, spec)| CachedToolchain {
+                    toolchain: Toolchain::from(spec),
+                    last_used: start + Duration::minutes(i as i64),
+                    installed_by_us: true,
+                })
+                .collect(),
+        }
+    }
+
+    fn specs(entries: &[CachedToolchain]) -> Vec<String> {
+        entries.iter().map(|e| e.toolchain.to_string()).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let limits = CacheLimits {
+            max_toolchains: 2,
+            max_target_bytes: 100,
+        };
+        let mut cache = cache(PathBuf::new(), limits, &["b", "a", "c"]);
+
+        let evicted = cache.select_evictions(&Toolchain::from("c"), |_| 10);
+        assert_eq!(specs(&evicted), vec!["b"]);
+        assert_eq!(specs(&cache.entries), vec!["a", "c"]);
+    }
+
+    #[test]
+    fn evicts_until_within_the_target_budget() {
+        let limits = CacheLimits {
+            max_toolchains: 3,
+            max_target_bytes: 25,
+        };
+        let mut cache = cache(PathBuf::new(), limits, &["a", "b", "c"]);
+
+        let evicted = cache.select_evictions(&Toolchain::from("c"), |_| 10);
+        assert_eq!(specs(&evicted), vec!["a"]);
+        assert_eq!(specs(&cache.entries), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn keeps_the_toolchain_in_use() {
+        let limits = CacheLimits {
+            max_toolchains: 1,
+            max_target_bytes: 5,
+        };
+        let mut cache = cache(PathBuf::new(), limits, &["b", "a", "c"]);
+
+        // even though it's the oldest and alone it's over the budget
+        let evicted = cache.select_evictions(&Toolchain::from("b"), |_| 10);
+        assert_eq!(specs(&evicted), vec!["a", "c"]);
+        assert_eq!(specs(&cache.entries), vec!["b"]);
+    }
+
+    #[test]
+    fn evicts_everything_when_no_toolchains_are_kept() {
+        let dir = tempdir().unwrap();
+        let limits = CacheLimits {
+            max_toolchains: 0,
+            max_target_bytes: 0,
+        };
+        let mut cache = cache(dir.path().join("cache.json"), limits, &["a", "b"]);
+        cache.entries[0].installed_by_us = false;
+
+        let provider = Uninstalls::default();
+        cache.evict(&provider, &Toolchain::from("b")).unwrap();
+
+        assert!(cache.entries.is_empty());
+        // a was already installed before we used it
+        assert_eq!(*provider.0.lock().unwrap(), vec![Toolchain::from("b")]);
+    }
+}
@@ -6,10 +6,13 @@ use askama::Template;
 use chrono::{DateTime, Utc};
 use criterion_stats::univariate::Sample;
 
+/// Unless `compare_across_hosts` is set, results are only compared with others measured on a
+/// host with the same fingerprint.
 pub fn build_website(
     data_dir: impl AsRef<Path>,
     output_dir: impl AsRef<Path>,
     publish: bool,
+    compare_across_hosts: bool,
 ) -> Result<()> {
     info!("reading all estimates from the data directory...");
     let data_storage = GitStore::ensure_initialized(data_dir.as_ref())?;
@@ -26,10 +29,30 @@ pub fn build_website(
             )
         })
         .collect();
+    let toolchain_metadata = data_storage.all_toolchain_metadata()?;
+    let artifacts = data_storage.all_stored_artifacts()?;
 
     info!("running analysis, building the website...");
-    let website = Website::from_estimates(estimates)?;
-    let files = website.render_files()?;
+    let website = Website::from_estimates(
+        estimates,
+        &artifacts,
+        &toolchain_metadata,
+        compare_across_hosts,
+    )?;
+    let mut files = website.render_files(data_dir.as_ref())?;
+
+    info!("copying logs from the data directory...");
+    let stored = artifacts
+        .values()
+        .flat_map(|by_toolchain| by_toolchain.values())
+        .flat_map(|by_name| by_name.values())
+        .collect::<BTreeSet<_>>();
+    for path in stored {
+        files.push((
+            artifact_site_path(path),
+            ::std::fs::read(data_dir.as_ref().join(path))?,
+        ));
+    }
 
     info!("generated {} files.", files.len());
 
@@ -90,14 +113,74 @@ struct Website {
     analysis: Analysis,
 }
 
+/// Where an artifact from the data directory is published on the website, as it's stored.
+fn artifact_site_path(data_path: &Path) -> PathBuf {
+    Path::new("artifacts").join(data_path)
+}
+
+/// Describes the conditions a timing's binary was built under, e.g. `12 crates compiled, in the
+/// background`. Empty for binaries whose build conditions weren't recorded.
+fn build_context(timing: &TimingRecord) -> String {
+    let mut context = Vec::new();
+    if let Some(compiled) = timing.build_metrics.get("build-compiled-crates") {
+        context.push(format!("{} crates compiled", compiled.median.raw()));
+    }
+    if let Some(concurrent) = timing.build_metrics.get("build-concurrent") {
+        if concurrent.median.raw() > 0.0 {
+            context.push(String::from("in the background"));
+        }
+    }
+    context.join(", ")
+}
+
 impl Website {
     pub fn from_estimates(
-        estimates: BTreeMap<String, BTreeMap<Toolchain, (Vec<u8>, Estimates)>>,
+        estimates: BTreeMap<(String, Option<String>), BTreeMap<Toolchain, StoredEstimates>>,
+        artifacts: &BTreeMap<
+            (String, Option<String>),
+            BTreeMap<Option<Toolchain>, BTreeMap<String, PathBuf>>,
+        >,
+        toolchain_metadata: &BTreeMap<Toolchain, ToolchainMetadata>,
+        compare_across_hosts: bool,
     ) -> Result<Self> {
         let mut benchmarks: Vec<Benchmark> = estimates
             .clone()
             .into_iter()
-            .map(|(name, estimates)| Benchmark::new(name, estimates.into_iter()))
+            .flat_map(|((name, build_config), estimates)| {
+                let artifacts = artifacts
+                    .get(&(name.clone(), build_config.clone()))
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|(maybe_tc, found)| maybe_tc.map(|tc| (tc, found)))
+                    .collect::<BTreeMap<_, _>>();
+
+                // each release channel gets its own series, comparing them to each other is noise
+                let mut by_channel = BTreeMap::<Channel, Vec<_>>::new();
+                for (toolchain, estimate) in estimates {
+                    by_channel
+                        .entry(toolchain.channel())
+                        .or_default()
+                        .push((toolchain, estimate));
+                }
+
+                by_channel.into_iter().map(move |(channel, series)| {
+                    let mut benchmark = Benchmark::new(
+                        name.clone(),
+                        channel,
+                        build_config.clone(),
+                        series.into_iter(),
+                        toolchain_metadata,
+                        compare_across_hosts,
+                    );
+                    benchmark.artifacts = artifacts
+                        .iter()
+                        .filter(|(tc, _)| tc.channel() == channel)
+                        .map(|(tc, found)| (tc.clone(), found.clone()))
+                        .collect();
+                    benchmark
+                })
+            })
             .collect();
 
         let all_timings = benchmarks
@@ -105,25 +188,36 @@ impl Website {
             .flat_map(|b| {
                 b.timings
                     .iter()
-                    .map(move |t| (b.name.clone(), t.to_owned()))
+                    .map(move |t| (b.name.clone(), b.build_config.clone(), t.to_owned()))
             })
             .collect::<Vec<_>>();
 
         let analysis = Analysis::new(all_timings);
 
-        let mut anomalies = BTreeMap::<String, Vec<(Toolchain, AnomalousTiming)>>::new();
+        let mut anomalies =
+            BTreeMap::<(String, Channel, Option<String>), Vec<(Toolchain, AnomalousTiming)>>::new();
 
         for (tc, timings) in &analysis.anomalous_timings {
             for anomaly in timings {
                 anomalies
-                    .entry(anomaly.bench_fn.clone())
+                    .entry((
+                        anomaly.bench_fn.clone(),
+                        tc.channel(),
+                        anomaly.build_config.clone(),
+                    ))
                     .or_default()
                     .push((tc.clone(), anomaly.to_owned()));
             }
         }
 
         for benchmark in &mut benchmarks {
-            benchmark.anomalous_timings = anomalies.remove(&benchmark.name).unwrap_or_default();
+            benchmark.anomalous_timings = anomalies
+                .remove(&(
+                    benchmark.name.clone(),
+                    benchmark.channel,
+                    benchmark.build_config.clone(),
+                ))
+                .unwrap_or_default();
         }
 
         Ok(Self {
@@ -133,7 +227,9 @@ impl Website {
         })
     }
 
-    pub fn render_files(&self) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    /// Renders every page of the site. Disassembly diffs are rendered from the artifacts stored in
+    /// the data directory.
+    pub fn render_files(&self, data_dir: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>> {
         let mut files = vec![(
             PathBuf::from("index.html"),
             self.render().unwrap().into_bytes(),
@@ -141,145 +237,462 @@ impl Website {
 
         for benchmark in &self.benchmarks {
             files.push((benchmark.path(), benchmark.render().unwrap().into_bytes()));
+
+            for pair in benchmark.profile_pairs() {
+                let before = String::from_utf8(read_artifact(data_dir, &pair.before_path)?)?;
+                let after = String::from_utf8(read_artifact(data_dir, &pair.after_path)?)?;
+
+                let page = ProfileDiff {
+                    name: benchmark.display_name(),
+                    benchmark_page: benchmark.file_name(),
+                    graph: FlameNode::differential(&before, &after),
+                    before: pair.before,
+                    after: pair.after.clone(),
+                };
+                files.push((
+                    benchmark.profile_diff_path(&pair.after),
+                    page.render().unwrap().into_bytes(),
+                ));
+            }
+
+            for pair in benchmark.disassembly_pairs() {
+                let before = String::from_utf8(read_artifact(data_dir, &pair.before_path)?)?;
+                let after = String::from_utf8(read_artifact(data_dir, &pair.after_path)?)?;
+
+                let page = DisassemblyDiff {
+                    name: benchmark.display_name(),
+                    benchmark_page: benchmark.file_name(),
+                    lines: diff_lines(&before, &after),
+                    before: pair.before,
+                    after: pair.after.clone(),
+                };
+                files.push((
+                    benchmark.disassembly_diff_path(&pair.after),
+                    page.render().unwrap().into_bytes(),
+                ));
+            }
         }
 
         Ok(files)
     }
 }
 
+/// The artifacts of two consecutive binaries in a benchmark's series, by the first toolchain which
+/// built each of them.
+struct ArtifactPair {
+    before: Toolchain,
+    before_path: PathBuf,
+    after: Toolchain,
+    after_path: PathBuf,
+}
+
+#[derive(Template)]
+#[template(path = "disassembly.html")]
+struct DisassemblyDiff {
+    name: String,
+    /// The benchmark's page, which is in the same directory.
+    benchmark_page: String,
+    before: Toolchain,
+    after: Toolchain,
+    lines: Vec<DiffLine>,
+}
+
+#[derive(Template)]
+#[template(path = "profile.html")]
+struct ProfileDiff {
+    name: String,
+    /// The benchmark's page, which is in the same directory.
+    benchmark_page: String,
+    before: Toolchain,
+    after: Toolchain,
+    graph: FlameNode,
+}
+
 #[derive(Template)]
 #[template(path = "benchmark.html")]
 pub struct Benchmark {
     name: String,
+    channel: Channel,
+    /// The name of the build configuration, `None` for the default release profile.
+    build_config: Option<String>,
     timings: Vec<TimingRecord>,
     anomalous_timings: Vec<(Toolchain, AnomalousTiming)>,
-    means: SimpleRuntimeMetrics,
-    std_devs: SimpleRuntimeMetrics,
+    /// Logs and other artifacts stored for each toolchain, by name, as paths in the data directory.
+    artifacts: BTreeMap<Toolchain, BTreeMap<String, PathBuf>>,
+    /// By runtime metric, for the chart's reference lines.
+    means: BTreeMap<String, R64>,
+    std_devs: BTreeMap<String, R64>,
+}
+
+/// A runtime metric's section of a benchmark page, with the anomalies found in it.
+struct AnomalyTable {
+    metric: String,
+    rows: Vec<AnomalyRow>,
+    /// How many of the benchmark's binaries didn't record the metric, e.g. because they were
+    /// measured without access to perf counters.
+    unavailable: usize,
+}
+
+struct AnomalyRow {
+    toolchain: Toolchain,
+    score: AnomalyScore,
+    /// Link to the toolchain's disassembly diff, empty if there isn't one.
+    asm_diff: String,
+    /// Link to the toolchain's differential flame graph, empty if it wasn't profiled.
+    profile_diff: String,
+}
+
+/// A row of a benchmark page's toolchain table.
+struct ToolchainRow {
+    toolchain: Toolchain,
+    binary_hash: String,
+    commit_hash: String,
+    commit_date: String,
+    llvm_version: String,
+    /// Names and site-relative paths of the toolchain's artifacts.
+    artifacts: Vec<(String, String)>,
+    /// Link to the diff against the previous binary's disassembly, empty if there isn't one.
+    asm_diff: String,
+    /// Link to the differential flame graph against the previous binary, empty if there isn't one.
+    profile_diff: String,
+    /// Runtime metrics recorded for other binaries of the benchmark but not this one.
+    unavailable_metrics: String,
+    /// What the binary's build involved, which build metrics should only be compared across.
+    build_context: String,
 }
 
 impl Benchmark {
-    pub fn empty(name: String) -> Self {
+    pub fn empty(name: String, channel: Channel, build_config: Option<String>) -> Self {
         Self {
             name,
+            channel,
+            build_config,
             timings: vec![],
             anomalous_timings: vec![],
-            means: SimpleRuntimeMetrics::ones(),
-            std_devs: SimpleRuntimeMetrics::ones(),
+            artifacts: BTreeMap::new(),
+            means: BTreeMap::new(),
+            std_devs: BTreeMap::new(),
         }
     }
 
     fn new(
         name: String,
-        mut estimates: impl Iterator<Item = (Toolchain, (Vec<u8>, Estimates))>,
+        channel: Channel,
+        build_config: Option<String>,
+        estimates: impl Iterator<Item = (Toolchain, StoredEstimates)>,
+        toolchain_metadata: &BTreeMap<Toolchain, ToolchainMetadata>,
+        compare_across_hosts: bool,
     ) -> Self {
-        let mut timings = Vec::new();
-        let first_estimate = estimates.next().unwrap();
-
-        let mut current_binhash = (first_estimate.1).0;
-        let mut current_toolchains = vec![first_estimate.0];
-        let mut current_measure = (first_estimate.1).1;
-
-        while let Some((tc, (binhash, measure))) = estimates.next() {
-            if binhash == current_binhash {
-                current_toolchains.push(tc.to_owned());
-            } else {
-                let timing = TimingRecord::new(
-                    &current_binhash,
-                    &current_toolchains,
-                    &current_measure,
-                    &timings,
-                );
-
-                timings.push(timing);
-
-                current_binhash = binhash.to_owned();
-                current_toolchains = vec![tc.to_owned()];
-                current_measure = measure.to_owned();
-            }
-        }
-
+        let mut timings = TimingRecord::series(estimates, toolchain_metadata, compare_across_hosts);
         timings.reverse();
         let means = Benchmark::calculate_means(&timings);
         let std_devs = Benchmark::calculate_std_devs(&timings, &means);
         Self {
             name: name.to_owned(),
+            channel,
+            build_config,
             timings,
             anomalous_timings: vec![],
+            artifacts: BTreeMap::new(),
             means,
             std_devs,
         }
     }
 
     pub fn path(&self) -> PathBuf {
-        Path::new("benchmarks").join(slugify(&self.name) + ".html")
+        Path::new("benchmarks").join(self.file_name())
+    }
+
+    fn file_name(&self) -> String {
+        format!("{}.html", self.page_stem())
+    }
+
+    fn page_stem(&self) -> String {
+        // nightly pages keep their original paths so that existing links still work
+        let mut stem = match self.channel {
+            Channel::Nightly => slugify(&self.name),
+            other => format!("{}-{}", slugify(&self.name), other),
+        };
+        if let Some(cfg) = &self.build_config {
+            stem.push_str(&format!("-config-{}", slugify(cfg)));
+        }
+        stem
+    }
+
+    /// The page comparing the disassembly of the binary first built by `after` to the one before.
+    fn disassembly_diff_path(&self, after: &Toolchain) -> PathBuf {
+        Path::new("benchmarks").join(self.disassembly_diff_file_name(after))
+    }
+
+    fn disassembly_diff_file_name(&self, after: &Toolchain) -> String {
+        format!(
+            "{}-asm-{}.html",
+            self.page_stem(),
+            slugify(after.to_string())
+        )
+    }
+
+    /// The page comparing the profile of the binary first built by `after` to the one before.
+    fn profile_diff_path(&self, after: &Toolchain) -> PathBuf {
+        Path::new("benchmarks").join(self.profile_diff_file_name(after))
+    }
+
+    fn profile_diff_file_name(&self, after: &Toolchain) -> String {
+        format!(
+            "{}-profile-{}.html",
+            self.page_stem(),
+            slugify(after.to_string())
+        )
+    }
+
+    /// Each binary in the series paired with the one before it, if both have the named artifact.
+    fn artifact_pairs(&self, name: &str) -> Vec<ArtifactPair> {
+        let artifact = |timing: &TimingRecord| {
+            timing
+                .toolchains
+                .iter()
+                .filter_map(|tc| self.artifacts.get(tc)?.get(name))
+                .next()
+                .cloned()
+        };
+
+        // timings are stored newest first
+        self.timings
+            .windows(2)
+            .filter_map(|pair| {
+                Some(ArtifactPair {
+                    before: pair[1].toolchains[0].clone(),
+                    before_path: artifact(&pair[1])?,
+                    after: pair[0].toolchains[0].clone(),
+                    after_path: artifact(&pair[0])?,
+                })
+            })
+            .collect()
+    }
+
+    fn disassembly_pairs(&self) -> Vec<ArtifactPair> {
+        self.artifact_pairs("disassembly")
+    }
+
+    /// Only the binaries on either side of an anomaly are profiled, see `profile_anomalies`.
+    fn profile_pairs(&self) -> Vec<ArtifactPair> {
+        self.artifact_pairs("profile")
+    }
+
+    /// Links (relative to this page) to the disassembly diffs of binaries, by their first toolchain.
+    fn disassembly_diff_links(&self) -> BTreeMap<Toolchain, String> {
+        self.disassembly_pairs()
+            .into_iter()
+            .map(|pair| {
+                let link = self.disassembly_diff_file_name(&pair.after);
+                (pair.after, link)
+            })
+            .collect()
+    }
+
+    /// Links (relative to this page) to the differential flame graphs of binaries, by their first
+    /// toolchain.
+    fn profile_diff_links(&self) -> BTreeMap<Toolchain, String> {
+        self.profile_pairs()
+            .into_iter()
+            .map(|pair| {
+                let link = self.profile_diff_file_name(&pair.after);
+                (pair.after, link)
+            })
+            .collect()
+    }
+
+    /// One table per runtime metric, listing the anomalous timings which could be scored on it.
+    fn anomaly_tables(&self) -> Vec<AnomalyTable> {
+        let links = self.disassembly_diff_links();
+        let profile_links = self.profile_diff_links();
+        self.runtime_metrics()
+            .into_iter()
+            .map(|metric| {
+                let rows = self
+                    .anomalous_timings
+                    .iter()
+                    .filter_map(|(tc, anomaly)| {
+                        Some(AnomalyRow {
+                            toolchain: tc.clone(),
+                            score: *anomaly.index.scores.get(&metric)?,
+                            asm_diff: links.get(tc).cloned().unwrap_or_default(),
+                            profile_diff: profile_links.get(tc).cloned().unwrap_or_default(),
+                        })
+                    })
+                    .collect();
+                let unavailable = self
+                    .timings
+                    .iter()
+                    .filter(|t| t.metrics.get(&metric).is_none())
+                    .count();
+                AnomalyTable {
+                    metric,
+                    rows,
+                    unavailable,
+                }
+            })
+            .collect()
     }
 
     pub fn link(&self) -> ::askama::MarkupDisplay<String> {
         ::askama::MarkupDisplay::Safe(format!(
             r#"<a href="{}">{}</a>"#,
             self.path().display(),
-            self.name
+            self.display_name()
         ))
     }
 
-    fn metrics_with_anomaly_indices(&self) -> Vec<&'static str> {
+    fn display_name(&self) -> String {
+        let mut qualifiers = Vec::new();
+        if self.channel != Channel::Nightly {
+            qualifiers.push(self.channel.to_string());
+        }
+        if let Some(cfg) = &self.build_config {
+            qualifiers.push(cfg.clone());
+        }
+
+        if qualifiers.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{} ({})", self.name, qualifiers.join(", "))
+        }
+    }
+
+    fn build_config_description(&self) -> String {
+        match &self.build_config {
+            Some(cfg) => format!("the {} configuration", cfg),
+            None => String::from("the default release profile"),
+        }
+    }
+
+    fn toolchain_rows(&self) -> Vec<ToolchainRow> {
+        let diff_links = self.disassembly_diff_links();
+        let profile_links = self.profile_diff_links();
+        let recorded = self.runtime_metrics();
+        let mut rows = Vec::new();
+        for timing in &self.timings {
+            let unavailable_metrics = recorded
+                .iter()
+                .filter(|m| timing.metrics.get(m).is_none())
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ");
+            let build_context = build_context(timing);
+            for (toolchain, commit_hash, commit_date, llvm_version) in timing.toolchain_rows() {
+                let artifacts = match self.artifacts.get(&toolchain) {
+                    Some(found) => found
+                        .iter()
+                        .map(|(name, path)| {
+                            (name.clone(), artifact_site_path(path).display().to_string())
+                        })
+                        .collect(),
+                    None => vec![],
+                };
+
+                rows.push(ToolchainRow {
+                    asm_diff: diff_links.get(&toolchain).cloned().unwrap_or_default(),
+                    profile_diff: profile_links.get(&toolchain).cloned().unwrap_or_default(),
+                    toolchain,
+                    binary_hash: timing.binary_hash.clone(),
+                    commit_hash,
+                    commit_date,
+                    llvm_version,
+                    artifacts,
+                    unavailable_metrics: unavailable_metrics.clone(),
+                    build_context: build_context.clone(),
+                });
+            }
+        }
+        rows
+    }
+
+    /// The runtime metrics recorded for any of this benchmark's binaries, runtime first.
+    fn runtime_metrics(&self) -> Vec<String> {
+        let mut metrics = self
+            .timings
+            .iter()
+            .flat_map(|t| t.metrics.0.keys().cloned())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        metrics.sort_by_key(|m| m.as_str() != "nanoseconds");
+        metrics
+    }
+
+    /// The hit ratio charts which can be drawn from the metrics recorded, as their id, title, and
+    /// the metrics counting accesses and misses.
+    fn ratio_charts(&self) -> Vec<(&'static str, &'static str, &'static str, &'static str)> {
+        let recorded = self.runtime_metrics();
         vec![
-            "nanoseconds",
-            "instructions",
-            "cpu_cycles",
-            "branch_instructions",
-            "branch_misses",
-            "cache_references",
-            "cache_misses",
+            (
+                "branch-predict-ratio",
+                "overall branch predict ratio",
+                "branch-instructions",
+                "branch-misses",
+            ),
+            (
+                "cache-ratio",
+                "overall cache hit ratio",
+                "cache-references",
+                "cache-misses",
+            ),
         ]
+        .into_iter()
+        .filter(|(_, _, access, miss)| {
+            recorded.iter().any(|m| m == access) && recorded.iter().any(|m| m == miss)
+        })
+        .collect()
     }
 
-    fn calculate_means(timings: &[TimingRecord]) -> SimpleRuntimeMetrics {
-        let calc = |getter: fn(&RuntimeMetrics) -> MetricData| {
-            calculate_sample_mean(
-                &timings
+    /// The build metrics recorded for any of this benchmark's binaries.
+    fn build_metrics(&self) -> Vec<&'static str> {
+        BUILD_METRICS
+            .iter()
+            .cloned()
+            .filter(|name| {
+                self.timings
                     .iter()
-                    .map(|record| getter(&record.metrics).median.raw())
-                    .collect::<Vec<_>>(),
-            )
-        };
-        SimpleRuntimeMetrics {
-            nanoseconds: calc(|m| m.nanoseconds),
-            instructions: calc(|m| m.instructions),
-            context_switches: calc(|m| m.context_switches),
-            cpu_clock: calc(|m| m.cpu_clock),
-            branch_instructions: calc(|m| m.branch_instructions),
-            branch_misses: calc(|m| m.branch_misses),
-            cache_misses: calc(|m| m.cache_misses),
-            cache_references: calc(|m| m.cache_references),
-            cpu_cycles: calc(|m| m.cpu_cycles),
+                    .any(|t| t.build_metrics.contains_key(*name))
+            })
+            .collect()
+    }
+
+    /// The recorded values of each runtime metric, for those with enough to compute statistics.
+    fn metric_samples(timings: &[TimingRecord]) -> BTreeMap<String, Vec<f64>> {
+        let mut samples = BTreeMap::<String, Vec<f64>>::new();
+        for record in timings {
+            for (name, data) in &record.metrics.0 {
+                samples
+                    .entry(name.clone())
+                    .or_default()
+                    .push(data.median.raw());
+            }
         }
+        samples
+            .into_iter()
+            .filter(|(_, values)| values.len() > 1)
+            .collect()
+    }
+
+    fn calculate_means(timings: &[TimingRecord]) -> BTreeMap<String, R64> {
+        Benchmark::metric_samples(timings)
+            .into_iter()
+            .map(|(name, values)| (name, calculate_sample_mean(&values)))
+            .collect()
     }
 
     fn calculate_std_devs(
         timings: &[TimingRecord],
-        means: &SimpleRuntimeMetrics,
-    ) -> SimpleRuntimeMetrics {
-        let calc = |getter: fn(&RuntimeMetrics) -> MetricData, mean: R64| {
-            calculate_sample_std_dev(
-                &timings
-                    .iter()
-                    .map(|record| getter(&record.metrics).median.raw())
-                    .collect::<Vec<_>>(),
-                mean.raw(),
-            )
-        };
-        SimpleRuntimeMetrics {
-            nanoseconds: calc(|m| m.nanoseconds, means.nanoseconds),
-            instructions: calc(|m| m.instructions, means.instructions),
-            context_switches: calc(|m| m.context_switches, means.context_switches),
-            cpu_clock: calc(|m| m.cpu_clock, means.cpu_clock),
-            branch_instructions: calc(|m| m.branch_instructions, means.branch_instructions),
-            branch_misses: calc(|m| m.branch_misses, means.branch_misses),
-            cache_misses: calc(|m| m.cache_misses, means.cache_misses),
-            cache_references: calc(|m| m.cache_references, means.cache_references),
-            cpu_cycles: calc(|m| m.cpu_cycles, means.cpu_cycles),
-        }
+        means: &BTreeMap<String, R64>,
+    ) -> BTreeMap<String, R64> {
+        Benchmark::metric_samples(timings)
+            .into_iter()
+            .filter_map(|(name, values)| {
+                let mean = means.get(&name)?.raw();
+                Some((name, calculate_sample_std_dev(&values, mean)))
+            })
+            .collect()
     }
 }
 
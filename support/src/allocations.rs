@@ -0,0 +1,362 @@
+//! An opt-in global allocator which counts what a benchmark allocates. Entrypoints for benchmarks
+//! with `count_allocations` set in the registry install it, and their crates are built with
+//! `--cfg lolbench_count_allocations` so that `wrap_libtest!` also passes each benchmark's body to
+//! an `AllocationBencher`. Once criterion has measured a benchmark its routine is run a fixed
+//! number of times more to record allocation metrics alongside the perf counters in
+//! `metrics-estimates.json`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering::SeqCst};
+
+use criterion::black_box;
+use serde_json::{self, Value};
+
+use Result;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Only set while an `AllocationBencher` is running a sample of the routine, so that criterion's
+/// own measurement (and anything else the process does) isn't slowed down by counting.
+static COUNTING: AtomicBool = AtomicBool::new(false);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Relative to the start of the sample, so negative if the routine frees blocks allocated before.
+static LIVE_BYTES: AtomicIsize = AtomicIsize::new(0);
+static PEAK_LIVE_BYTES: AtomicIsize = AtomicIsize::new(0);
+
+/// Allocates with the system allocator, counting allocations and the bytes they hold while a
+/// sample is being taken. A reallocation counts as freeing the old block and allocating the new
+/// one.
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    /// Called by entrypoints which install this as the global allocator, so that benchmarks know
+    /// to measure their allocations.
+    pub fn enable(&self) {
+        ENABLED.store(true, SeqCst);
+    }
+}
+
+fn allocated(size: usize) {
+    ALLOCATIONS.fetch_add(1, SeqCst);
+    ALLOCATED_BYTES.fetch_add(size, SeqCst);
+    let live = LIVE_BYTES.fetch_add(size as isize, SeqCst) + size as isize;
+
+    let mut peak = PEAK_LIVE_BYTES.load(SeqCst);
+    while live > peak {
+        match PEAK_LIVE_BYTES.compare_exchange_weak(peak, live, SeqCst, SeqCst) {
+            Ok(_) => break,
+            Err(current) => peak = current,
+        }
+    }
+}
+
+fn deallocated(size: usize) {
+    DEALLOCATIONS.fetch_add(1, SeqCst);
+    LIVE_BYTES.fetch_sub(size as isize, SeqCst);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() && COUNTING.load(SeqCst) {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() && COUNTING.load(SeqCst) {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        if COUNTING.load(SeqCst) {
+            deallocated(layout.size());
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() && COUNTING.load(SeqCst) {
+            deallocated(layout.size());
+            allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// The metrics recorded for each iteration, in the order `AllocationBencher` samples them.
+const METRICS: [&str; 4] = [
+    "allocations",
+    "deallocations",
+    "allocated-bytes",
+    "peak-live-bytes",
+];
+
+/// How many iterations are sampled. Allocations rarely vary between iterations, so this doesn't
+/// need to be anywhere near criterion's sample size.
+const SAMPLES: usize = 10;
+
+/// Zeroes the counters and starts counting.
+fn start_sample() {
+    ALLOCATIONS.store(0, SeqCst);
+    DEALLOCATIONS.store(0, SeqCst);
+    ALLOCATED_BYTES.store(0, SeqCst);
+    LIVE_BYTES.store(0, SeqCst);
+    PEAK_LIVE_BYTES.store(0, SeqCst);
+    COUNTING.store(true, SeqCst);
+}
+
+/// Stops counting, returning what was counted since `start_sample` in the order of `METRICS`.
+fn finish_sample() -> [f64; 4] {
+    COUNTING.store(false, SeqCst);
+    [
+        ALLOCATIONS.load(SeqCst) as f64,
+        DEALLOCATIONS.load(SeqCst) as f64,
+        ALLOCATED_BYTES.load(SeqCst) as f64,
+        PEAK_LIVE_BYTES.load(SeqCst) as f64,
+    ]
+}
+
+/// Stands in for criterion's `Bencher` when measuring allocations, running the routine once per
+/// sample after a warm up iteration. Only the routine is counted, not any setup or dropping of its
+/// output that criterion wouldn't time either.
+#[derive(Default)]
+pub struct AllocationBencher {
+    samples: Vec<[f64; 4]>,
+}
+
+impl AllocationBencher {
+    pub fn iter<O, R: FnMut() -> O>(&mut self, mut routine: R) {
+        // like criterion, dropping each output is part of the routine
+        self.iter_with_setup(
+            || (),
+            |()| {
+                black_box(routine());
+            },
+        );
+    }
+
+    pub fn iter_with_setup<I, O, S, R>(&mut self, mut setup: S, mut routine: R)
+    where
+        S: FnMut() -> I,
+        R: FnMut(I) -> O,
+    {
+        // the first call may allocate lazily initialized state which later ones reuse
+        black_box(routine(setup()));
+
+        for _ in 0..SAMPLES {
+            let input = setup();
+            start_sample();
+            let output = routine(input);
+            self.samples.push(finish_sample());
+            drop(black_box(output));
+        }
+    }
+
+    pub fn iter_with_large_drop<O, R: FnMut() -> O>(&mut self, mut routine: R) {
+        self.iter_with_setup(|| (), |()| routine());
+    }
+
+    pub fn iter_with_large_setup<I, O, S, R>(&mut self, setup: S, routine: R)
+    where
+        S: FnMut() -> I,
+        R: FnMut(I) -> O,
+    {
+        self.iter_with_setup(setup, routine);
+    }
+}
+
+/// Measures a benchmark's allocations per iteration if the counting allocator is installed, adding
+/// them and the process' peak RSS to the metrics criterion recorded for `id`.
+pub fn measure_allocations<F: FnOnce(&mut AllocationBencher)>(id: &str, bench: F) -> Result<()> {
+    if !ENABLED.load(SeqCst) {
+        return Ok(());
+    }
+
+    let mut bencher = AllocationBencher::default();
+    bench(&mut bencher);
+    if bencher.samples.is_empty() {
+        return Ok(());
+    }
+
+    let mut estimates = BTreeMap::new();
+    for (i, metric) in METRICS.iter().enumerate() {
+        let values = bencher.samples.iter().map(|s| s[i]).collect::<Vec<_>>();
+        estimates.insert(metric.to_string(), Statistic::from_samples(&values));
+    }
+    if let Some(kb) = max_rss_kilobytes() {
+        estimates.insert(
+            String::from("max-rss-kilobytes"),
+            Statistic::from_samples(&[kb]),
+        );
+    }
+
+    let path = PathBuf::from(
+        ::std::env::var("CARGO_TARGET_DIR").unwrap_or_else(|_| String::from("target")),
+    )
+    .join("criterion")
+    .join(id)
+    .join("new")
+    .join("metrics-estimates.json");
+
+    let mut metrics: BTreeMap<String, Value> = match fs::read_to_string(&path) {
+        Ok(existing) => serde_json::from_str(&existing)?,
+        Err(_) => BTreeMap::new(),
+    };
+    for (metric, statistic) in estimates {
+        metrics.insert(metric, serde_json::to_value(statistic)?);
+    }
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(&path, serde_json::to_string(&metrics)?)?;
+    Ok(())
+}
+
+/// The process' peak resident set size, from `/proc/self/status`.
+fn max_rss_kilobytes() -> Option<f64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line["VmHWM:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+// the below mirrors the estimates criterion writes, which lolbench reads back
+
+#[derive(Serialize)]
+struct Statistic {
+    #[serde(rename = "Mean")]
+    mean: Estimate,
+    #[serde(rename = "Median")]
+    median: Estimate,
+    #[serde(rename = "MedianAbsDev")]
+    median_abs_dev: Estimate,
+    #[serde(rename = "Slope")]
+    slope: Estimate,
+    #[serde(rename = "StdDev")]
+    std_dev: Estimate,
+}
+
+#[derive(Serialize)]
+struct Estimate {
+    confidence_interval: ConfidenceInterval,
+    point_estimate: f64,
+    standard_error: f64,
+}
+
+#[derive(Serialize)]
+struct ConfidenceInterval {
+    confidence_level: f64,
+    lower_bound: f64,
+    upper_bound: f64,
+}
+
+impl Statistic {
+    /// Summarizes samples, with 95% confidence intervals from the standard error of the mean.
+    fn from_samples(values: &[f64]) -> Self {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let median = median(values);
+        let std_dev = if values.len() > 1 {
+            (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        let deviations = values
+            .iter()
+            .map(|v| (v - median).abs())
+            .collect::<Vec<_>>();
+        let standard_error = std_dev / n.sqrt();
+
+        let estimate = |point_estimate: f64| Estimate {
+            confidence_interval: ConfidenceInterval {
+                confidence_level: 0.95,
+                lower_bound: point_estimate - 1.96 * standard_error,
+                upper_bound: point_estimate + 1.96 * standard_error,
+            },
+            point_estimate,
+            standard_error,
+        };
+
+        Self {
+            mean: estimate(mean),
+            median: estimate(median),
+            median_abs_dev: estimate(self::median(&deviations)),
+            slope: estimate(mean),
+            std_dev: estimate(std_dev),
+        }
+    }
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn medians() {
+        assert_eq!(median(&[3.0]), 3.0);
+        assert_eq!(median(&[5.0, 1.0, 3.0]), 3.0);
+        assert_eq!(median(&[4.0, 1.0, 3.0, 2.0]), 2.5);
+    }
+
+    #[test]
+    fn summarizes_samples() {
+        let statistic = Statistic::from_samples(&[2.0, 4.0, 4.0, 4.0, 6.0]);
+        assert_eq!(statistic.mean.point_estimate, 4.0);
+        assert_eq!(statistic.median.point_estimate, 4.0);
+        assert_eq!(statistic.median_abs_dev.point_estimate, 0.0);
+        assert_eq!(statistic.std_dev.point_estimate, 2.0f64.sqrt());
+
+        let error = 2.0f64.sqrt() / 5.0f64.sqrt();
+        assert!((statistic.mean.standard_error - error).abs() < 1e-12);
+        let interval = &statistic.mean.confidence_interval;
+        assert!((interval.lower_bound - (4.0 - 1.96 * error)).abs() < 1e-12);
+        assert!((interval.upper_bound - (4.0 + 1.96 * error)).abs() < 1e-12);
+
+        // a single sample, like max RSS, is exact
+        let single = Statistic::from_samples(&[7.0]);
+        assert_eq!(single.std_dev.point_estimate, 0.0);
+        assert_eq!(single.median.confidence_interval.lower_bound, 7.0);
+    }
+
+    #[test]
+    fn counts_peak_live_bytes_within_a_sample() {
+        // the allocator isn't installed in tests, so only these calls are counted
+        start_sample();
+        allocated(100);
+        allocated(50);
+        deallocated(100);
+        allocated(20);
+        assert_eq!(finish_sample(), [3.0, 1.0, 170.0, 150.0]);
+
+        // freeing something allocated before the sample doesn't count towards its peak
+        start_sample();
+        deallocated(64);
+        allocated(32);
+        assert_eq!(finish_sample(), [1.0, 1.0, 32.0, 0.0]);
+        assert!(!COUNTING.load(SeqCst));
+    }
+}
@@ -7,13 +7,22 @@ extern crate proc_macro_hack;
 extern crate serde_derive;
 
 extern crate criterion;
+#[macro_use]
 extern crate failure;
+#[macro_use]
+extern crate lazy_static;
 extern crate marky_mark;
 extern crate noisy_float;
+extern crate serde_json;
+
+mod allocations;
+mod profiling;
 
+pub use allocations::{measure_allocations, AllocationBencher, CountingAllocator};
 pub use criterion::{black_box, init_logging, Bencher, Criterion};
 pub use marky_mark::Benchmark;
 pub use noisy_float::prelude::*;
+pub use profiling::{Sampling, PERF_ACK_FIFO_VAR, PERF_CTL_FIFO_VAR};
 pub type Result<T> = std::result::Result<T, failure::Error>;
 
 #[doc(hidden)]
@@ -36,8 +45,28 @@ macro_rules! wrap_libtest {
             use lolbench_support::black_box;
             c.bench_function(
                 concat!(module_path!(), "::", stringify!($name)),
-                |$bencher| $body,
+                |$bencher| {
+                    // only built for profiling, see lolbench_support::profiling
+                    #[cfg(lolbench_profile)]
+                    let _sampling = ::lolbench_support::Sampling::start();
+                    $body
+                },
             );
+            // only built for benchmarks which count allocations, see lolbench_support::allocations.
+            // criterion's own results are already written, so a failure here only loses the counts
+            #[cfg(lolbench_count_allocations)]
+            {
+                if let Err(why) = ::lolbench_support::measure_allocations(
+                    concat!(module_path!(), "::", stringify!($name)),
+                    |$bencher: &mut ::lolbench_support::AllocationBencher| $body,
+                ) {
+                    eprintln!(
+                        "unable to record allocations of {}: {}",
+                        concat!(module_path!(), "::", stringify!($name)),
+                        why
+                    );
+                }
+            }
         }
     };
     (fn $name:ident($bencher:ident : &mut test::Bencher) $body:block) => {
@@ -62,8 +91,28 @@ macro_rules! wrap_libtest {
             use lolbench_support::black_box;
             c.bench_function(
                 concat!(module_path!(), "::", stringify!($name)),
-                |$bencher| $body,
+                |$bencher| {
+                    // only built for profiling, see lolbench_support::profiling
+                    #[cfg(lolbench_profile)]
+                    let _sampling = ::lolbench_support::Sampling::start();
+                    $body
+                },
             );
+            // only built for benchmarks which count allocations, see lolbench_support::allocations.
+            // criterion's own results are already written, so a failure here only loses the counts
+            #[cfg(lolbench_count_allocations)]
+            {
+                if let Err(why) = ::lolbench_support::measure_allocations(
+                    concat!(module_path!(), "::", stringify!($name)),
+                    |$bencher: &mut ::lolbench_support::AllocationBencher| $body,
+                ) {
+                    eprintln!(
+                        "unable to record allocations of {}: {}",
+                        concat!(module_path!(), "::", stringify!($name)),
+                        why
+                    );
+                }
+            }
         }
     };
     ($module:path,fn $name:ident($bencher:ident : &mut test::Bencher) $body:block) => {
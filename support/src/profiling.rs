@@ -0,0 +1,99 @@
+//! Lets `perf record` sample only a benchmark's routine when lolbench profiles it. lolbench builds
+//! the benchmarks it profiles with `--cfg lolbench_profile`, starts perf with its events disabled
+//! and passes the paths of perf's control and acknowledgement fifos in `lolbench_perf_ctl_fifo` and
+//! `lolbench_perf_ack_fifo`. In those builds `wrap_libtest!` holds a `Sampling` guard while
+//! criterion runs a benchmark's body, so that neither the process' startup nor criterion's
+//! analysis end up in the profile. Other builds don't touch any of this.
+
+use std::env::var_os;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::Mutex;
+
+use Result;
+
+/// Set by lolbench to the fifo perf reads commands from.
+pub const PERF_CTL_FIFO_VAR: &str = "lolbench_perf_ctl_fifo";
+/// Set by lolbench to the fifo perf acknowledges commands on.
+pub const PERF_ACK_FIFO_VAR: &str = "lolbench_perf_ack_fifo";
+
+lazy_static! {
+    static ref CONTROL: Mutex<Option<Control>> =
+        Mutex::new(Control::from_env().unwrap_or_else(|why| {
+            eprintln!("unable to open perf's control fifos: {}", why);
+            None
+        }));
+}
+
+struct Control {
+    ctl: File,
+    ack: BufReader<File>,
+}
+
+impl Control {
+    fn from_env() -> Result<Option<Self>> {
+        let (ctl, ack) = match (var_os(PERF_CTL_FIFO_VAR), var_os(PERF_ACK_FIFO_VAR)) {
+            (Some(ctl), Some(ack)) => (ctl, ack),
+            _ => return Ok(None),
+        };
+
+        // perf holds both open for reading and writing, so neither of these blocks
+        Ok(Some(Control {
+            ctl: OpenOptions::new().write(true).open(ctl)?,
+            ack: BufReader::new(File::open(ack)?),
+        }))
+    }
+
+    /// Sends perf a command and waits for it to be carried out.
+    fn send(&mut self, command: &str) -> Result<()> {
+        writeln!(self.ctl, "{}", command)?;
+
+        let mut reply = Vec::new();
+        self.ack.read_until(b'\n', &mut reply)?;
+        // perf terminates each acknowledgement with a nul, which is left over from the last one
+        let reply = String::from_utf8_lossy(&reply);
+        ensure!(
+            reply.trim_matches(|c: char| c == '\0' || c.is_whitespace()) == "ack",
+            "perf didn't acknowledge `{}`: {:?}",
+            command,
+            reply
+        );
+        Ok(())
+    }
+}
+
+fn control(command: &str) {
+    let mut current = CONTROL.lock().unwrap();
+    let sent = match current.as_mut() {
+        Some(control) => control.send(command),
+        None => return,
+    };
+
+    // perf won't be listening to anything else either, and the run's log says why
+    if let Err(why) = sent {
+        eprintln!(
+            "unable to {} perf's sampling, no longer trying: {}",
+            command, why
+        );
+        *current = None;
+    }
+}
+
+/// Samples the process while held, if it's being profiled by lolbench.
+pub struct Sampling(());
+
+impl Sampling {
+    pub fn start() -> Self {
+        control("enable");
+        Sampling(())
+    }
+}
+
+impl Drop for Sampling {
+    fn drop(&mut self) {
+        // a benchmark which panicked won't have a profile to store anyway
+        if !::std::thread::panicking() {
+            control("disable");
+        }
+    }
+}